@@ -0,0 +1,148 @@
+//! Durability policies: how hard `yaque` tries to get your data onto the disk.
+//!
+//! A write that was flushed is in the OS page cache. It survives your program
+//! crashing, but not the OS crashing or the power going out. Only `fsync` (or
+//! its cheaper cousin, `fdatasync`) guarantees that.
+
+use std::fs::{rename, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// How hard one side of the queue tries to make its writes survive a power
+/// loss. For the [`crate::Sender`], this applies to the segment files. For the
+/// [`crate::Receiver`], this applies to the `recv-metadata` file.
+///
+/// Each variant down this list trades more throughput for more crash safety.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Durability {
+    /// Only flushes the data to the OS. This is the default and the behavior
+    /// of `yaque` before durability was configurable.
+    #[default]
+    Flush,
+    /// Calls `fdatasync` on every send (or every save, for the receiver). When
+    /// a send returns, the data is in the disk.
+    Sync,
+    /// Calls `fdatasync` at most once every given interval, grouping all the
+    /// writes in between in a single sync. This policy is enforced
+    /// _synchronously_: it is only checked when something is written, so no
+    /// timers are involved. Therefore, the last writes stay unsynced until the
+    /// next write after the interval, or until the sender or receiver is
+    /// dropped. Use [`crate::Sender::sync`] to sync a sender that went idle.
+    Periodic(Duration),
+}
+
+/// Applies a [`Durability`] policy to a file, keeping track of when it was
+/// last synced.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    durability: Durability,
+    last_synced_at: Instant,
+    /// Whether there are flushed writes that were not synced yet.
+    is_dirty: bool,
+}
+
+impl Default for Syncer {
+    fn default() -> Syncer {
+        Syncer::new(Durability::default())
+    }
+}
+
+impl Syncer {
+    pub fn new(durability: Durability) -> Syncer {
+        Syncer {
+            durability,
+            last_synced_at: Instant::now(),
+            is_dirty: false,
+        }
+    }
+
+    /// The policy being applied.
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Whether this policy syncs anything at all. If so, creating files should
+    /// also sync their directory.
+    pub fn is_enabled(&self) -> bool {
+        self.durability != Durability::Flush
+    }
+
+    /// Whether there are flushed writes that were not synced yet.
+    pub fn is_dirty(&self) -> bool {
+        self.is_dirty
+    }
+
+    /// Call this after each flush. Syncs the file if the policy says so.
+    pub fn maybe_sync(&mut self, file: &File) -> io::Result<()> {
+        match self.durability {
+            Durability::Flush => Ok(()),
+            Durability::Sync => self.sync(file),
+            Durability::Periodic(interval) => {
+                if self.last_synced_at.elapsed() >= interval {
+                    self.sync(file)
+                } else {
+                    self.is_dirty = true;
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    /// Syncs the file if there is anything left unsynced by the policy. Use
+    /// this before letting go of a file.
+    pub fn sync_if_dirty(&mut self, file: &File) -> io::Result<()> {
+        if self.is_dirty {
+            self.sync(file)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Syncs the file unconditionally.
+    pub fn sync(&mut self, file: &File) -> io::Result<()> {
+        file.sync_data()?;
+        self.last_synced_at = Instant::now();
+        self.is_dirty = false;
+
+        Ok(())
+    }
+}
+
+/// Replaces the contents of a file atomically: they are written to a temporary
+/// file next to it (with the extension `tmp`), which is then renamed over it.
+/// So, a crash leaves either the old contents or the new ones behind, never a
+/// truncated file. Whenever the policy syncs the temporary file, the directory
+/// is synced after the rename, so that the rename survives a power loss too.
+pub(crate) fn replace_file(path: &Path, contents: &[u8], syncer: &mut Syncer) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&temp_path)?;
+    file.write_all(contents)?;
+    syncer.maybe_sync(&file)?;
+    drop(file);
+
+    rename(&temp_path, path)?;
+
+    if syncer.is_enabled() && !syncer.is_dirty() {
+        sync_dir(path.parent().expect("file must have parent"))?;
+    }
+
+    Ok(())
+}
+
+/// Syncs a directory, so that files created in (or removed from) it are
+/// persisted. This is a no-op on platforms where directories cannot be opened
+/// as files.
+pub(crate) fn sync_dir<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        File::open(path.as_ref())?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Encryption at rest, with XChaCha20-Poly1305. This needs the `encryption`
+//! feature.
+//!
+//! Each encrypted element starts with the id of the key it was encrypted with
+//! (4 bytes, big endian) and a random nonce (24 bytes), followed by the
+//! ciphertext and its tag. The plaintext is the metadata of the element, if
+//! any, followed by the element (compressed, if it is). Besides the plaintext,
+//! the tag covers the flags, the expiry time and the schema version of the
+//! records it is written in (see [`crate::record`]), which are not encrypted,
+//! so that tampering with any of them is detected.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+
+/// The size of a key, in bytes.
+pub(crate) const KEY_LEN: usize = 32;
+
+/// The size of the key id in an encrypted element.
+#[cfg(feature = "encryption")]
+const KEY_ID_LEN: usize = 4;
+
+/// The size of the nonce in an encrypted element.
+#[cfg(feature = "encryption")]
+const NONCE_LEN: usize = 24;
+
+/// The keys used to encrypt and decrypt the elements of a queue, each one with
+/// an id. Elements are encrypted with the current key and the id of the key
+/// is stored with each element. So, to rotate keys, make the new key the
+/// current one and keep the old one around (see [`Keyring::old_key`]) until
+/// every element encrypted with it is consumed.
+///
+/// Keys are never printed, not even by the `Debug` implementation.
+#[derive(Clone)]
+pub struct Keyring {
+    current: u32,
+    keys: BTreeMap<u32, [u8; KEY_LEN]>,
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("current", &self.current)
+            .field("ids", &self.keys.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Keyring {
+    /// Creates a keyring with a single key, which is the current one.
+    pub fn new(id: u32, key: [u8; KEY_LEN]) -> Keyring {
+        let mut keys = BTreeMap::new();
+        keys.insert(id, key);
+
+        Keyring { current: id, keys }
+    }
+
+    /// Adds a key that is only used for decrypting elements encrypted with it.
+    /// Adding a key with the id of the current key does nothing.
+    pub fn old_key(mut self, id: u32, key: [u8; KEY_LEN]) -> Keyring {
+        self.keys.entry(id).or_insert(key);
+        self
+    }
+
+    /// The id of the key elements are encrypted with.
+    pub fn current_id(&self) -> u32 {
+        self.current
+    }
+}
+
+/// Checks that encryption is available in this build.
+///
+/// # Errors
+///
+/// This function returns an error of kind `Unsupported` if the `encryption`
+/// feature is not enabled.
+pub(crate) fn check_supported() -> io::Result<()> {
+    if cfg!(feature = "encryption") {
+        Ok(())
+    } else {
+        Err(unsupported())
+    }
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "encryption needs the `encryption` feature",
+    )
+}
+
+/// Encrypts an element with the current key, authenticating some associated
+/// data along with it.
+#[cfg(feature = "encryption")]
+pub(crate) fn encrypt(keyring: &Keyring, associated: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(&keyring.keys[&keyring.current]));
+    let nonce: [u8; NONCE_LEN] = rand::random();
+    let payload = Payload {
+        msg: data,
+        aad: associated,
+    };
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), payload)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "could not encrypt element"))?;
+
+    let mut encrypted = Vec::with_capacity(KEY_ID_LEN + NONCE_LEN + ciphertext.len());
+    encrypted.extend_from_slice(&keyring.current.to_be_bytes());
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&ciphertext);
+
+    Ok(encrypted)
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn encrypt(_: &Keyring, _: &[u8], _: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+/// Decrypts an element, checking that neither it nor the associated data were
+/// tampered with.
+///
+/// # Errors
+///
+/// This function returns an error of kind `PermissionDenied` if there is no
+/// keyring or if the keyring does not have the key the element was encrypted
+/// with, of kind `InvalidData` if the element does not check out and of kind
+/// `Unsupported` if the `encryption` feature is not enabled.
+#[cfg(feature = "encryption")]
+pub(crate) fn decrypt(
+    keyring: Option<&Keyring>,
+    associated: &[u8],
+    data: &[u8],
+) -> io::Result<Vec<u8>> {
+    use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+    use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+    use std::convert::TryInto;
+
+    let tampered = || {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "encrypted element does not check out; it was tampered with or corrupted",
+        )
+    };
+
+    if data.len() < KEY_ID_LEN + NONCE_LEN {
+        return Err(tampered());
+    }
+
+    let (key_id, rest) = data.split_at(KEY_ID_LEN);
+    let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+    let key_id = u32::from_be_bytes(key_id.try_into().expect("has 4 bytes"));
+
+    let keyring = keyring.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "element is encrypted, but there is no keyring to decrypt it",
+        )
+    })?;
+    let key = keyring.keys.get(&key_id).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!(
+                "element is encrypted with key {}, which is not in the keyring",
+                key_id
+            ),
+        )
+    })?;
+
+    let cipher = XChaCha20Poly1305::new(Key::from_slice(key));
+    let payload = Payload {
+        msg: ciphertext,
+        aad: associated,
+    };
+
+    cipher
+        .decrypt(XNonce::from_slice(nonce), payload)
+        .map_err(|_| tampered())
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn decrypt(_: Option<&Keyring>, _: &[u8], _: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported())
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotation() {
+        let old = Keyring::new(1, [1; KEY_LEN]);
+        let new = Keyring::new(2, [2; KEY_LEN]).old_key(1, [1; KEY_LEN]);
+
+        let encrypted = encrypt(&old, b"associated", b"some data").unwrap();
+        assert_eq!(
+            decrypt(Some(&new), b"associated", &encrypted).unwrap(),
+            b"some data"
+        );
+
+        // The old keyring does not know the new key:
+        let encrypted = encrypt(&new, b"associated", b"some data").unwrap();
+        let err = decrypt(Some(&old), b"associated", &encrypted).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        // Tampering:
+        let err = decrypt(Some(&new), b"tampered", &encrypted).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        let err = decrypt(Some(&new), b"associated", &tampered).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Keys stay secret:
+        assert!(!format!("{:?}", new).contains("[2"));
+    }
+}
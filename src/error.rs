@@ -87,3 +87,73 @@ impl TryRecvError {
         }
     }
 }
+
+/// What is wrong with a corrupted record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The header of the record fails its parity check. The length of the
+    /// record cannot be trusted and so nothing after it in the segment can be
+    /// read.
+    Header([u8; 4]),
+    /// The checksum stored in the record does not match its contents.
+    Checksum {
+        /// The checksum stored in the record.
+        expected: u32,
+        /// The checksum of what was actually read.
+        found: u32,
+    },
+    /// The record is too short to be valid in its format.
+    Malformed,
+    /// The record has flags set that this version of `yaque` does not know.
+    UnknownFlags(u8),
+}
+
+/// A record in the queue that failed an integrity check. This error comes
+/// wrapped in an [`io::Error`] of kind [`io::ErrorKind::InvalidData`]. Use
+/// [`Corrupted::find`] to get it back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Corrupted {
+    /// The segment where the corrupted record is.
+    pub segment: u64,
+    /// The position of the corrupted record in its segment.
+    pub position: u64,
+    /// What is wrong with the record.
+    pub kind: CorruptionKind,
+}
+
+impl fmt::Display for Corrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corrupted record at segment {}, position {}: ",
+            self.segment, self.position
+        )?;
+
+        match &self.kind {
+            CorruptionKind::Header(header) => write!(f, "bad header {:?}", header),
+            CorruptionKind::Checksum { expected, found } => write!(
+                f,
+                "checksum mismatch (expected {:#010x}, found {:#010x})",
+                expected, found
+            ),
+            CorruptionKind::Malformed => write!(f, "record is malformed"),
+            CorruptionKind::UnknownFlags(flags) => write!(f, "unknown flags {:#04x}", flags),
+        }
+    }
+}
+
+impl std::error::Error for Corrupted {}
+
+impl From<Corrupted> for io::Error {
+    fn from(corrupted: Corrupted) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, corrupted)
+    }
+}
+
+impl Corrupted {
+    /// Finds the corruption error inside an IO error returned by this crate, if
+    /// that is what the error is about.
+    pub fn find(error: &io::Error) -> Option<&Corrupted> {
+        error.get_ref()?.downcast_ref()
+    }
+}
@@ -1,6 +1,6 @@
 //! Hamming-encoded headers for the preppers. Reading a corrupted header can
-//! have devastating consequences. It is better to panic. And yes, this _has_
-//! happened in a real world scenario (SIGKILL).
+//! have devastating consequences. It is better to stop reading. And yes, this
+//! _has_ happened in a real world scenario (SIGKILL).
 //!
 //! Here is how to generate the parity masks, in Python:
 //! ```python
@@ -13,6 +13,12 @@
 //!     print()
 //! ```
 //! See [this section in Wikipedia](https://en.wikipedia.org/wiki/Hamming_code#General_algorithm).
+//!
+//! There are two flavors of header. In the original one ([`Header::encode`]),
+//! the first parity bit covers only the length. This only allows detecting
+//! errors. In the other one ([`Header::encode_secded`]), it covers all the
+//! other bits, which makes it an extended Hamming code: single flipped bits
+//! are corrected and double flipped bits are detected (SECDED).
 
 /// Parity check mask.
 const P0: u32 = 0b11_1111_1111_1111_1111_1111_1111; // just a regular parity chech (not Hamming!)
@@ -27,6 +33,32 @@ const P4: u32 = 0b11_1111_1100_0000_0111_1111_0000;
 /// Hamming fifth parity bit mask.
 const P5: u32 = 0b11_1111_1111_1111_1000_0000_0000;
 
+/// The outcome of decoding a SECDED header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decoded {
+    /// The header is fine.
+    Ok(Header),
+    /// One bit was flipped and it was corrected.
+    Corrected {
+        /// The corrected header.
+        header: Header,
+        /// Which bit was flipped (0 is the least significant one).
+        bit: u32,
+    },
+    /// At least two bits were flipped. The length cannot be trusted.
+    Uncorrectable,
+}
+
+/// The Hamming syndrome of a flipped bit in the length, i.e., which of the
+/// parities P1-P5 it affects.
+fn syndrome_of(bit: u32) -> u32 {
+    [P1, P2, P3, P4, P5]
+        .iter()
+        .enumerate()
+        .map(|(i, mask)| ((mask >> bit) & 0b1) << i)
+        .sum()
+}
+
 /// A structure holding all the header info.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Header {
@@ -35,11 +67,14 @@ pub struct Header {
 }
 
 impl Header {
+    /// The biggest length a header can hold: 26bit available ~= 67MB.
+    pub const MAX_LEN: u32 = 0x03_FF_FF_FFu32;
+
     /// Creates a new [`Header`] from header info (just the length by now).
     pub fn new(len: u32) -> Header {
         // last 6bits clean or 26bit available ~= 67MB:
         assert!(
-            len == len & 0x03_FF_FF_FFu32,
+            len == len & Header::MAX_LEN,
             "length too big: {} > 2 ^ 26",
             len
         );
@@ -67,67 +102,78 @@ impl Header {
         u32::to_be_bytes(encoded)
     }
 
-    /// Decodes a header from the actual bits.
-    pub fn decode(header: [u8; 4]) -> Header {
+    /// Decodes a header from the actual bits. Returns `None` if any of the
+    /// parity checks fails, since then the length cannot be trusted.
+    pub fn decode(header: [u8; 4]) -> Option<Header> {
         let encoded = u32::from_be_bytes(header);
+        let len = encoded & Header::MAX_LEN;
 
         // check parities...
-
-        fn xor(a: bool, b: bool) -> bool {
-            a && b || !a && !b
+        for (i, mask) in [P0, P1, P2, P3, P4, P5].iter().enumerate() {
+            let parity = (len & mask).count_ones() & 0b1;
+            if parity != (encoded >> (26 + i)) & 0b1 {
+                return None;
+            }
         }
 
-        assert!(
-            xor(
-                (encoded & P0).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 26) != 0
-            ),
-            "parity 0 wrong: {:?}",
-            header
-        );
-        assert!(
-            xor(
-                (encoded & P1).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 27) != 0
-            ),
-            "parity 1 wrong: {:?}",
-            header
-        );
-        assert!(
-            xor(
-                (encoded & P2).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 28) != 0
-            ),
-            "parity 2 wrong: {:?}",
-            header
-        );
-        assert!(
-            xor(
-                (encoded & P3).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 29) != 0
-            ),
-            "parity 3 wrong: {:?}",
-            header
-        );
-        assert!(
-            xor(
-                (encoded & P4).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 30) != 0
-            ),
-            "parity 4 wrong: {:?}",
-            header
-        );
-        assert!(
-            xor(
-                (encoded & P5).count_ones() as u8 & 0b1 != 0,
-                encoded & (1 << 31) != 0
-            ),
-            "parity 5 wrong: {:?}",
-            header
-        );
+        Some(Header { len })
+    }
+
+    /// Encodes this header into the actual bits, with the first parity bit
+    /// covering all the other bits. Decode it with [`Header::decode_secded`].
+    ///
+    /// Beware: the header for [`Header::MAX_LEN`] is all ones, which is the
+    /// same as the EOF header.
+    pub fn encode_secded(&self) -> [u8; 4] {
+        let mut encoded = u32::from_be_bytes(self.encode()) & !(1 << 26);
+        encoded |= (encoded.count_ones() & 0b1) << 26;
+
+        u32::to_be_bytes(encoded)
+    }
 
-        // clear flags
-        Header::new(encoded & 0x03_FF_FF_FFu32)
+    /// Decodes a header encoded with [`Header::encode_secded`], correcting a
+    /// single flipped bit, if that is the case.
+    pub fn decode_secded(header: [u8; 4]) -> Decoded {
+        let mut encoded = u32::from_be_bytes(header);
+
+        // Which of the parities P1-P5 are wrong:
+        let syndrome = [P1, P2, P3, P4, P5]
+            .iter()
+            .enumerate()
+            .map(|(i, mask)| {
+                let parity = (encoded & Header::MAX_LEN & mask).count_ones() & 0b1;
+                (parity ^ (encoded >> (27 + i)) & 0b1) << i
+            })
+            .sum::<u32>();
+        // An odd number of flipped bits, if wrong:
+        let is_p0_wrong = encoded.count_ones() & 0b1 != 0;
+
+        let bit = match (syndrome, is_p0_wrong) {
+            (0, false) => {
+                return Decoded::Ok(Header {
+                    len: encoded & Header::MAX_LEN,
+                })
+            }
+            (_, false) => return Decoded::Uncorrectable,
+            // Only P0 itself is wrong:
+            (0, true) => 26,
+            // Only one of P1-P5 is wrong:
+            (syndrome, true) if syndrome.is_power_of_two() => 27 + syndrome.trailing_zeros(),
+            // One bit in the length is wrong:
+            (syndrome, true) => match (0..26).find(|&bit| syndrome_of(bit) == syndrome) {
+                Some(bit) => bit,
+                None => return Decoded::Uncorrectable,
+            },
+        };
+
+        encoded ^= 1 << bit;
+
+        Decoded::Corrected {
+            header: Header {
+                len: encoded & Header::MAX_LEN,
+            },
+            bit,
+        }
     }
 }
 
@@ -147,15 +193,53 @@ mod test {
 
         for (_i, header) in lengths.enumerate() {
             // println!("{}", i);
-            assert_eq!(header, Header::decode(header.encode()));
+            assert_eq!(Some(header), Header::decode(header.encode()));
         }
     }
 
     #[test]
-    #[should_panic]
     fn encode_gibberish() {
         let bad = *b"Asbt";
         println!("{:?}", bad);
-        println!("{:?}", Header::decode(bad));
+        assert_eq!(Header::decode(bad), None);
+    }
+
+    #[test]
+    fn secded_length_ok() {
+        for header in lots_of_lengths().take(100_000) {
+            assert_eq!(
+                Decoded::Ok(header),
+                Header::decode_secded(header.encode_secded())
+            );
+        }
+    }
+
+    #[test]
+    fn secded_corrects_one_bit() {
+        for header in lots_of_lengths().take(1_000) {
+            let encoded = u32::from_be_bytes(header.encode_secded());
+
+            for bit in 0..32 {
+                let flipped = u32::to_be_bytes(encoded ^ (1 << bit));
+                assert_eq!(
+                    Decoded::Corrected { header, bit },
+                    Header::decode_secded(flipped)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn secded_detects_two_bits() {
+        for header in lots_of_lengths().take(100) {
+            let encoded = u32::from_be_bytes(header.encode_secded());
+
+            for bit1 in 0..32 {
+                for bit2 in 0..bit1 {
+                    let flipped = u32::to_be_bytes(encoded ^ (1 << bit1) ^ (1 << bit2));
+                    assert_eq!(Decoded::Uncorrectable, Header::decode_secded(flipped));
+                }
+            }
+        }
     }
 }
@@ -30,7 +30,8 @@
 //! ```
 //! You can also use [`Sender::open`] and [`Receiver::open`] to open only one
 //! half of the channel, if you need to. You can also use [`SenderBuilder`]
-//! to instantiate a customized sender.
+//! to instantiate a customized sender. If many senders need to write to the
+//! same queue, see [`SenderBuilder::multi_producer`].
 //!
 //! The usage is similar to the MPSC channel in the standard library, except
 //! that the sending and receiving methods, [`Sender::send`] and [`Receiver::recv`]
@@ -78,6 +79,44 @@
 //! rolling back, you may call [`queue::RecvGuard::rollback`] which _will_ return the
 //! underlying error.
 //!
+//! If an element cannot be processed, say so with [`queue::RecvGuard::nack`].
+//! The element is rolled back as usual, but the receiver keeps count. Give the
+//! receiver a [`queue::DeadLetterQueue`] with [`ReceiverBuilder::dead_letter`]
+//! and, after too many attempts, the element is moved to another queue (as a
+//! [`queue::DeadLetter`], with the reason) instead of blocking the queue
+//! forever. If the problem is likely to go away by itself (e.g., a flaky
+//! dependency), use [`queue::RecvGuard::retry_after`] instead: the element is
+//! set aside and received again after a delay that doubles with every
+//! attempt, while the receiver moves on.
+//!
+//! If you would rather work with [`futures::Stream`] combinators, use
+//! [`Receiver::into_stream`]. Each element then comes in a
+//! [`queue::OwnedRecvGuard`], which does not borrow the receiver and so can be
+//! moved into other tasks. It behaves just like a `RecvGuard`: commit it when
+//! you are done, or it will be rolled back on drop and received again.
+//!
+//! You can also get a single owned guard with [`Receiver::recv_owned`], e.g., to
+//! hand it over to a spawned task that commits it later. By default, only one
+//! owned guard can be out at a time. Set [`ReceiverBuilder::max_in_flight`] to
+//! process many elements in parallel and commit them in any order. The saved
+//! state only moves past the elements committed in a row from the front, so
+//! nothing is lost in a crash, though some elements may be received twice.
+//!
+//! ## Delayed elements
+//!
+//! Sometimes, an element should only be received some time from now (say, a
+//! job to be run in ten minutes). Send it with [`Sender::send_after`] or
+//! [`Sender::send_at`] and the receiver will only get it once it is due.
+//! Meanwhile, the other elements in the queue are received as usual. Delayed
+//! elements are kept aside, one file each, so this is not meant for huge
+//! amounts of data.
+//!
+//! The opposite also happens: an element may be useless after some time (say,
+//! a notification nobody will look at anymore). Send it with
+//! [`Sender::send_with_ttl`] and the receiver will drop it if it finds it
+//! expired, or move it to the dead-letter queue, if there is one. See
+//! [`Receiver::expired`]. This needs record format `v2`.
+//!
 //! ## Batches
 //!
 //! You can use the `yaque` queue to send and receive batches of data ,
@@ -86,6 +125,138 @@
 //! operation is made. See [`Sender::send_batch`], [`Receiver::recv_batch`] and
 //! [`Receiver::recv_until`] for more information on receiver batches.
 //!
+//! If your sends come from many concurrent tasks, you don't have to batch them
+//! by hand. A [`GroupSender`], created with [`Sender::into_group`], coalesces
+//! all the sends issued within a short window into a single write (and a single
+//! sync, if you have set a [`Durability`] policy).
+//!
+//! A [`Sender`] is also a [`futures::Sink`], so you can `forward` a stream
+//! straight into a queue. Elements sent into the sink are buffered and flushed
+//! together when the sink is flushed. When the queue is full, the sink waits
+//! for the receiver, just like [`Sender::send`].
+//!
+//! ## Competing consumers
+//!
+//! A [`Receiver`] is the one and only consumer of a queue. If you need many
+//! consumers to share the work, open the queue with [`Worker::open`] instead.
+//! Every element is handed to only one [`Worker`] at a time, under a
+//! [`queue::Lease`]. The lease must be acknowledged with [`queue::Lease::ack`]
+//! before it expires. Otherwise, the element is delivered again to whichever
+//! worker comes next. Do not mix workers and a receiver on the same queue.
+//!
+//! ## Consumer groups
+//!
+//! If, instead, many consumers each need to see _every_ element (say, one for
+//! auditing and another for indexing), give each of them a consumer group with
+//! [`ReceiverBuilder::group`]. Each group keeps its own state and a segment is
+//! only deleted once the slowest group has moved past it. Use
+//! [`queue::groups`] to list the groups of a queue and
+//! [`queue::try_delete_group`] to get rid of a group you don't need anymore.
+//! Otherwise, it will hold on to the data forever.
+//!
+//! ## Keeping history around
+//!
+//! By default, segments are deleted as soon as they are consumed. If you would
+//! rather keep some recent history (e.g., to replay it after a bug downstream),
+//! set a [`queue::Retention`] policy with [`queue::set_retention`]. Then,
+//! consumed segments are kept until they get too old, too big or too many and
+//! are only deleted by [`queue::sweep`], which you should run every so often.
+//! Use [`Receiver::rewind`] to go back to the oldest element still kept.
+//!
+//! If you need to keep what was consumed somewhere else instead, give the
+//! receiver a [`queue::Archive`] policy with [`ReceiverBuilder::archive`].
+//! Consumed segments are then moved to the `archive` folder inside the queue
+//! folder (and compressed, if you enable the `gzip` feature).
+//!
+//! ## Monitoring
+//!
+//! To know how far behind the receivers are, use [`queue::stats`]. It reports
+//! how many elements are pending (and how many bytes they take), how many
+//! segments there are and where the sender and the slowest receiver are. It
+//! takes no locks, so you can call it from anywhere (say, a metrics endpoint)
+//! while the queue is in use. It only sees what the receivers have saved,
+//! though: see [`ReceiverBuilder::save_every`]. Receivers also count what is
+//! pending for them with [`Receiver::len`], in-flight elements included.
+//! [`Sender::len`] counts what is pending for the slowest receiver.
+//!
+//! ## Metrics
+//!
+//! If you use a monitoring system, enable the `metrics` feature and implement
+//! `metrics::Recorder` to get counters and gauges out of senders and
+//! receivers: elements and bytes sent and received, commits, rollbacks,
+//! segments created and deleted, how long sends wait for room in a full queue,
+//! how long saving the receiver state takes and how far behind the receiver is
+//! (in bytes, whenever it saves its state, and in elements, whenever you call
+//! [`Receiver::len`]). Give the recorder to the sender with
+//! `SenderBuilder::recorder` and to the receiver with `ReceiverBuilder::recorder`.
+//!
+//! ## Detecting corruption
+//!
+//! By default, records in the queue are just a length and the data. A bit
+//! flipped on disk in the data goes unnoticed. If you care, create the queue
+//! in [`RecordFormat::V2`], using [`SenderBuilder::record_format`]. Then, each
+//! record also carries a CRC32C checksum and receiving a corrupted record
+//! returns an error of kind `InvalidData`. Use [`Corrupted::find`] on the error
+//! to know where the corrupted record is. The format is recorded in the queue
+//! when it is created and cannot be changed afterwards.
+//!
+//! In both formats, the length of each record is protected by parity bits. In
+//! `v2`, a single flipped bit in the length is also corrected on read. Use
+//! [`header_errors`] to know how often this happens. When the length is
+//! corrupted beyond repair, there is no telling where the next record is, so
+//! the rest of the segment is lost. Either way, [`Receiver`] stays put at
+//! the corrupted record and returns the same error until you choose what to do
+//! about it: stop, skip the rest of the segment with
+//! [`Receiver::skip_segment`] or move the segment aside for later inspection
+//! with [`Receiver::quarantine_segment`].
+//!
+//! Queues in [`RecordFormat::V2`] also take elements bigger than 64MiB, which
+//! is the limit for [`RecordFormat::V1`]. These are split into many records
+//! when sent and put back together when received. In `v1`, sending something
+//! that big returns an error.
+//!
+//! ## Metadata
+//!
+//! Queues in [`RecordFormat::V2`] can also carry some metadata along with each
+//! element: a sequence number, the time it was sent and string headers (say,
+//! a trace id). Turn it on with [`SenderBuilder::metadata`], send headers with
+//! [`Sender::send_with_headers`] and read everything back with
+//! [`queue::RecvGuard::metadata`]. Sequence numbers are inferred from the end
+//! of the queue when the sender is opened, like the rest of the sender state.
+//!
+//! ## Schema versions
+//!
+//! When you change the format of your elements, old elements may still be
+//! sitting in the queue. Tag each element with the version of its format with
+//! [`SenderBuilder::schema_version`] (in [`RecordFormat::V2`]) and give the
+//! receiver a [`Schema`] with an upgrade from each older version, through
+//! [`ReceiverBuilder::schema`]. Then, older elements are upgraded as they are
+//! received and you don't need to drain the queue before deploying. Elements
+//! sent without a version are of version `0`.
+//!
+//! ## Compression
+//!
+//! If disk space is tight, queues in [`RecordFormat::V2`] can also compress
+//! each element with LZ4 or Zstandard, behind the `lz4` and `zstd` features.
+//! Set it with [`SenderBuilder::compression`]. Receivers decompress elements
+//! on their own, as long as the same feature is enabled. Elements are
+//! compressed one by one (even in a batch), so this pays off for elements of
+//! a few hundred bytes or more.
+//!
+//! ## Encryption at rest
+//!
+//! If your queues hold sensitive data, enable the `encryption` feature and
+//! give the sender a [`Keyring`] with [`SenderBuilder::keyring`]. Then, every
+//! element is encrypted with XChaCha20-Poly1305 (after being compressed, if it
+//! is) in queues in [`RecordFormat::V2`]. Receivers need the keys too, through
+//! [`ReceiverBuilder::keyring`]. Tampering with an element is an error of kind
+//! `InvalidData` on receive, never garbage. Each element records the id of the
+//! key it was encrypted with, so you can rotate keys at any time: make the new
+//! key the current one and keep the old one around with [`Keyring::old_key`]
+//! until the elements encrypted with it are consumed. Metadata is encrypted
+//! with the element, but expiry times, schema versions and lengths are not
+//! (see [`SenderBuilder::keyring`]).
+//!
 //! ## Tired of `.await`ing? Timeouts are supported
 //!
 //! If you need your application to not stall when nothing is being put on the
@@ -180,30 +351,53 @@
 //! However, be warned that this is only a _mitigation_ of consistency problems, not
 //! a solution.
 //!
+//! All of the above holds as long as the OS survives. If the _power_ goes out,
+//! whatever was only flushed to the OS may be lost. If you need to survive that,
+//! set a [`Durability`] policy with [`SenderBuilder::durability`] and
+//! [`ReceiverBuilder::durability`]. Syncing every send is slow, so you can also
+//! choose to sync periodically.
+//!
 //! ## Known issues and next steps
 //!
 //! * ~~This is a brand new project. Although I have tested it and it will
 //! certainly not implode your computer, don't trust your life on it yet.~~ This code
 //! is running in production for non-critical applications.
 //! * Wastes too much kernel time when the queue is small enough and the sender
-//! sends many frequent small messages non-atomically. You can mitigate that by
-//! writing in batches to the queue.
+//!   sends many frequent small messages non-atomically. You can mitigate that by
+//!   writing in batches to the queue or by using a [`GroupSender`], which does the
+//!   batching for you.
 //! * There are probably unknown bugs hidden in some corner case. If you find
 //! one, please [fill an issue on GitHub](https://github.com/tokahuke/yaque/issues/new).
 //! Pull requests and contributions are also greatly appreciated.
 //!
 
+mod durability;
+mod encryption;
 mod error;
 mod header;
+#[cfg(not(feature = "metrics"))]
+#[allow(dead_code)]
+mod metrics;
+mod record;
+mod schema;
 mod state;
 mod sync;
 mod version;
 mod watcher;
 
+#[cfg(feature = "metrics")]
+pub mod metrics;
 pub mod mutex;
 pub mod queue;
 #[cfg(feature = "recovery")]
 pub mod recovery;
 
-pub use error::{TryRecvError, TrySendError};
-pub use queue::{channel, Receiver, ReceiverBuilder, Sender, SenderBuilder, QueueIter};
+pub use durability::Durability;
+pub use encryption::Keyring;
+pub use error::{Corrupted, CorruptionKind, TryRecvError, TrySendError};
+pub use queue::{
+    channel, stats, GroupSender, QueueIter, Receiver, ReceiverBuilder, Sender, SenderBuilder,
+    Stats, Worker, WorkerBuilder,
+};
+pub use record::{header_errors, Compression, HeaderErrors, Metadata, RecordFormat};
+pub use schema::Schema;
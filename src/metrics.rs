@@ -0,0 +1,148 @@
+//! Metrics on what senders and receivers are doing, for your own monitoring
+//! system. This needs the `metrics` feature.
+//!
+//! Implement [`Recorder`] and give it to the sender with
+//! [`crate::SenderBuilder::recorder`] and to the receiver with
+//! [`crate::ReceiverBuilder::recorder`]. Each metric comes with the path to
+//! the queue it is about. If you need to tell consumer groups apart, give each
+//! group a recorder of its own.
+
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Receives the metrics of senders and receivers. This is called right in the
+/// middle of sending and receiving, so make it quick: update an atomic or a
+/// metric in your metrics library of choice, but don't do IO.
+pub trait Recorder: Send + Sync {
+    /// Adds `value` to a counter.
+    fn increment(&self, queue: &Path, counter: Counter, value: u64);
+    /// Sets a gauge to `value`.
+    fn gauge(&self, queue: &Path, gauge: Gauge, value: u64);
+    /// Records how long something took.
+    fn timing(&self, queue: &Path, timing: Timing, duration: Duration);
+}
+
+/// Things that are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Counter {
+    /// Elements sent, by the sender.
+    MessagesSent,
+    /// Bytes of the elements sent, as given to the sender (i.e., before
+    /// compression or encryption), by the sender.
+    BytesSent,
+    /// Elements received, by the receiver. Elements are counted again every
+    /// time they are received after a rollback.
+    MessagesReceived,
+    /// Bytes of the elements received, by the receiver.
+    BytesReceived,
+    /// Transactions committed, by the receiver. Each [`crate::queue::RecvGuard`]
+    /// counts as one, whatever the number of elements in it.
+    Commits,
+    /// Transactions rolled back, by the receiver.
+    Rollbacks,
+    /// Segments created, by the sender.
+    SegmentsCreated,
+    /// Segments deleted (or archived) once consumed, by the receiver.
+    SegmentsDeleted,
+}
+
+impl Counter {
+    /// A name for the counter, in `snake_case`, such as `messages_sent`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Counter::MessagesSent => "messages_sent",
+            Counter::BytesSent => "bytes_sent",
+            Counter::MessagesReceived => "messages_received",
+            Counter::BytesReceived => "bytes_received",
+            Counter::Commits => "commits",
+            Counter::Rollbacks => "rollbacks",
+            Counter::SegmentsCreated => "segments_created",
+            Counter::SegmentsDeleted => "segments_deleted",
+        }
+    }
+}
+
+/// Things that are measured every now and then.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Gauge {
+    /// How many elements the receiver has not committed yet. Counting them
+    /// means reading the header of every pending element, so this is only
+    /// measured when you ask for it, with [`crate::Receiver::len`].
+    LagMessages,
+    /// How many bytes the elements the receiver has not committed yet take on
+    /// disk, headers included. This is measured whenever the receiver saves
+    /// its state (see [`crate::ReceiverBuilder::save_every`]), from the size of
+    /// the segment files, without reading any elements.
+    LagBytes,
+}
+
+impl Gauge {
+    /// A name for the gauge, in `snake_case`, such as `lag_messages`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Gauge::LagMessages => "lag_messages",
+            Gauge::LagBytes => "lag_bytes",
+        }
+    }
+}
+
+/// Things that are timed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum Timing {
+    /// How long a send waited for room in a full queue (see
+    /// [`crate::SenderBuilder::max_queue_size`]). Only sends that had to wait
+    /// are timed.
+    QueueFull,
+    /// How long the receiver took to save its state.
+    Save,
+}
+
+impl Timing {
+    /// A name for the timing, in `snake_case`, such as `queue_full`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Timing::QueueFull => "queue_full",
+            Timing::Save => "save",
+        }
+    }
+}
+
+/// Where senders and receivers send their metrics to, if anywhere.
+#[derive(Clone, Default)]
+pub(crate) struct Metrics {
+    recorder: Option<Arc<dyn Recorder>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(recorder: Option<Arc<dyn Recorder>>) -> Metrics {
+        Metrics { recorder }
+    }
+
+    /// Whether there is a recorder. Use this to skip measuring things that
+    /// are costly to measure.
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub(crate) fn increment(&self, queue: &Path, counter: Counter, value: u64) {
+        if let Some(recorder) = &self.recorder {
+            recorder.increment(queue, counter, value);
+        }
+    }
+
+    pub(crate) fn gauge(&self, queue: &Path, gauge: Gauge, value: u64) {
+        if let Some(recorder) = &self.recorder {
+            recorder.gauge(queue, gauge, value);
+        }
+    }
+
+    pub(crate) fn timing(&self, queue: &Path, timing: Timing, duration: Duration) {
+        if let Some(recorder) = &self.recorder {
+            recorder.timing(queue, timing, duration);
+        }
+    }
+}
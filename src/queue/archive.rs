@@ -0,0 +1,142 @@
+use std::fs::*;
+use std::io::{self};
+use std::path::{Path, PathBuf};
+
+use super::segment_filename;
+
+/// The name of the archive folder inside the queue folder.
+fn archive_dirname<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("archive")
+}
+
+/// An archive policy for a receiver. With an archive policy, segments the
+/// receiver has fully consumed are moved into the `archive` folder inside the
+/// queue folder, instead of being deleted. Use [`Archive::max_bytes`] to keep
+/// the archive from growing forever: when the archive gets too big, the oldest
+/// archived segments are deleted.
+///
+/// Archived segments are kept in the same format as the segments in the queue.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Archive {
+    max_bytes: Option<u64>,
+    #[cfg(feature = "gzip")]
+    compress: bool,
+}
+
+impl Archive {
+    pub fn new() -> Archive {
+        Archive::default()
+    }
+
+    /// Keeps up to this many bytes in the archive, deleting the oldest archived
+    /// segments first.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Archive {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Compresses archived segments with gzip, as `N.q.gz`. This is done
+    /// synchronously, when the segment is archived, so it will slow the
+    /// receiver down a bit whenever it moves past a segment.
+    #[cfg(feature = "gzip")]
+    pub fn compress(mut self, compress: bool) -> Archive {
+        self.compress = compress;
+        self
+    }
+
+    /// Moves a fully consumed segment into the archive and then enforces the
+    /// archive size limit.
+    pub(crate) fn archive_segment<P: AsRef<Path>>(&self, base: P, segment: u64) -> io::Result<()> {
+        let archive_dir = archive_dirname(base.as_ref());
+        create_dir_all(&archive_dir)?;
+
+        log::debug!("archiving segment {} from {:?}", segment, base.as_ref());
+
+        let outcome = self.move_segment(base.as_ref(), &archive_dir, segment);
+        match outcome {
+            // Someone else got here first:
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            result => result?,
+        }
+
+        if let Some(max_bytes) = self.max_bytes {
+            enforce_max_bytes(&archive_dir, max_bytes)?;
+        }
+
+        Ok(())
+    }
+
+    fn move_segment(&self, base: &Path, archive_dir: &Path, segment: u64) -> io::Result<()> {
+        #[cfg(feature = "gzip")]
+        if self.compress {
+            return compress_segment(base, archive_dir, segment);
+        }
+
+        rename(
+            segment_filename(base, segment),
+            segment_filename(archive_dir, segment),
+        )
+    }
+}
+
+/// Compresses a segment into the archive, as `N.q.gz`, and then deletes it.
+#[cfg(feature = "gzip")]
+fn compress_segment(base: &Path, archive_dir: &Path, segment: u64) -> io::Result<()> {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+
+    let segment_path = segment_filename(base, segment);
+
+    // Compress to a temporary file, so that a crash never leaves a half-written
+    // segment in the archive:
+    let mut source = File::open(&segment_path)?;
+    let temp_path = archive_dir.join(format!("{}.q.gz.tmp", segment));
+    let mut encoder = GzEncoder::new(File::create(&temp_path)?, Compression::default());
+    io::copy(&mut source, &mut encoder)?;
+    encoder.finish()?;
+
+    rename(temp_path, archive_dir.join(format!("{}.q.gz", segment)))?;
+    remove_file(segment_path)
+}
+
+/// Deletes the oldest segments in the archive until it fits in the given size.
+fn enforce_max_bytes(archive_dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut archived = vec![];
+    for maybe_entry in read_dir(archive_dir)? {
+        let entry = maybe_entry?;
+        let file_name = entry.file_name();
+        let file_name = file_name.to_string_lossy();
+
+        // Leftovers of an interrupted compression are not archived segments:
+        let segment = file_name
+            .strip_suffix(".q")
+            .or_else(|| file_name.strip_suffix(".q.gz"))
+            .and_then(|stem| stem.parse::<u64>().ok());
+
+        if let Some(segment) = segment {
+            archived.push((segment, entry.path(), entry.metadata()?.len()));
+        }
+    }
+
+    archived.sort_unstable_by_key(|&(segment, _, _)| segment);
+
+    let mut n_bytes = archived.iter().map(|&(_, _, len)| len).sum::<u64>();
+    for (segment, path, len) in archived {
+        if n_bytes <= max_bytes {
+            break;
+        }
+
+        log::debug!(
+            "removing segment {} from archive {:?}",
+            segment,
+            archive_dir
+        );
+        match remove_file(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+        n_bytes -= len;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,161 @@
+use std::convert::TryInto;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::encryption::Keyring;
+use crate::error::TrySendError;
+use crate::record::RecordFormat;
+
+use super::{Sender, SenderBuilder};
+
+/// A dead-letter policy for a receiver. With a dead-letter policy, an element
+/// that is negatively acknowledged (see [`super::RecvGuard::nack`]) too many
+/// times is moved to another queue, the dead-letter queue, as a
+/// [`DeadLetter`], and the receiver moves on. So are expired elements (see
+/// [`crate::Receiver::expired`]).
+///
+/// Many receivers may share the same dead-letter queue: elements are appended
+/// to it in multi-producer mode (see [`SenderBuilder::multi_producer`]).
+///
+/// If the receiver has a keyring (see [`crate::ReceiverBuilder::keyring`]),
+/// dead letters are encrypted with it, so the dead-letter queue must be in
+/// record format `v2` and be read with a keyring as well.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeadLetterQueue {
+    base: PathBuf,
+    max_attempts: u32,
+}
+
+impl DeadLetterQueue {
+    /// Creates a dead-letter policy sending elements to the queue at `base`.
+    pub fn new<P: AsRef<Path>>(base: P) -> DeadLetterQueue {
+        DeadLetterQueue {
+            base: base.as_ref().to_owned(),
+            max_attempts: 5,
+        }
+    }
+
+    /// Sets how many times an element can be negatively acknowledged before it
+    /// is moved to the dead-letter queue.
+    ///
+    /// Default value: `5`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max_attempts` is zero.
+    pub fn max_attempts(mut self, max_attempts: u32) -> DeadLetterQueue {
+        assert!(max_attempts > 0, "got max_attempts=0");
+        self.max_attempts = max_attempts;
+        self
+    }
+
+    /// The path to the dead-letter queue.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// Whether an element negatively acknowledged this many times is dead.
+    pub(crate) fn is_dead(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+
+    /// Opens the sender side of the dead-letter queue, encrypting with the
+    /// keyring of the receiver, if it has one.
+    pub(crate) fn open_sender(&self, keyring: Option<&Keyring>) -> io::Result<Sender> {
+        let builder = SenderBuilder::new().multi_producer(true);
+
+        match keyring {
+            Some(keyring) => builder
+                .record_format(Some(RecordFormat::V2))
+                .keyring(keyring.clone())
+                .open(&self.base),
+            None => builder.open(&self.base),
+        }
+    }
+}
+
+/// An element in a dead-letter queue, together with why it got there.
+///
+/// In the dead-letter queue, each dead letter is stored as the number of
+/// attempts (4 bytes, big endian), the length of the reason (4 bytes, big
+/// endian), the reason (in UTF-8) and then the element itself. Use
+/// [`DeadLetter::decode`] on what you receive from a dead-letter queue.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeadLetter {
+    /// How many times the element was negatively acknowledged. This is zero
+    /// for expired elements.
+    pub attempts: u32,
+    /// The reason given the last time the element was negatively acknowledged,
+    /// or `"expired"` for expired elements (see [`crate::Receiver::expired`]).
+    pub reason: String,
+    /// The element itself.
+    pub data: Vec<u8>,
+}
+
+impl DeadLetter {
+    /// Encodes this dead letter as an element of the dead-letter queue.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = Vec::with_capacity(8 + self.reason.len() + self.data.len());
+        encoded.extend_from_slice(&self.attempts.to_be_bytes());
+        encoded.extend_from_slice(&(self.reason.len() as u32).to_be_bytes());
+        encoded.extend_from_slice(self.reason.as_bytes());
+        encoded.extend_from_slice(&self.data);
+
+        encoded
+    }
+
+    /// Decodes an element received from a dead-letter queue.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidData` if the element is
+    /// not a dead letter.
+    pub fn decode(encoded: &[u8]) -> io::Result<DeadLetter> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a dead letter");
+
+        let attempts = encoded.get(..4).ok_or_else(invalid)?;
+        let reason_len = encoded.get(4..8).ok_or_else(invalid)?;
+        let reason_len = u32::from_be_bytes(reason_len.try_into().expect("4 bytes")) as usize;
+        let reason_end = reason_len.checked_add(8).ok_or_else(invalid)?;
+        let reason = encoded.get(8..reason_end).ok_or_else(invalid)?;
+
+        Ok(DeadLetter {
+            attempts: u32::from_be_bytes(attempts.try_into().expect("4 bytes")),
+            reason: String::from_utf8(reason.to_vec()).map_err(|_| invalid())?,
+            data: encoded[reason_end..].to_vec(),
+        })
+    }
+
+    /// Sends this dead letter to the dead-letter queue.
+    pub(crate) fn send_to(&self, sender: &mut Sender) -> io::Result<()> {
+        // Dead-letter queues have no size limit:
+        sender
+            .try_send(self.encode())
+            .map_err(TrySendError::unwrap_io)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let dead_letter = DeadLetter {
+            attempts: 3,
+            reason: "not today".to_owned(),
+            data: b"some data".to_vec(),
+        };
+
+        let decoded = DeadLetter::decode(&dead_letter.encode()).unwrap();
+        assert_eq!(decoded, dead_letter);
+
+        let err = DeadLetter::decode(b"short").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut huge = dead_letter.encode();
+        huge[4..8].copy_from_slice(&u32::MAX.to_be_bytes());
+        let err = DeadLetter::decode(&huge).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,189 @@
+use std::fs::*;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::encryption::Keyring;
+use crate::record::{Envelope, Metadata, RecordFormat};
+
+/// The name of the folder where the delayed elements of a consumer group are
+/// kept, inside the queue folder.
+pub(crate) fn delayed_dirname<P: AsRef<Path>>(base: P, group: Option<&str>) -> PathBuf {
+    match group {
+        Some(group) => base.as_ref().join(format!("delayed-{}", group)),
+        None => base.as_ref().join("delayed"),
+    }
+}
+
+/// How often a receiver looks for elements that other processes have put in
+/// its delayed folder.
+pub(crate) const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tells apart the elements put by this process with the same due time.
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// An element kept in a delayed folder until it is due.
+#[derive(Debug)]
+pub(crate) struct Delayed {
+    pub path: PathBuf,
+    pub due: SystemTime,
+}
+
+/// What is read back from a delayed element.
+#[derive(Debug)]
+pub(crate) struct DelayedElement {
+    /// How many attempts at the element there were so far.
+    pub attempts: u32,
+    /// The element itself.
+    pub data: Vec<u8>,
+    /// When the element expires, if ever.
+    pub expires_at: Option<SystemTime>,
+    /// The metadata of the element, if any.
+    pub metadata: Option<Metadata>,
+    /// The schema version of the element, if any.
+    pub schema_version: Option<u32>,
+}
+
+fn as_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Puts an element in a delayed folder, to be due at a given time. Each
+/// element is a file named `<due>-<pid>-<sequence>.d`, where `due` is in
+/// milliseconds since the epoch, holding the number of attempts so far (4
+/// bytes, big endian) followed by the element, written as `v2` records with
+/// the given envelope (see [`crate::record`]), whatever the format of the
+/// queue.
+pub(crate) fn put(
+    dir: &Path,
+    data: &[u8],
+    envelope: &Envelope,
+    attempts: u32,
+    due: SystemTime,
+) -> io::Result<()> {
+    let mut contents = attempts.to_be_bytes().to_vec();
+    RecordFormat::V2.write_enveloped(&mut contents, data, envelope)?;
+
+    create_dir_all(dir)?;
+
+    let name = format!(
+        "{:020}-{}-{}",
+        as_millis(due),
+        process::id(),
+        SEQUENCE.fetch_add(1, Ordering::Relaxed)
+    );
+
+    // Write to a temporary file, so that a crash never leaves a half-written
+    // element behind. This is the only copy of the element, so sync it too:
+    let temp_path = dir.join(format!("{}.d.tmp", name));
+    let mut file = File::create(&temp_path)?;
+    file.write_all(&contents)?;
+    file.sync_data()?;
+
+    rename(temp_path, dir.join(format!("{}.d", name)))
+}
+
+/// Finds the element due first in a delayed folder, if any.
+pub(crate) fn first(dir: &Path) -> io::Result<Option<Delayed>> {
+    let entries = match read_dir(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        result => result?,
+    };
+
+    let mut first: Option<(String, u64)> = None;
+    for maybe_entry in entries {
+        let file_name = maybe_entry?.file_name().to_string_lossy().into_owned();
+
+        // Leftovers of an interrupted put are not delayed elements:
+        let due = file_name
+            .strip_suffix(".d")
+            .and_then(|stem| stem.split('-').next())
+            .and_then(|due| due.parse::<u64>().ok());
+
+        if let Some(due) = due {
+            // Names start with the due time, zero-padded:
+            let is_first = match &first {
+                Some((first_name, _)) => file_name < *first_name,
+                None => true,
+            };
+
+            if is_first {
+                first = Some((file_name, due));
+            }
+        }
+    }
+
+    Ok(first.map(|(file_name, due)| Delayed {
+        path: dir.join(file_name),
+        due: UNIX_EPOCH + Duration::from_millis(due),
+    }))
+}
+
+/// Logs a warning if some element in a delayed folder is due, for the receive
+/// methods that never take delayed elements (see `Sender::send_at`).
+pub(crate) fn warn_if_due(dir: &Path, method: &str) -> io::Result<()> {
+    if let Some(first) = first(dir)? {
+        if first.due <= SystemTime::now() {
+            log::warn!(
+                "there are delayed elements due in {:?}, which `{}` never receives \
+                (use `recv`, `try_recv` or `recv_timeout` instead)",
+                dir,
+                method,
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads a delayed element, decrypting it with the given keyring, if it is
+/// encrypted.
+pub(crate) fn read(path: &Path, keyring: Option<&Keyring>) -> io::Result<DelayedElement> {
+    let contents = std::fs::read(path)?;
+    let invalid = |err: io::Error| {
+        io::Error::new(
+            err.kind(),
+            format!("bad delayed element {:?}: {}", path, err),
+        )
+    };
+
+    if contents.len() < 4 {
+        return Err(invalid(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "too short",
+        )));
+    }
+
+    let attempts = u32::from_be_bytes([contents[0], contents[1], contents[2], contents[3]]);
+    let (data, record) = RecordFormat::V2
+        .read_element(&contents[4..], keyring)
+        .map_err(invalid)?;
+
+    Ok(DelayedElement {
+        attempts,
+        data,
+        expires_at: record.expires_at,
+        metadata: record.metadata,
+        schema_version: record.schema_version,
+    })
+}
+
+/// Removes a delayed element that was received and committed.
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    match remove_file(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Removes a delayed folder, with all the elements in it.
+pub(crate) fn remove_dir(dir: &Path) -> io::Result<()> {
+    match remove_dir_all(dir) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
@@ -0,0 +1,169 @@
+use futures::channel::oneshot;
+use futures::lock::Mutex;
+use futures_timer::Delay;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use super::Sender;
+
+/// The sends waiting to be committed together.
+#[derive(Default)]
+struct Batch {
+    /// The data sent so far.
+    items: Vec<Vec<u8>>,
+    /// The total size of `items`, in bytes.
+    in_bytes: usize,
+    /// When the first item in this batch arrived.
+    started_at: Option<Instant>,
+    /// One channel for each send waiting for this batch to be committed.
+    waiters: Vec<oneshot::Sender<io::Result<()>>>,
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        // Nobody is going to commit these anymore:
+        for waiter in self.waiters.drain(..) {
+            waiter.send(Err(dropped())).ok();
+        }
+    }
+}
+
+/// The error of a send whose group was dropped before being committed.
+fn dropped() -> io::Error {
+    io::Error::other("group sender was dropped before the group was committed")
+}
+
+struct Inner {
+    sender: Sender,
+    batch: Batch,
+    /// Incremented every time a batch is committed. This is how a send knows
+    /// whether its batch was already committed by someone else.
+    generation: u64,
+}
+
+impl Inner {
+    /// Writes the current batch in one go and tells everybody how it went.
+    async fn commit(&mut self) {
+        log::trace!(
+            "committing group of {} items ({} bytes)",
+            self.batch.items.len(),
+            self.batch.in_bytes
+        );
+
+        // The batch stays put until it is written. If this future is dropped
+        // before that (e.g., while waiting for room in the queue), the next
+        // send to get to the deadline commits it instead:
+        let outcome = self.sender.send_batch(&self.batch.items).await;
+        let mut batch = std::mem::take(&mut self.batch);
+        self.generation += 1;
+
+        for waiter in batch.waiters.drain(..) {
+            // `io::Error` is not `Clone`. So, the best we can do is:
+            let outcome = match &outcome {
+                Ok(()) => Ok(()),
+                Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+            };
+
+            // Ignore waiters that gave up on waiting:
+            waiter.send(outcome).ok();
+        }
+    }
+}
+
+struct Shared {
+    window: Duration,
+    max_batch_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+/// A sender that coalesces concurrent sends into a single write and a single
+/// sync (group commit). Sends issued within a short time window of each other
+/// (or until a byte budget is exceeded) are written together, as in
+/// [`Sender::send_batch`], and every send only resolves after the shared write
+/// is done.
+///
+/// This is most useful together with [`crate::Durability::Sync`]: instead of
+/// one `fdatasync` per send, you get one `fdatasync` per group. It also saves
+/// you from having to batch small and frequent sends by hand.
+///
+/// You can get one of these with [`Sender::into_group`]. This structure is
+/// cheap to clone and all clones send to the same queue.
+#[derive(Clone)]
+pub struct GroupSender {
+    shared: Arc<Shared>,
+}
+
+impl GroupSender {
+    pub(crate) fn new(sender: Sender, window: Duration, max_batch_bytes: usize) -> GroupSender {
+        GroupSender {
+            shared: Arc::new(Shared {
+                window,
+                max_batch_bytes,
+                inner: Mutex::new(Inner {
+                    sender,
+                    batch: Batch::default(),
+                    generation: 0,
+                }),
+            }),
+        }
+    }
+
+    /// Sends some data into the queue, together with whatever else is being
+    /// sent at the same time. This resolves only after the whole group was
+    /// written (and synced, depending on the [`crate::Durability`] of the
+    /// underlying sender). Just like [`Sender::send`], this will `.await` for
+    /// the queue to have space if it is full.
+    ///
+    /// If the returned future is dropped before completion, the data may or
+    /// may not be sent, depending on whether its group was already committed.
+    /// A group is never lost because a commit was dropped halfway, though: it
+    /// is kept until the next send commits it.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing or
+    /// flushing the queue. If this happens, all the sends in the same group
+    /// get the same error. An error of kind `InvalidInput` is returned right
+    /// away if the data does not fit in a record (see
+    /// [`crate::SenderBuilder::record_format`]). This does not affect the
+    /// other sends in the group.
+    pub async fn send<D: AsRef<[u8]>>(&self, data: D) -> io::Result<()> {
+        let (waiter, outcome) = oneshot::channel();
+
+        let maybe_deadline = {
+            let mut inner = self.shared.inner.lock().await;
+
+            // Do not fail everybody else's sends because of this one:
+            let record_format = inner.sender.record_format();
+            record_format.check_len(data.as_ref().len())?;
+
+            let batch = &mut inner.batch;
+            let started_at = *batch.started_at.get_or_insert_with(Instant::now);
+            batch.in_bytes += data.as_ref().len();
+            batch.items.push(data.as_ref().to_vec());
+            batch.waiters.push(waiter);
+
+            if batch.in_bytes >= self.shared.max_batch_bytes {
+                inner.commit().await;
+                None
+            } else {
+                Some((started_at + self.shared.window, inner.generation))
+            }
+        };
+
+        // Everybody in the group waits for the deadline. Whoever gets there
+        // first commits for everybody else. This way, there is no problem if
+        // any one of the futures is dropped while waiting.
+        if let Some((deadline, generation)) = maybe_deadline {
+            Delay::new(deadline.saturating_duration_since(Instant::now())).await;
+
+            let mut inner = self.shared.inner.lock().await;
+            if inner.generation == generation {
+                inner.commit().await;
+            }
+        }
+
+        outcome.await.unwrap_or_else(|_| Err(dropped()))
+    }
+}
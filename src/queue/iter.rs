@@ -2,7 +2,10 @@ use std::fs::*;
 use std::io::{self};
 use std::path::{Path, PathBuf};
 
+use crate::encryption::Keyring;
+use crate::error::Corrupted;
 use crate::header::Header;
+use crate::record::{Record, RecordFormat};
 use crate::sync::{FileGuard, SyncFollower};
 use crate::version::check_queue_version;
 use crate::state::{QueueStatePersistence, QueueState};
@@ -24,11 +27,22 @@ use super::{segment_filename, HEADER_EOF};
 /// 
 /// And you also get some extra percents of performance from a simpler
 /// implementation. Don't pay for what you don't use!
+///
+/// If the iterator finds a corrupted record (see [`crate::Corrupted`]), it
+/// returns the error and then ends. Expired elements (see
+/// [`crate::Sender::try_send_with_ttl`]) are yielded like any other and so
+/// are elements of older schema versions (see [`crate::Schema`]), as they were
+/// sent.
 pub struct QueueIter {
     _file_guard: FileGuard,
     base: PathBuf,
     state: QueueState,
+    record_format: RecordFormat,
     sync_follower: SyncFollower,
+    /// The keys to decrypt encrypted elements with, if any.
+    keyring: Option<Keyring>,
+    /// Whether a corrupted record was found. Nothing is read after that.
+    is_corrupted: bool,
 }
 
 impl QueueIter {
@@ -52,7 +66,7 @@ impl QueueIter {
         log::trace!("created queue directory");
 
         // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
-        check_queue_version(base.as_ref())?;
+        let record_format = check_queue_version(base.as_ref(), None)?;
 
         // Acquire guard and state:
         let file_guard = try_acquire_recv_lock(base.as_ref())?;
@@ -71,10 +85,21 @@ impl QueueIter {
             _file_guard: file_guard,
             state,
             base: PathBuf::from(base.as_ref()),
+            record_format,
             sync_follower,
+            keyring: None,
+            is_corrupted: false,
         })
     }
 
+    /// Sets the keys used to decrypt encrypted elements. See
+    /// [`crate::SenderBuilder::keyring`]. Without them, encrypted elements
+    /// are errors.
+    pub fn keyring(mut self, keyring: Keyring) -> QueueIter {
+        self.keyring = Some(keyring);
+        self
+    }
+
     /// Puts the queue in another position in another segment. This forcibly
     /// discards the old tail follower and fethces a fresh new one, so be
     /// careful.
@@ -109,7 +134,10 @@ impl QueueIter {
         }
 
         // Now, you set the header!
-        let decoded = Header::decode(header);
+        let (segment, position) = (self.state.segment, self.state.position);
+        let decoded = self
+            .record_format
+            .decode_header(header, segment, position)?;
 
         log::trace!("got header {:?} (read {} bytes)", header, decoded.len());
 
@@ -117,18 +145,57 @@ impl QueueIter {
 
     }
 
-    /// Reads one element from the queue.
-    fn read_one(&mut self) -> io::Result<Vec<u8>> {
+    /// Reads one record from the queue and moves past it. Returns the record
+    /// and where it starts.
+    fn read_record(&mut self) -> io::Result<(Record, QueueState)> {
         // Get the length:
         let header = self.read_header()?;
 
         // With the length, read the data:
         let mut data = vec![0; header.len() as usize];
-        self.sync_follower
-            .read_exact(&mut data)
-            .expect("poisoned queue");
+        self.sync_follower.read_exact(&mut data)?;
+
+        let record_state = self.state;
+        let (segment, position) = (record_state.segment, record_state.position);
+        let record = self.record_format.decode(data, segment, position)?;
+        self.state.advance_position(4 + header.len() as u64);
+
+        Ok((record, record_state))
+    }
+
+    /// Reads one element from the queue, putting it back together if it is
+    /// split in many records.
+    fn read_one(&mut self) -> io::Result<Vec<u8>> {
+        let mut element_state = None;
+        let mut element = vec![];
+
+        loop {
+            let record = match self.read_record() {
+                Ok((record, record_state)) => {
+                    element_state.get_or_insert(record_state);
+                    record
+                }
+                Err(err) => {
+                    // Go back to where the element starts, so that nothing is
+                    // left half-read (e.g., if it is still being written):
+                    self.state = element_state.unwrap_or(self.state);
+                    self.sync_follower
+                        .seek(io::SeekFrom::Start(self.state.position))?;
+                    return Err(err);
+                }
+            };
+
+            if element.is_empty() {
+                element = record.payload;
+            } else {
+                element.extend(record.payload);
+            }
 
-        Ok(data)
+            if !record.is_continued {
+                let (element, _) = record.encoding.decode(element, self.keyring.as_ref())?;
+                return Ok(element);
+            }
+        }
     }
 }
 
@@ -136,13 +203,20 @@ impl Iterator for QueueIter {
     type Item = io::Result<Vec<u8>>;
 
     fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.is_corrupted {
+            return None;
+        }
+
         match self.read_one() {
             Ok(item) => Some(Ok(item)),
             Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
                 log::trace!("got interrupted by eof");
                 None
             }
-            Err(err) => Some(Err(err)),
+            Err(err) => {
+                self.is_corrupted = Corrupted::find(&err).is_some();
+                Some(Err(err))
+            }
         }
     }
 }
@@ -1,12 +1,27 @@
 //! Queue implementation and utility functions.
 
+mod archive;
+mod dead_letter;
+mod delayed;
+mod group;
 mod iter;
 mod receiver;
+mod retention;
 mod sender;
+mod stats;
+mod stream;
+mod worker;
 
+pub use archive::Archive;
+pub use dead_letter::{DeadLetter, DeadLetterQueue};
+pub use group::GroupSender;
 pub use iter::{QueueIter};
-pub use receiver::{Receiver, ReceiverBuilder, RecvGuard};
+pub use receiver::{OwnedRecvGuard, Receiver, ReceiverBuilder, RecvGuard};
+pub use retention::{retention, set_retention, sweep, Retention};
 pub use sender::{Sender, SenderBuilder};
+pub use stats::{stats, Stats};
+pub use stream::RecvStream;
+pub use worker::{Lease, Worker, WorkerBuilder};
 
 #[cfg(feature = "recovery")]
 pub(crate) use receiver::recv_lock_filename;
@@ -17,7 +32,13 @@ use std::fs::*;
 use std::io::{self};
 use std::path::{Path, PathBuf};
 
-use receiver::{acquire_recv_lock, try_acquire_recv_lock};
+use crate::state::{QueueState, QueueStatePersistence};
+use crate::sync::FileGuard;
+
+use receiver::{
+    acquire_group_recv_lock, acquire_recv_lock, check_group_name, try_acquire_group_recv_lock,
+    try_acquire_recv_lock,
+};
 use sender::{acquire_send_lock, try_acquire_send_lock};
 
 /// The name of segment file in the queue folder.
@@ -25,6 +46,107 @@ fn segment_filename<P: AsRef<Path>>(base: P, segment: u64) -> PathBuf {
     base.as_ref().join(format!("{}.q", segment))
 }
 
+/// Lists the segments present in the queue folder, in ascending order.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+fn segments<P: AsRef<Path>>(base: P) -> io::Result<Vec<u64>> {
+    let mut segments = vec![];
+    for maybe_entry in read_dir(base.as_ref())? {
+        let path = maybe_entry?.path();
+        if path.extension().map(|ext| ext == "q").unwrap_or(false) {
+            let segment = path
+                .file_stem()
+                .expect("has extension, therefore has stem")
+                .to_string_lossy()
+                .parse::<u64>()
+                .expect("failed to parse segment filename");
+
+            segments.push(segment);
+        }
+    }
+
+    segments.sort_unstable();
+
+    Ok(segments)
+}
+
+/// Removes one segment from the queue folder, if it is still there.
+fn remove_segment<P: AsRef<Path>>(base: P, segment: u64) -> io::Result<()> {
+    log::debug!("removing segment {} from {:?}", segment, base.as_ref());
+    match remove_file(segment_filename(base, segment)) {
+        // Someone else got here first:
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Finds the segment of the slowest consumer group of the queue. The current
+/// group and state of the caller, if any, are given explicitly, since its saved
+/// state may be lagging behind. Returns `None` if there is no group or if some
+/// group is being saved right now.
+fn slowest_segment<P: AsRef<Path>>(
+    base: P,
+    current: Option<(Option<&str>, QueueState)>,
+) -> io::Result<Option<u64>> {
+    let group_states = match QueueStatePersistence::group_states(base.as_ref()) {
+        Ok(group_states) => group_states,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+            // Someone is saving right now. Leave it for the next time:
+            log::debug!("consumer group being saved; not removing segments");
+            return Ok(None);
+        }
+        Err(err) => return Err(err),
+    };
+
+    Ok(group_states
+        .into_iter()
+        .filter(|(other, _)| {
+            current
+                .map(|(group, _)| other.as_deref() != group)
+                .unwrap_or(true)
+        })
+        .map(|(_, other_state)| other_state.segment)
+        .chain(current.map(|(_, state)| state.segment))
+        .min())
+}
+
+/// Removes the segments that all the consumer groups of the queue have moved
+/// past (see [`slowest_segment`]), unless the queue has a retention policy. In
+/// this case, segments are only ever removed by [`sweep`]. With an archive
+/// policy, segments are archived instead of removed. Returns how many segments
+/// were removed or archived.
+fn remove_consumed_segments<P: AsRef<Path>>(
+    base: P,
+    current: Option<(Option<&str>, QueueState)>,
+    archive: Option<&Archive>,
+) -> io::Result<u64> {
+    if retention(base.as_ref())?.is_some() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+    if let Some(slowest) = slowest_segment(base.as_ref(), current)? {
+        for segment in segments(base.as_ref())? {
+            if segment >= slowest {
+                continue;
+            }
+
+            if let Some(archive) = archive {
+                archive.archive_segment(base.as_ref(), segment)?;
+            } else {
+                remove_segment(base.as_ref(), segment)?;
+            }
+
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
 /// The value of a header EOF.
 const HEADER_EOF: [u8; 4] = [255, 255, 255, 255];
 
@@ -34,14 +156,19 @@ pub fn channel<P: AsRef<Path>>(base: P) -> io::Result<(Sender, Receiver)> {
 }
 
 /// Tries to deletes a queue at the given path. This function will fail if the
-/// queue is in use either for sending or receiving.
+/// queue is in use either for sending or receiving (by any consumer group).
 pub fn try_clear<P: AsRef<Path>>(base: P) -> io::Result<()> {
     let mut send_lock = try_acquire_send_lock(base.as_ref())?;
     let mut recv_lock = try_acquire_recv_lock(base.as_ref())?;
+    let mut group_locks = groups(base.as_ref())?
+        .into_iter()
+        .map(|group| try_acquire_group_recv_lock(base.as_ref(), Some(&group)))
+        .collect::<io::Result<Vec<_>>>()?;
 
     // Sets the the locks to ignore when their files magically disappear.
     send_lock.ignore();
     recv_lock.ignore();
+    group_locks.iter_mut().for_each(FileGuard::ignore);
 
     remove_dir_all(base.as_ref())?;
 
@@ -49,20 +176,65 @@ pub fn try_clear<P: AsRef<Path>>(base: P) -> io::Result<()> {
 }
 
 /// Deletes a queue at the given path. This function will await the queue to
-/// become available for both sending and receiving.
+/// become available for both sending and receiving (by any consumer group).
 pub async fn clear<P: AsRef<Path>>(base: P) -> io::Result<()> {
     let mut send_lock = acquire_send_lock(base.as_ref()).await?;
     let mut recv_lock = acquire_recv_lock(base.as_ref()).await?;
+    let mut group_locks = vec![];
+    for group in groups(base.as_ref())? {
+        group_locks.push(acquire_group_recv_lock(base.as_ref(), Some(&group)).await?);
+    }
 
     // Sets the the locks to ignore when their files magically disappear.
     send_lock.ignore();
     recv_lock.ignore();
+    group_locks.iter_mut().for_each(FileGuard::ignore);
 
     remove_dir_all(base.as_ref())?;
 
     Ok(())
 }
 
+/// Lists the named consumer groups of a queue, in alphabetical order. See
+/// [`ReceiverBuilder::group`] for more information on consumer groups.
+pub fn groups<P: AsRef<Path>>(base: P) -> io::Result<Vec<String>> {
+    let mut groups = QueueStatePersistence::group_states(base)?
+        .into_iter()
+        .filter_map(|(group, _)| group)
+        .collect::<Vec<_>>();
+    groups.sort();
+
+    Ok(groups)
+}
+
+/// Deletes the state of a consumer group, its delayed elements and the segments
+/// that only this group was holding on to. This function will fail if the group
+/// is in use.
+pub fn try_delete_group<P: AsRef<Path>>(base: P, group: &str) -> io::Result<()> {
+    check_group_name(group)?;
+    let lock = try_acquire_group_recv_lock(base.as_ref(), Some(group))?;
+    QueueStatePersistence::remove(base.as_ref(), Some(group))?;
+    delayed::remove_dir(&delayed::delayed_dirname(base.as_ref(), Some(group)))?;
+    drop(lock);
+    remove_consumed_segments(base.as_ref(), None, None)?;
+
+    Ok(())
+}
+
+/// Deletes the state of a consumer group, its delayed elements and the segments
+/// that only this group was holding on to. This function will await the group
+/// to become available.
+pub async fn delete_group<P: AsRef<Path>>(base: P, group: &str) -> io::Result<()> {
+    check_group_name(group)?;
+    let lock = acquire_group_recv_lock(base.as_ref(), Some(group)).await?;
+    QueueStatePersistence::remove(base.as_ref(), Some(group))?;
+    delayed::remove_dir(&delayed::delayed_dirname(base.as_ref(), Some(group)))?;
+    drop(lock);
+    remove_consumed_segments(base.as_ref(), None, None)?;
+
+    Ok(())
+}
+
 /// Global initialization for tests
 #[cfg(test)]
 #[ctor::ctor]
@@ -91,16 +263,18 @@ fn init_log() {
 mod tests {
     use super::*;
 
-    // use futures::StreamExt;
+    use futures::{FutureExt, StreamExt};
     use futures_timer::Delay;
     use rand::{Rng, SeedableRng};
     use rand_xorshift::XorShiftRng;
     use std::sync::Arc;
     use std::time::Duration;
 
-    use crate::error::{TryRecvError, TrySendError};
+    use crate::error::{Corrupted, CorruptionKind, TryRecvError, TrySendError};
+    use crate::header::Header;
+    use crate::record::{header_errors, RecordFormat};
 
-    use self::sender::get_queue_size;
+    use self::sender::{get_queue_size, send_lock_filename};
 
     fn data_lots_of_data() -> impl Iterator<Item = Vec<u8>> {
         let mut rng = XorShiftRng::from_rng(rand::thread_rng()).expect("can init");
@@ -713,6 +887,1669 @@ mod tests {
         assert_eq!(data, iterated);
     }
 
+    #[test]
+    fn test_durability() {
+        fn test(durability: crate::Durability, base: &str) {
+            let data = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+
+            let mut sender = SenderBuilder::new()
+                .segment_size(512)
+                .durability(durability)
+                .open(base)
+                .unwrap();
+
+            for item in &data[..500] {
+                sender.try_send(item).unwrap();
+            }
+
+            sender.try_send_batch(&data[500..]).unwrap();
+            sender.sync().unwrap();
+            drop(sender);
+
+            let mut receiver = ReceiverBuilder::new()
+                .durability(durability)
+                .open(base)
+                .unwrap();
+
+            let received = receiver
+                .try_recv_batch(1_000)
+                .map_err(TryRecvError::unwrap_io)
+                .unwrap();
+            assert_eq!(&*received, &data);
+            received.commit().unwrap();
+            drop(receiver);
+
+            // The saved state is there for the next receiver:
+            let mut receiver = Receiver::open(base).unwrap();
+            assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+            assert!(!Path::new(base).join("recv-metadata.tmp").exists());
+        }
+
+        test(crate::Durability::Sync, "data/durability-sync");
+        test(
+            crate::Durability::Periodic(Duration::from_millis(1)),
+            "data/durability-periodic",
+        );
+    }
+
+    #[test]
+    fn test_interrupted_save() {
+        let mut sender = Sender::open("data/interrupted-save").unwrap();
+        sender.try_send(b"survives").unwrap();
+
+        let mut receiver = Receiver::open("data/interrupted-save").unwrap();
+        receiver.save().unwrap();
+        drop(receiver);
+
+        // A crash in the middle of saving leaves only the temporary files:
+        write("data/interrupted-save/recv-metadata.tmp", b"").unwrap();
+        write("data/interrupted-save/recv-metadata-group.tmp", b"").unwrap();
+
+        assert!(groups("data/interrupted-save").unwrap().is_empty());
+
+        let mut receiver = Receiver::open("data/interrupted-save").unwrap();
+        let received = receiver
+            .try_recv()
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        assert_eq!(&*received, b"survives");
+    }
+
+    #[test]
+    fn test_group_sender() {
+        let sender = SenderBuilder::new()
+            .segment_size(4096)
+            .durability(crate::Durability::Sync)
+            .open("data/group-sender")
+            .unwrap()
+            .into_group(Duration::from_millis(5), 1024);
+
+        // Four producers, each sending its id and a counter:
+        let producers = (0..4u8)
+            .map(|producer| {
+                let sender = sender.clone();
+                std::thread::spawn(move || {
+                    futures::executor::block_on(async move {
+                        for i in 0..250u32 {
+                            let mut item = vec![producer];
+                            item.extend(i.to_be_bytes());
+                            sender.send(item).await.unwrap();
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for producer in producers {
+            producer.join().expect("producer thread panicked");
+        }
+
+        // Everything is there and each producer is in order:
+        let mut receiver = Receiver::open("data/group-sender").unwrap();
+        let received = receiver
+            .try_recv_batch(1_000)
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        let mut next = [0u32; 4];
+
+        for item in received.iter() {
+            let counter = u32::from_be_bytes([item[1], item[2], item[3], item[4]]);
+            assert_eq!(counter, next[item[0] as usize]);
+            next[item[0] as usize] += 1;
+        }
+
+        assert_eq!(next, [250; 4]);
+    }
+
+    #[test]
+    fn test_multi_producer() {
+        let sender = SenderBuilder::new()
+            .segment_size(512)
+            .multi_producer(true)
+            .open("data/multi-producer")
+            .unwrap();
+
+        // Four producers, each sending its id and a counter. Half of them are
+        // clones and the other half are opened independently:
+        let producers = (0..4u8)
+            .map(|producer| {
+                let mut sender = if producer % 2 == 0 {
+                    sender.try_clone().unwrap()
+                } else {
+                    SenderBuilder::new()
+                        .segment_size(512)
+                        .multi_producer(true)
+                        .open("data/multi-producer")
+                        .unwrap()
+                };
+
+                std::thread::spawn(move || {
+                    futures::executor::block_on(async move {
+                        for i in 0..500u32 {
+                            let mut item = vec![producer];
+                            item.extend(i.to_be_bytes());
+
+                            if i % 10 == 0 {
+                                sender.send_batch(&[item.clone(), item]).await.unwrap();
+                            } else {
+                                sender.send(item).await.unwrap();
+                            }
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for producer in producers {
+            producer.join().expect("producer thread panicked");
+        }
+
+        // Nothing was interleaved and each producer is in order:
+        let mut receiver = Receiver::open("data/multi-producer").unwrap();
+        let received = receiver
+            .try_recv_batch(2_200)
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        let mut next = [0u32; 4];
+        let mut items = received.iter();
+
+        while let Some(item) = items.next() {
+            let counter = u32::from_be_bytes([item[1], item[2], item[3], item[4]]);
+            assert_eq!(counter, next[item[0] as usize]);
+            next[item[0] as usize] += 1;
+
+            if counter % 10 == 0 {
+                assert_eq!(items.next(), Some(item));
+            }
+        }
+
+        assert_eq!(next, [500; 4]);
+    }
+
+    #[test]
+    fn test_multi_producer_catches_up() {
+        let open = || {
+            SenderBuilder::new()
+                .segment_size(32)
+                .multi_producer(true)
+                .open("data/multi-producer-catches-up")
+                .unwrap()
+        };
+        let mut behind = open();
+        let mut ahead = open();
+        behind.try_send(b"first").unwrap();
+
+        // Moves on by a few segments, which the receiver then deletes:
+        for _ in 0..9 {
+            ahead.try_send(b"0123456789").unwrap();
+        }
+
+        let mut receiver = Receiver::open("data/multi-producer-catches-up").unwrap();
+        let received = receiver
+            .try_recv_batch(10)
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        assert_eq!(&*received[0], b"first");
+        received.commit().unwrap();
+        assert!(!segment_filename("data/multi-producer-catches-up", 1).exists());
+
+        behind.try_send(b"last").unwrap();
+
+        let received = receiver
+            .try_recv()
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        assert_eq!(&*received, b"last");
+    }
+
+    #[test]
+    fn test_multi_producer_dead_lock() {
+        let mut sender = SenderBuilder::new()
+            .multi_producer(true)
+            .open("data/multi-producer-dead-lock")
+            .unwrap();
+
+        // Some other instance died while appending:
+        let dead = format!(
+            "pid={}\ntoken={}",
+            std::process::id(),
+            crate::sync::UNIQUE_PROCESS_TOKEN.wrapping_add(1)
+        );
+        let lock = send_lock_filename("data/multi-producer-dead-lock");
+        std::fs::write(&lock, &dead).unwrap();
+
+        let err = sender.try_send(b"never").unwrap_err().unwrap_io();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        let err = futures::executor::block_on(sender.send(b"never")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        crate::recovery::unlock_for_sending("data/multi-producer-dead-lock").unwrap();
+        sender.try_send(b"now").unwrap();
+
+        // Waiting for the lock does not block the thread, so that other tasks
+        // get to run (and release it) meanwhile:
+        futures::executor::block_on(async {
+            let release = || async {
+                Delay::new(Duration::from_millis(50)).await;
+                crate::recovery::unlock_for_sending("data/multi-producer-dead-lock").unwrap();
+            };
+
+            std::fs::write(&lock, &dead).unwrap();
+            let (sent, ()) = futures::join!(sender.send(b"later"), release());
+            sent.unwrap();
+
+            std::fs::write(&lock, &dead).unwrap();
+            let sunk = futures::SinkExt::send(&mut sender, b"sunk");
+            let (sunk, ()) = futures::join!(sunk, release());
+            sunk.unwrap();
+        });
+
+        let mut receiver = Receiver::open("data/multi-producer-dead-lock").unwrap();
+        let received = receiver
+            .try_recv_batch(3)
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        assert_eq!(
+            &*received,
+            &[b"now".to_vec(), b"later".to_vec(), b"sunk".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_workers() {
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/workers")
+            .unwrap();
+        let dataset = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+        sender.try_send_batch(&dataset).unwrap();
+
+        let worker = Worker::open("data/workers").unwrap();
+        let consumers = (0..4)
+            .map(|_| {
+                let worker = worker.clone();
+                std::thread::spawn(move || {
+                    let mut received = vec![];
+                    loop {
+                        match worker.try_claim() {
+                            Ok(lease) => {
+                                received.push(lease.to_vec());
+                                lease.ack().unwrap();
+                            }
+                            Err(TryRecvError::QueueEmpty) => break received,
+                            Err(TryRecvError::Io(err)) => panic!("{}", err),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut received = consumers
+            .into_iter()
+            .flat_map(|consumer| consumer.join().expect("consumer thread panicked"))
+            .collect::<Vec<_>>();
+
+        // Everything was received exactly once:
+        let mut expected = dataset;
+        received.sort();
+        expected.sort();
+        assert_eq!(received, expected);
+
+        // All segments but the last one are gone and a receiver sees nothing:
+        let mut receiver = Receiver::open("data/workers").unwrap();
+        assert!(matches!(receiver.try_recv(), Err(TryRecvError::QueueEmpty)));
+        assert_eq!(
+            read_dir("data/workers")
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("q".as_ref()))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_consumer_groups() {
+        let count_segments = || {
+            read_dir("data/consumer-groups")
+                .unwrap()
+                .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("q".as_ref()))
+                .count()
+        };
+
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/consumer-groups")
+            .unwrap();
+        let dataset = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+        for data in &dataset {
+            sender.try_send(data).unwrap();
+        }
+        let n_segments = count_segments();
+        assert!(n_segments > 1);
+
+        futures::executor::block_on(async {
+            let mut audit = ReceiverBuilder::new()
+                .group("audit")
+                .open("data/consumer-groups")
+                .unwrap();
+            let mut index = ReceiverBuilder::new()
+                .group("index")
+                .open("data/consumer-groups")
+                .unwrap();
+            assert_eq!(groups("data/consumer-groups").unwrap(), ["audit", "index"]);
+
+            // Each group gets everything...
+            let batch = audit.recv_batch(1_000).await.unwrap();
+            assert_eq!(&*batch, &dataset);
+            batch.commit().unwrap();
+
+            // ... but segments stay around for the slowest group...
+            assert_eq!(count_segments(), n_segments);
+
+            let batch = index.recv_batch(1_000).await.unwrap();
+            assert_eq!(&*batch, &dataset);
+            batch.commit().unwrap();
+
+            // ... until it moves past them.
+            assert_eq!(count_segments(), 1);
+
+            // Groups are exclusive and must have decent names:
+            assert!(ReceiverBuilder::new()
+                .group("audit")
+                .open("data/consumer-groups")
+                .is_err());
+            assert!(ReceiverBuilder::new()
+                .group("../audit")
+                .open("data/consumer-groups")
+                .is_err());
+            assert!(try_delete_group("data/consumer-groups", "audit").is_err());
+
+            // A late group holds on to what is still there:
+            drop(index);
+            try_delete_group("data/consumer-groups", "index").unwrap();
+            for data in &dataset {
+                sender.try_send(data).unwrap();
+            }
+            let mut alerting = ReceiverBuilder::new()
+                .group("alerting")
+                .open("data/consumer-groups")
+                .unwrap();
+            let batch = audit.recv_batch(1_000).await.unwrap();
+            batch.commit().unwrap();
+            assert!(count_segments() > 1);
+            assert_eq!(
+                groups("data/consumer-groups").unwrap(),
+                ["alerting", "audit"]
+            );
+
+            // (it starts at the bottom of the oldest segment)
+            let mut received = vec![];
+            loop {
+                match alerting.try_recv() {
+                    Ok(data) => {
+                        received.push(data.to_vec());
+                        data.commit().unwrap();
+                    }
+                    Err(TryRecvError::QueueEmpty) => break,
+                    Err(TryRecvError::Io(err)) => panic!("{}", err),
+                }
+            }
+            assert!(received.ends_with(&dataset));
+            assert_eq!(count_segments(), 1);
+        });
+    }
+
+    #[test]
+    fn test_retention() {
+        let count_segments = || segments("data/retention").unwrap().len();
+
+        set_retention("data/retention", Some(Retention::new().max_segments(2))).unwrap();
+        assert_eq!(
+            retention("data/retention").unwrap(),
+            Some(Retention::new().max_segments(2))
+        );
+
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/retention")
+            .unwrap();
+        let dataset = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+        for data in &dataset {
+            sender.try_send(data).unwrap();
+        }
+        let n_segments = count_segments();
+        assert!(n_segments > 2);
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/retention").unwrap();
+
+            // Nothing is deleted on consume...
+            let batch = receiver.recv_batch(1_000).await.unwrap();
+            batch.commit().unwrap();
+            assert_eq!(count_segments(), n_segments);
+
+            // ... so it can be replayed...
+            receiver.rewind().unwrap();
+            let batch = receiver.recv_batch(1_000).await.unwrap();
+            assert_eq!(&*batch, &dataset);
+            batch.commit().unwrap();
+            receiver.save().unwrap();
+
+            // ... until swept away.
+            assert_eq!(sweep("data/retention").unwrap(), n_segments - 2);
+            assert_eq!(count_segments(), 2);
+            assert_eq!(sweep("data/retention").unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn test_retention_fresh_receiver() {
+        set_retention(
+            "data/retention-fresh",
+            Some(Retention::new().max_segments(2)),
+        )
+        .unwrap();
+
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/retention-fresh")
+            .unwrap();
+        let dataset = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+        for data in &dataset {
+            sender.try_send(data).unwrap();
+        }
+
+        // A default receiver that has not received anything yet...
+        let mut receiver = Receiver::open("data/retention-fresh").unwrap();
+
+        // ... holds the segments back, however far a group gets:
+        let mut group = ReceiverBuilder::new()
+            .group("fast")
+            .open("data/retention-fresh")
+            .unwrap();
+        group
+            .try_recv_batch(1_000)
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap()
+            .commit()
+            .unwrap();
+        group.save().unwrap();
+        assert_eq!(sweep("data/retention-fresh").unwrap(), 0);
+
+        let received = receiver
+            .try_recv()
+            .map_err(TryRecvError::unwrap_io)
+            .unwrap();
+        assert_eq!(&*received, &dataset[0]);
+    }
+
+    #[test]
+    fn test_archive() {
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/archive")
+            .unwrap();
+        let dataset = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+        for data in &dataset {
+            sender.try_send(data).unwrap();
+        }
+        let n_segments = segments("data/archive").unwrap().len();
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .archive(Archive::new().max_bytes(4_096))
+                .open("data/archive")
+                .unwrap();
+            let batch = receiver.recv_batch(1_000).await.unwrap();
+            batch.commit().unwrap();
+        });
+
+        // Consumed segments are gone from the queue, but the most recent ones
+        // are in the archive, just as they were:
+        let archived = segments("data/archive/archive").unwrap();
+        let last_archived = *archived.last().unwrap();
+        let archive_size = archived
+            .iter()
+            .map(|&segment| segment_filename("data/archive/archive", segment))
+            .map(|path| metadata(path).unwrap().len())
+            .sum::<u64>();
+        assert_eq!(segments("data/archive").unwrap().len(), 1);
+        assert_eq!(last_archived, n_segments as u64 - 2);
+        assert!(archived.len() < n_segments - 1);
+        assert!(archive_size <= 4_096);
+
+        let contents = read(segment_filename("data/archive/archive", last_archived)).unwrap();
+        let len = Header::decode([contents[0], contents[1], contents[2], contents[3]])
+            .unwrap()
+            .len();
+        assert!(dataset.contains(&contents[4..4 + len as usize].to_vec()));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn test_archive_compressed() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/archive-compressed")
+            .unwrap();
+        let dataset = data_lots_of_data().take(100).collect::<Vec<_>>();
+        for data in &dataset {
+            sender.try_send(data).unwrap();
+        }
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .archive(Archive::new().compress(true))
+                .open("data/archive-compressed")
+                .unwrap();
+            let batch = receiver.recv_batch(100).await.unwrap();
+            batch.commit().unwrap();
+        });
+
+        let mut decompressed = vec![];
+        GzDecoder::new(File::open("data/archive-compressed/archive/0.q.gz").unwrap())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(&decompressed[4..4 + dataset[0].len()], &*dataset[0]);
+    }
+
+    #[test]
+    fn test_lease_expiry() {
+        futures::executor::block_on(async {
+            let mut sender = Sender::open("data/lease-expiry").unwrap();
+            sender.try_send(b"first").unwrap();
+            sender.try_send(b"second").unwrap();
+
+            let worker = WorkerBuilder::new()
+                .lease_duration(Duration::from_millis(100))
+                .poll_every(Duration::from_millis(10))
+                .open("data/lease-expiry")
+                .unwrap();
+
+            // Claimed elements are invisible to other claims...
+            let first = worker.claim().await.unwrap();
+            assert_eq!(&*first, b"first");
+            let second = worker.try_claim().map_err(TryRecvError::unwrap_io).unwrap();
+            assert_eq!(&*second, b"second");
+            assert!(matches!(worker.try_claim(), Err(TryRecvError::QueueEmpty)));
+
+            // ... and released ones are visible right away...
+            second.release().unwrap();
+            let second = worker.try_claim().map_err(TryRecvError::unwrap_io).unwrap();
+            assert_eq!(&*second, b"second");
+            second.ack().unwrap();
+
+            // ... and so are expired ones, after a while:
+            let stale = first;
+            let first = worker.claim().await.unwrap();
+            assert_eq!(&*first, b"first");
+
+            // ... which are not the old lease's to acknowledge anymore:
+            let err = stale.ack().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert!(matches!(worker.try_claim(), Err(TryRecvError::QueueEmpty)));
+            first.ack().unwrap();
+
+            assert!(matches!(worker.try_claim(), Err(TryRecvError::QueueEmpty)));
+        });
+    }
+
+    #[test]
+    fn test_record_checksums() {
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .open("data/record-checksums")
+            .unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+        sender.try_send(b"third").unwrap();
+        drop(sender);
+
+        // The format is fixed when the queue is created:
+        let err = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V1))
+            .open("data/record-checksums")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let read_all = || {
+            QueueIter::open("data/record-checksums")
+                .unwrap()
+                .collect::<io::Result<Vec<_>>>()
+        };
+        assert_eq!(
+            read_all().unwrap(),
+            vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]
+        );
+
+        // Flip a bit in the payload of "second" (after 14 bytes of "first"):
+        let mut contents = read("data/record-checksums/0.q").unwrap();
+        contents[14 + 4 + 5] ^= 0b1;
+        write("data/record-checksums/0.q", contents).unwrap();
+
+        let err = read_all().unwrap_err();
+        let corrupted = Corrupted::find(&err).unwrap();
+        assert_eq!((corrupted.segment, corrupted.position), (0, 14));
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/record-checksums").unwrap();
+            let first = receiver.recv().await.unwrap();
+            assert_eq!(&*first, b"first");
+            first.commit().unwrap();
+
+            // The corrupted record is reported every time, not skipped:
+            for _ in 0..2 {
+                let err = receiver.recv().await.err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+                assert_eq!(Corrupted::find(&err).unwrap().position, 14);
+            }
+        });
+    }
+
+    #[test]
+    fn test_big_elements() {
+        let too_big = vec![7; Header::MAX_LEN as usize + 1_000];
+
+        // In v1, big elements are refused, and nothing gets in the queue:
+        let mut sender = Sender::open("data/big-elements-v1").unwrap();
+        let err = sender
+            .try_send(&too_big)
+            .map_err(TrySendError::unwrap_io)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = sender
+            .try_send_batch(vec![&b"small"[..], &too_big])
+            .map_err(TrySendError::unwrap_io)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        sender.try_send(b"small").unwrap();
+        drop(sender);
+
+        let received = QueueIter::open("data/big-elements-v1")
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(received, vec![b"small".to_vec()]);
+
+        // In v2, they are split and put back together:
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .open("data/big-elements-v2")
+            .unwrap();
+        sender.try_send(b"before").unwrap();
+        sender.try_send(&too_big).unwrap();
+        sender.try_send(b"after").unwrap();
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/big-elements-v2").unwrap();
+
+            // Also after a rollback in the middle of it all:
+            let batch = receiver.recv_batch(2).await.unwrap();
+            assert_eq!(&*batch[0], b"before");
+            assert!(batch[1] == too_big);
+            batch.rollback().unwrap();
+
+            let batch = receiver.recv_batch(3).await.unwrap();
+            assert_eq!(&*batch[0], b"before");
+            assert!(batch[1] == too_big);
+            assert_eq!(&*batch[2], b"after");
+            batch.commit().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_corrupted_header() {
+        // One element per segment:
+        let mut sender = SenderBuilder::new()
+            .segment_size(1)
+            .open("data/corrupted-header")
+            .unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+        sender.try_send(b"third").unwrap();
+        drop(sender);
+
+        let corrupt_header = |segment| {
+            let path = segment_filename("data/corrupted-header", segment);
+            let mut contents = read(&path).unwrap();
+            contents[0..4].copy_from_slice(b"Asbt");
+            write(&path, contents).unwrap();
+        };
+
+        // The iterator reports it once and then stops:
+        corrupt_header(0);
+        let mut iter = QueueIter::open("data/corrupted-header").unwrap();
+        let err = iter.next().unwrap().unwrap_err();
+        let corrupted = Corrupted::find(&err).unwrap();
+        assert_eq!((corrupted.segment, corrupted.position), (0, 0));
+        assert!(matches!(corrupted.kind, CorruptionKind::Header(_)));
+        assert!(iter.next().is_none());
+        drop(iter);
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/corrupted-header").unwrap();
+
+            // The receiver stays put until told what to do...
+            for _ in 0..2 {
+                let err = receiver.recv().await.err().unwrap();
+                assert_eq!(Corrupted::find(&err).unwrap().segment, 0);
+            }
+
+            // ... such as skipping the segment...
+            receiver.skip_segment().unwrap();
+            let second = receiver.recv().await.unwrap();
+            assert_eq!(&*second, b"second");
+            second.commit().unwrap();
+
+            // ... or putting it aside:
+            corrupt_header(2);
+            let err = receiver.recv().await.err().unwrap();
+            assert_eq!(Corrupted::find(&err).unwrap().segment, 2);
+            receiver.quarantine_segment().unwrap();
+            assert!(!segment_filename("data/corrupted-header", 2).exists());
+            assert!(segment_filename("data/corrupted-header/quarantine", 2).exists());
+
+            let mut sender = Sender::open("data/corrupted-header").unwrap();
+            sender.try_send(b"fourth").unwrap();
+            let fourth = receiver.recv().await.unwrap();
+            assert_eq!(&*fourth, b"fourth");
+            fourth.commit().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_corrupted_stream() {
+        let mut sender = SenderBuilder::new()
+            .segment_size(1)
+            .open("data/corrupted-stream")
+            .unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+
+        let path = segment_filename("data/corrupted-stream", 0);
+        let mut contents = read(&path).unwrap();
+        contents[0..4].copy_from_slice(b"Asbt");
+        write(&path, contents).unwrap();
+
+        futures::executor::block_on(async {
+            let mut stream = Receiver::open("data/corrupted-stream")
+                .unwrap()
+                .into_stream();
+
+            // The error is yielded once and then the stream ends...
+            let err = stream.next().await.unwrap().err().unwrap();
+            assert_eq!(Corrupted::find(&err).unwrap().segment, 0);
+            assert!(stream.next().await.is_none());
+
+            // ... so that the receiver can get past it:
+            let mut receiver = stream.into_inner().unwrap();
+            receiver.skip_segment().unwrap();
+            let mut stream = receiver.into_stream();
+            let second = stream.next().await.unwrap().unwrap();
+            assert_eq!(&*second, b"second");
+            second.commit();
+        });
+    }
+
+    #[test]
+    fn test_header_correction() {
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .open("data/header-correction")
+            .unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+        drop(sender);
+
+        // One flipped bit in the first header and two in the second one (after
+        // 14 bytes of "first"):
+        let mut contents = read("data/header-correction/0.q").unwrap();
+        contents[3] ^= 0b100;
+        contents[14 + 3] ^= 0b101;
+        write("data/header-correction/0.q", contents).unwrap();
+
+        let errors_before = header_errors();
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/header-correction").unwrap();
+
+            let first = receiver.recv().await.unwrap();
+            assert_eq!(&*first, b"first");
+            first.commit().unwrap();
+
+            let err = receiver.recv().await.err().unwrap();
+            let corrupted = Corrupted::find(&err).unwrap();
+            assert_eq!((corrupted.segment, corrupted.position), (0, 14));
+            assert!(matches!(corrupted.kind, CorruptionKind::Header(_)));
+        });
+
+        // Other tests may be counting too:
+        let errors_after = header_errors();
+        assert!(errors_after.corrected > errors_before.corrected);
+        assert!(errors_after.uncorrectable > errors_before.uncorrectable);
+    }
+
+    #[test]
+    fn test_into_stream() {
+        let data = data_lots_of_data().take(1_000).collect::<Vec<_>>();
+
+        // Populate a queue:
+        let mut sender = SenderBuilder::new()
+            .segment_size(512)
+            .open("data/into-stream")
+            .unwrap();
+
+        sender.try_send_batch(&data).unwrap();
+
+        futures::executor::block_on(async move {
+            let stream = Receiver::open("data/into-stream").unwrap().into_stream();
+            let streamed = stream
+                .take(1_000)
+                .map(|guard| guard.unwrap().into_inner())
+                .collect::<Vec<_>>()
+                .await;
+            assert_eq!(data, streamed);
+        });
+    }
+
+    #[test]
+    fn test_recv_owned() {
+        let mut sender = Sender::open("data/recv-owned").unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+        sender.try_send(b"third").unwrap();
+
+        futures::executor::block_on(async move {
+            let mut receiver = Receiver::open("data/recv-owned").unwrap();
+
+            // Committed in another thread:
+            let first = receiver.recv_owned().await.unwrap();
+            std::thread::spawn(move || {
+                assert_eq!(&*first, b"first");
+                first.commit();
+            })
+            .join()
+            .unwrap();
+
+            // Nothing can be received while a guard is out:
+            let second = receiver.recv_owned().await.unwrap();
+            assert_eq!(&*second, b"second");
+            assert!(matches!(
+                receiver.try_recv().err().unwrap(),
+                TryRecvError::QueueEmpty
+            ));
+
+            // Rolled back in another thread:
+            std::thread::spawn(move || second.rollback())
+                .join()
+                .unwrap();
+
+            let second = receiver.recv().await.unwrap();
+            assert_eq!(&*second, b"second");
+            second.commit().unwrap();
+
+            let third = receiver.recv_owned().await.unwrap();
+            assert_eq!(third.into_inner(), b"third");
+            // Let the commit reach the receiver:
+            assert!(receiver.recv_owned().now_or_never().is_none());
+        });
+
+        // The commits are persisted:
+        futures::executor::block_on(async move {
+            let mut receiver = Receiver::open("data/recv-owned").unwrap();
+            sender.try_send(b"fourth").unwrap();
+            assert_eq!(&*receiver.recv().await.unwrap(), b"fourth");
+        });
+    }
+
+    #[test]
+    fn test_out_of_order_commits() {
+        let mut sender = Sender::open("data/out-of-order-commits").unwrap();
+        for item in [b"a", b"b", b"c", b"d", b"e"] {
+            sender.try_send(item).unwrap();
+        }
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .max_in_flight(3)
+                .open("data/out-of-order-commits")
+                .unwrap();
+
+            let a = receiver.recv_owned().await.unwrap();
+            let b = receiver.recv_owned().await.unwrap();
+            let c = receiver.recv_owned().await.unwrap();
+            assert_eq!(&*a, b"a");
+            assert_eq!(&*b, b"b");
+            assert_eq!(&*c, b"c");
+
+            // No room for a fourth one:
+            assert!(receiver.recv_owned().now_or_never().is_none());
+
+            // Committed elements make room, even if `a` is still out:
+            c.commit();
+            b.commit();
+            let d = receiver.recv_owned().await.unwrap();
+            assert_eq!(&*d, b"d");
+
+            // A rollback goes back to the first element not committed:
+            a.commit();
+            drop(d);
+            let d = receiver.recv_owned().await.unwrap();
+            assert_eq!(&*d, b"d");
+            d.commit();
+
+            // And this one is rolled back on drop:
+            assert_eq!(&*receiver.recv_owned().await.unwrap(), b"e");
+        });
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/out-of-order-commits").unwrap();
+            assert_eq!(&*receiver.recv().await.unwrap(), b"e");
+        });
+    }
+
+    #[test]
+    fn test_commit_watermark() {
+        let mut sender = Sender::open("data/commit-watermark").unwrap();
+        for item in [b"a", b"b", b"c"] {
+            sender.try_send(item).unwrap();
+        }
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .max_in_flight(2)
+                .open("data/commit-watermark")
+                .unwrap();
+
+            let a = receiver.recv_owned().await.unwrap();
+            let b = receiver.recv_owned().await.unwrap();
+            b.commit();
+            let c = receiver.recv_owned().await.unwrap();
+            c.commit();
+            assert!(receiver.recv_owned().now_or_never().is_none());
+
+            // The state can't move past `a`:
+            drop(receiver);
+            a.commit();
+        });
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/commit-watermark").unwrap();
+            assert_eq!(&*receiver.recv().await.unwrap(), b"a");
+        });
+    }
+
+    #[test]
+    fn test_dead_letter() {
+        let mut sender = Sender::open("data/dead-letter").unwrap();
+        sender.try_send(b"poison").unwrap();
+        sender.try_send(b"fine").unwrap();
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .dead_letter(DeadLetterQueue::new("data/dead-letter-dlq").max_attempts(3))
+                .open("data/dead-letter")
+                .unwrap();
+
+            // Retried until the last attempt:
+            for _ in 0..3 {
+                let poison = receiver.recv().await.unwrap();
+                assert_eq!(&*poison, b"poison");
+                poison.nack("can't handle this").unwrap();
+            }
+
+            let fine = receiver.recv().await.unwrap();
+            assert_eq!(&*fine, b"fine");
+            fine.commit().unwrap();
+
+            let mut dead_letters = Receiver::open("data/dead-letter-dlq").unwrap();
+            let dead_letter = DeadLetter::decode(&dead_letters.recv().await.unwrap()).unwrap();
+            assert_eq!(dead_letter.attempts, 3);
+            assert_eq!(dead_letter.reason, "can't handle this");
+            assert_eq!(dead_letter.data, b"poison");
+        });
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let mut sender = Sender::open("data/retry-after").unwrap();
+        sender.try_send(b"flaky").unwrap();
+        sender.try_send(b"fine").unwrap();
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/retry-after").unwrap();
+
+            let flaky = receiver.recv().await.unwrap();
+            assert_eq!(&*flaky, b"flaky");
+            flaky.retry_after(Duration::from_millis(100)).unwrap();
+
+            // Not blocked by the delayed element:
+            let fine = receiver.recv().await.unwrap();
+            assert_eq!(&*fine, b"fine");
+            fine.commit().unwrap();
+
+            // The second retry waits twice as long:
+            let start = std::time::Instant::now();
+            let flaky = receiver.recv().await.unwrap();
+            assert_eq!(&*flaky, b"flaky");
+            flaky.retry_after(Duration::from_millis(100)).unwrap();
+            assert!(receiver.try_recv().is_err());
+
+            let flaky = receiver.recv().await.unwrap();
+            assert_eq!(&*flaky, b"flaky");
+            assert!(start.elapsed() >= Duration::from_millis(200));
+            flaky.commit().unwrap();
+        });
+
+        assert_eq!(read_dir("data/retry-after/delayed").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_retry_after_keeps_envelope() {
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .metadata(true)
+            .open("data/retry-after-envelope")
+            .unwrap();
+        let mut headers = std::collections::BTreeMap::new();
+        headers.insert("trace-id".to_owned(), "abc".to_owned());
+        sender.try_send_with_headers(b"tagged", &headers).unwrap();
+        sender
+            .try_send_with_ttl(b"short-lived", Duration::from_millis(200))
+            .unwrap();
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/retry-after-envelope").unwrap();
+
+            let tagged = receiver.recv().await.unwrap();
+            tagged.retry_after(Duration::from_millis(10)).unwrap();
+            let short_lived = receiver.recv().await.unwrap();
+            assert_eq!(&*short_lived, b"short-lived");
+            short_lived.retry_after(Duration::from_millis(400)).unwrap();
+
+            // Same metadata as before:
+            let tagged = receiver.recv().await.unwrap();
+            assert_eq!(&*tagged, b"tagged");
+            assert_eq!(tagged.metadata().unwrap().sequence, 0);
+            assert_eq!(tagged.metadata().unwrap().headers, headers);
+            tagged.commit().unwrap();
+
+            // Expired while set aside:
+            let timeout = Delay::new(Duration::from_millis(800));
+            assert!(receiver.recv_timeout(timeout).await.unwrap().is_none());
+            assert_eq!(receiver.expired(), 1);
+        });
+
+        assert_eq!(
+            read_dir("data/retry-after-envelope/delayed")
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_send_after() {
+        let mut receiver = Receiver::open("data/send-after").unwrap();
+        let mut sender = Sender::open("data/send-after").unwrap();
+
+        let start = std::time::Instant::now();
+        sender
+            .send_after(b"later", Duration::from_millis(300))
+            .unwrap();
+        sender.try_send(b"now").unwrap();
+
+        futures::executor::block_on(async {
+            // Not blocked by the delayed element:
+            let now = receiver.recv().await.unwrap();
+            assert_eq!(&*now, b"now");
+            now.commit().unwrap();
+
+            // Found by the receiver, even if it was open before:
+            let later = receiver.recv().await.unwrap();
+            assert_eq!(&*later, b"later");
+            assert!(start.elapsed() >= Duration::from_millis(300));
+            later.commit().unwrap();
+
+            // Due right away:
+            sender
+                .send_at(b"past", std::time::SystemTime::UNIX_EPOCH)
+                .unwrap();
+
+            // Batches never take it (they only warn), but leave it there:
+            assert!(matches!(
+                receiver.try_recv_batch(1),
+                Err(TryRecvError::QueueEmpty)
+            ));
+            let past = receiver.recv().await.unwrap();
+            assert_eq!(&*past, b"past");
+            past.commit().unwrap();
+        });
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .open("data/ttl")
+            .unwrap();
+        sender
+            .try_send_with_ttl(b"stale", Duration::from_millis(10))
+            .unwrap();
+        sender
+            .try_send_with_ttl(b"fresh", Duration::from_secs(3600))
+            .unwrap();
+        sender.try_send(b"forever").unwrap();
+
+        std::thread::sleep(Duration::from_millis(20));
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .dead_letter(DeadLetterQueue::new("data/ttl-dlq"))
+                .open("data/ttl")
+                .unwrap();
+
+            // Nothing is gotten rid of until a commit moves past it:
+            let fresh = receiver.recv().await.unwrap();
+            assert_eq!(&*fresh, b"fresh");
+            fresh.rollback().unwrap();
+            assert_eq!(receiver.expired(), 0);
+
+            let fresh = receiver.recv().await.unwrap();
+            assert_eq!(&*fresh, b"fresh");
+            fresh.commit().unwrap();
+
+            let forever = receiver.recv().await.unwrap();
+            assert_eq!(&*forever, b"forever");
+            forever.commit().unwrap();
+
+            assert_eq!(receiver.expired(), 1);
+
+            let mut dead_letters = Receiver::open("data/ttl-dlq").unwrap();
+            let guard = dead_letters.recv().await.unwrap();
+            let dead_letter = DeadLetter::decode(&guard).unwrap();
+            assert_eq!(dead_letter.reason, "expired");
+            assert_eq!(dead_letter.data, b"stale");
+            guard.commit().unwrap();
+            assert!(matches!(
+                dead_letters.try_recv(),
+                Err(TryRecvError::QueueEmpty)
+            ));
+        });
+
+        // No room for the expiry time in v1:
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V1))
+            .open("data/ttl-v1")
+            .unwrap();
+        let err = sender
+            .try_send_with_ttl(b"stale", Duration::from_millis(10))
+            .unwrap_err()
+            .unwrap_io();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let open_sender = || {
+            SenderBuilder::new()
+                .record_format(Some(RecordFormat::V2))
+                .metadata(true)
+                .multi_producer(true)
+                .open("data/metadata")
+                .unwrap()
+        };
+
+        let mut headers = std::collections::BTreeMap::new();
+        headers.insert("trace-id".to_owned(), "abc".to_owned());
+
+        let mut sender = open_sender();
+        sender.try_send(b"first").unwrap();
+        sender.try_send_with_headers(b"second", &headers).unwrap();
+
+        // Numbering goes on in a new sender and across senders:
+        let mut other_sender = open_sender();
+        other_sender.try_send(b"third").unwrap();
+        sender.try_send(b"fourth").unwrap();
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/metadata").unwrap();
+
+            for (sequence, expected) in [&b"first"[..], b"second", b"third", b"fourth"]
+                .iter()
+                .enumerate()
+            {
+                let guard = receiver.recv().await.unwrap();
+                assert_eq!(&*guard, expected);
+
+                let metadata = guard.metadata().unwrap();
+                assert_eq!(metadata.sequence, sequence as u64);
+                assert!(metadata.timestamp.elapsed().unwrap() < Duration::from_secs(60));
+                assert_eq!(metadata.headers.len(), if sequence == 1 { 1 } else { 0 });
+
+                guard.commit().unwrap();
+            }
+        });
+
+        // Headers need metadata:
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .open("data/metadata-none")
+            .unwrap();
+        let err = sender
+            .try_send_with_headers(b"data", &headers)
+            .unwrap_err()
+            .unwrap_io();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_schema_versions() {
+        use crate::schema::Schema;
+
+        let open_sender = |version: Option<u32>| {
+            let builder = SenderBuilder::new().record_format(Some(RecordFormat::V2));
+            match version {
+                Some(version) => builder.schema_version(version),
+                None => builder,
+            }
+            .open("data/schema-versions")
+            .unwrap()
+        };
+
+        open_sender(None).try_send(b"zero").unwrap();
+        open_sender(Some(1)).try_send(b"one").unwrap();
+        open_sender(Some(2)).try_send(b"two").unwrap();
+        open_sender(Some(3)).try_send(b"three").unwrap();
+
+        futures::executor::block_on(async {
+            let schema = Schema::new(2)
+                .upgrade(0, |data| Ok([&b"from 0: "[..], &data].concat()))
+                .upgrade(1, |data| Ok([&b"from 1: "[..], &data].concat()));
+            let mut receiver = ReceiverBuilder::new()
+                .schema(schema)
+                .open("data/schema-versions")
+                .unwrap();
+
+            for expected in [&b"from 0: zero"[..], b"from 1: one", b"two"] {
+                let guard = receiver.recv().await.unwrap();
+                assert_eq!(&*guard, expected);
+                guard.commit().unwrap();
+            }
+
+            // Sent by a newer sender:
+            for _ in 0..2 {
+                let err = receiver.recv().await.err().unwrap();
+                assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            }
+        });
+
+        // Without a schema, elements are read as they were sent:
+        let mut iter = QueueIter::open("data/schema-versions").unwrap();
+        assert_eq!(iter.next().unwrap().unwrap(), b"three");
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_compression() {
+        use crate::record::Compression;
+
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .compression(Some(Compression::Zstd(3)))
+            .open("data/compression")
+            .unwrap();
+        let mut dataset = data_lots_of_data().take(100).collect::<Vec<_>>();
+        dataset.push(b"compress me!".repeat(1000));
+        sender.try_send_batch(&dataset).unwrap();
+        drop(sender);
+
+        let iterated = QueueIter::open("data/compression")
+            .unwrap()
+            .collect::<io::Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(iterated, dataset);
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/compression").unwrap();
+            let batch = receiver.recv_batch(dataset.len()).await.unwrap();
+            assert_eq!(&*batch, &dataset);
+            batch.commit().unwrap();
+        });
+
+        // No room for it in v1:
+        let err = SenderBuilder::new()
+            .compression(Some(Compression::Zstd(3)))
+            .open("data/compression-v1")
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() {
+        use crate::encryption::Keyring;
+
+        let open_sender = |keyring| {
+            SenderBuilder::new()
+                .record_format(Some(RecordFormat::V2))
+                .metadata(true)
+                .keyring(keyring)
+                .open("data/encryption")
+                .unwrap()
+        };
+
+        let mut sender = open_sender(Keyring::new(1, [1; 32]));
+        sender.try_send(b"secret").unwrap();
+        drop(sender);
+
+        // Rotate keys (numbering goes on, even if the metadata is encrypted):
+        let keyring = Keyring::new(2, [2; 32]).old_key(1, [1; 32]);
+        let mut sender = open_sender(keyring.clone());
+        let mut headers = std::collections::BTreeMap::new();
+        headers.insert("trace-id".to_owned(), "secret trace".to_owned());
+        sender
+            .try_send_with_headers(b"another secret", &headers)
+            .unwrap();
+        drop(sender);
+
+        let segment = read(segment_filename("data/encryption", 0)).unwrap();
+        assert!(!segment.windows(6).any(|window| window == b"secret"));
+
+        futures::executor::block_on(async {
+            // No keys, no secrets:
+            let mut receiver = Receiver::open("data/encryption").unwrap();
+            let err = receiver.recv().await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            drop(receiver);
+
+            let mut receiver = ReceiverBuilder::new()
+                .keyring(keyring)
+                .open("data/encryption")
+                .unwrap();
+            let first = receiver.recv().await.unwrap();
+            assert_eq!(&*first, b"secret");
+            assert_eq!(first.metadata().unwrap().sequence, 0);
+            first.commit().unwrap();
+
+            let second = receiver.recv().await.unwrap();
+            assert_eq!(&*second, b"another secret");
+            assert_eq!(second.metadata().unwrap().sequence, 1);
+            assert_eq!(second.metadata().unwrap().headers, headers);
+            second.commit().unwrap();
+        });
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption_set_aside() {
+        use crate::encryption::Keyring;
+
+        let keyring = Keyring::new(1, [1; 32]);
+        let contains_secret = |contents: &[u8]| contents.windows(6).any(|w| w == b"secret");
+        let secret_set_aside = || {
+            read_dir("data/encryption-set-aside/delayed")
+                .unwrap()
+                .any(|entry| contains_secret(&read(entry.unwrap().path()).unwrap()))
+        };
+
+        let mut sender = SenderBuilder::new()
+            .record_format(Some(RecordFormat::V2))
+            .keyring(keyring.clone())
+            .open("data/encryption-set-aside")
+            .unwrap();
+        sender
+            .send_after(b"secret", Duration::from_secs(0))
+            .unwrap();
+        assert!(!secret_set_aside());
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .keyring(keyring.clone())
+                .dead_letter(DeadLetterQueue::new("data/encryption-set-aside-dlq").max_attempts(2))
+                .open("data/encryption-set-aside")
+                .unwrap();
+
+            let secret = receiver.recv().await.unwrap();
+            assert_eq!(&*secret, b"secret");
+            secret.retry_after(Duration::from_millis(10)).unwrap();
+            assert!(!secret_set_aside());
+
+            let secret = receiver.recv().await.unwrap();
+            assert_eq!(&*secret, b"secret");
+            secret.nack("still secret").unwrap();
+
+            let segment = read(segment_filename("data/encryption-set-aside-dlq", 0)).unwrap();
+            assert!(!contains_secret(&segment));
+
+            let mut dead_letters = ReceiverBuilder::new()
+                .keyring(keyring)
+                .open("data/encryption-set-aside-dlq")
+                .unwrap();
+            let dead_letter = DeadLetter::decode(&dead_letters.recv().await.unwrap()).unwrap();
+            assert_eq!(dead_letter.reason, "still secret");
+            assert_eq!(dead_letter.data, b"secret");
+        });
+    }
+
+    #[test]
+    fn test_stats() {
+        let err = stats("data/stats").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let mut sender = SenderBuilder::new()
+            .segment_size(32)
+            .open("data/stats")
+            .unwrap();
+        for _ in 0..10 {
+            sender.try_send(b"0123456789").unwrap();
+        }
+
+        // Each element takes 4 bytes of header and 10 of data, three of them
+        // to a segment:
+        let stats = stats("data/stats").unwrap();
+        assert_eq!(stats.pending_elements, 10);
+        assert_eq!(stats.pending_bytes, 10 * 14);
+        assert_eq!(stats.segments, 4);
+        assert_eq!(stats.sender_segment, 3);
+        assert_eq!(stats.sender_position, 14);
+        assert_eq!(stats.receiver_segment, 0);
+        assert_eq!(sender.len().unwrap(), 10);
+
+        futures::executor::block_on(async {
+            let mut receiver = Receiver::open("data/stats").unwrap();
+            receiver.recv_batch(3).await.unwrap().rollback().unwrap();
+            assert_eq!(receiver.len().unwrap(), 10);
+            receiver.recv_batch(3).await.unwrap().commit().unwrap();
+            assert_eq!(receiver.len().unwrap(), 7);
+
+            // The sender only sees what the receiver saved:
+            receiver.save().unwrap();
+            assert_eq!(sender.len().unwrap(), 7);
+
+            // The slowest group counts:
+            let mut fast = ReceiverBuilder::new()
+                .group("fast")
+                .open("data/stats")
+                .unwrap();
+            fast.recv_batch(10).await.unwrap().commit().unwrap();
+            fast.save().unwrap();
+            assert!(fast.is_empty().unwrap());
+            assert_eq!(sender.len().unwrap(), 7);
+        });
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_metrics() {
+        use crate::metrics::{Counter, Gauge, Recorder, Timing};
+        use std::collections::BTreeMap;
+        use std::sync::Mutex;
+
+        #[derive(Default)]
+        struct TestRecorder(Mutex<BTreeMap<&'static str, u64>>);
+
+        impl Recorder for TestRecorder {
+            fn increment(&self, _: &Path, counter: Counter, value: u64) {
+                *self.0.lock().unwrap().entry(counter.name()).or_default() += value;
+            }
+
+            fn gauge(&self, _: &Path, gauge: Gauge, value: u64) {
+                self.0.lock().unwrap().insert(gauge.name(), value);
+            }
+
+            fn timing(&self, _: &Path, timing: Timing, _: Duration) {
+                *self.0.lock().unwrap().entry(timing.name()).or_default() += 1;
+            }
+        }
+
+        let recorder = Arc::new(TestRecorder::default());
+        let mut sender = SenderBuilder::new()
+            .segment_size(32)
+            .recorder(recorder.clone())
+            .open("data/metrics")
+            .unwrap();
+        for _ in 0..10 {
+            sender.try_send(b"0123456789").unwrap();
+        }
+
+        futures::executor::block_on(async {
+            let mut receiver = ReceiverBuilder::new()
+                .save_every_nth(None)
+                .save_every(None)
+                .recorder(recorder.clone())
+                .open("data/metrics")
+                .unwrap();
+            receiver.recv_batch(4).await.unwrap().rollback().unwrap();
+            receiver.recv_batch(4).await.unwrap().commit().unwrap();
+            receiver.save().unwrap();
+            assert!(!recorder.0.lock().unwrap().contains_key("lag_messages"));
+            assert_eq!(receiver.len().unwrap(), 6);
+
+            // Three elements to a segment (see `test_stats`):
+            let metrics = recorder.0.lock().unwrap();
+            assert_eq!(metrics["messages_sent"], 10);
+            assert_eq!(metrics["bytes_sent"], 100);
+            assert_eq!(metrics["segments_created"], 3);
+            assert_eq!(metrics["messages_received"], 8);
+            assert_eq!(metrics["bytes_received"], 80);
+            assert_eq!(metrics["commits"], 1);
+            assert_eq!(metrics["rollbacks"], 1);
+            assert_eq!(metrics["segments_deleted"], 1);
+            assert_eq!(metrics["save"], 1);
+            assert_eq!(metrics["lag_messages"], 6);
+            assert_eq!(metrics["lag_bytes"], 6 * 14);
+        });
+
+        // Waiting for room:
+        let mut sender = SenderBuilder::new()
+            .segment_size(32)
+            .max_queue_size(Some(64))
+            .recorder(recorder.clone())
+            .open("data/metrics-full")
+            .unwrap();
+        for _ in 0..6 {
+            sender.try_send(b"0123456789").unwrap();
+        }
+
+        let blocked = std::thread::spawn(move || {
+            futures::executor::block_on(sender.send(b"0123456789")).unwrap();
+        });
+
+        futures::executor::block_on(async {
+            Delay::new(Duration::from_millis(100)).await;
+            let mut receiver = Receiver::open("data/metrics-full").unwrap();
+            receiver.recv_batch(4).await.unwrap().commit().unwrap();
+        });
+
+        blocked.join().unwrap();
+        assert_eq!(recorder.0.lock().unwrap()["queue_full"], 1);
+    }
+
+    #[test]
+    fn test_sink() {
+        let dataset = data_lots_of_data().take(10_000).collect::<Vec<_>>();
+        let arc_sender = Arc::new(dataset);
+        let arc_receiver = arc_sender.clone();
+
+        // Forward a stream into a small queue, which will fill up:
+        let enqueue = std::thread::spawn(move || {
+            futures::executor::block_on(async {
+                let mut sender = SenderBuilder::new()
+                    .segment_size(512)
+                    .max_queue_size(Some(2048))
+                    .open("data/sink")
+                    .unwrap();
+                futures::stream::iter(&*arc_sender)
+                    .map(Ok)
+                    .forward(&mut sender)
+                    .await
+                    .unwrap();
+            });
+        });
+
+        let dequeue = std::thread::spawn(move || {
+            futures::executor::block_on(async {
+                let mut receiver = Receiver::open("data/sink").unwrap();
+
+                for (i, should_be) in arc_receiver.iter().enumerate() {
+                    let data = receiver.recv().await.unwrap();
+                    assert_eq!(&*data, should_be, "at sample {}", i);
+                    data.commit().unwrap();
+                }
+            });
+        });
+
+        enqueue.join().expect("enqueue thread panicked");
+        dequeue.join().expect("dequeue thread panicked");
+    }
+
+    #[test]
+    fn test_into_stream_rollback() {
+        let mut sender = Sender::open("data/into-stream-rollback").unwrap();
+        sender.try_send(b"first").unwrap();
+        sender.try_send(b"second").unwrap();
+
+        futures::executor::block_on(async move {
+            let mut stream = Receiver::open("data/into-stream-rollback")
+                .unwrap()
+                .into_stream();
+
+            // Dropped and explicitly rolled back guards are received again:
+            let first = stream.next().await.unwrap().unwrap();
+            assert_eq!(&*first, b"first");
+            drop(first);
+
+            let first = stream.next().await.unwrap().unwrap();
+            assert_eq!(&*first, b"first");
+            first.rollback();
+
+            let first = stream.next().await.unwrap().unwrap();
+            assert_eq!(&*first, b"first");
+            first.commit();
+
+            let second = stream.next().await.unwrap().unwrap();
+            assert_eq!(&*second, b"second");
+            second.commit();
+
+            // Let the commit reach the receiver:
+            assert!(stream.next().now_or_never().is_none());
+        });
+
+        // The commits are persisted:
+        futures::executor::block_on(async move {
+            let mut receiver = Receiver::open("data/into-stream-rollback").unwrap();
+            sender.try_send(b"third").unwrap();
+            assert_eq!(&*receiver.recv().await.unwrap(), b"third");
+        });
+    }
+
     // #[test]
     // fn test_stream() {
     //     let data = data_lots_of_data().take(10_000).collect::<Vec<_>>();
@@ -1,43 +1,125 @@
+use futures::channel::oneshot;
 use futures::future;
 use futures::FutureExt;
+use futures_timer::Delay;
 use std::collections::VecDeque;
 use std::fs::*;
 use std::future::Future;
 use std::io::{self};
+use std::num::NonZeroUsize;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
-use std::time::{Duration, Instant};
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::durability::Durability;
+use crate::encryption::{self, Keyring};
 use crate::error::TryRecvError;
 use crate::header::Header;
+#[cfg(feature = "metrics")]
+use crate::metrics::Recorder;
+use crate::metrics::{Counter, Gauge, Metrics, Timing};
+use crate::record::{Envelope, Metadata, RecordFormat};
+use crate::schema::Schema;
 use crate::state::QueueState;
 use crate::state::QueueStatePersistence;
 use crate::sync::{FileGuard, TailFollower};
 use crate::version::check_queue_version;
 
-use super::{segment_filename, HEADER_EOF};
+use super::delayed::{self, delayed_dirname};
+use super::stats;
+use super::{
+    remove_consumed_segments, segment_filename, segments, Archive, DeadLetter, DeadLetterQueue,
+    RecvStream, Sender, HEADER_EOF,
+};
+
+/// The name of the folder where [`Receiver::quarantine_segment`] puts
+/// corrupted segments.
+fn quarantine_dirname<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("quarantine")
+}
 
 /// The name of the receiver lock in the queue folder.
 pub(crate) fn recv_lock_filename<P: AsRef<Path>>(base: P) -> PathBuf {
-    base.as_ref().join("recv.lock")
+    group_recv_lock_filename(base, None)
+}
+
+/// The name of the receiver lock of a consumer group in the queue folder.
+fn group_recv_lock_filename<P: AsRef<Path>>(base: P, group: Option<&str>) -> PathBuf {
+    match group {
+        Some(group) => base.as_ref().join(format!("recv-{}.lock", group)),
+        None => base.as_ref().join("recv.lock"),
+    }
+}
+
+/// Checks whether a consumer group name can be used in file names.
+pub(crate) fn check_group_name(group: &str) -> io::Result<()> {
+    let is_valid = !group.is_empty()
+        && group
+            .chars()
+            .all(|ch| ch.is_ascii_alphanumeric() || ch == '-' || ch == '_');
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "invalid consumer group name `{}`: use only ASCII letters, digits, `-` and `_`",
+                group
+            ),
+        ))
+    }
 }
 
 /// Tries to acquire the receiver lock for a queue.
 pub(crate) fn try_acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
-    FileGuard::try_lock(recv_lock_filename(base.as_ref()))?.ok_or_else(|| {
+    try_acquire_group_recv_lock(base, None)
+}
+
+/// Tries to acquire the receiver lock of a consumer group for a queue.
+pub(crate) fn try_acquire_group_recv_lock<P: AsRef<Path>>(
+    base: P,
+    group: Option<&str>,
+) -> io::Result<FileGuard> {
+    FileGuard::try_lock(group_recv_lock_filename(base.as_ref(), group))?.ok_or_else(|| {
         io::Error::new(
             io::ErrorKind::Other,
-            format!(
-                "queue `{}` receiver side already in use",
-                base.as_ref().to_string_lossy()
-            ),
+            match group {
+                Some(group) => format!(
+                    "queue `{}` consumer group `{}` already in use",
+                    base.as_ref().to_string_lossy(),
+                    group
+                ),
+                None => format!(
+                    "queue `{}` receiver side already in use",
+                    base.as_ref().to_string_lossy()
+                ),
+            },
         )
     })
 }
 
+/// Acquires the receiver lock for a queue, spinning if locked. This is used by
+/// workers, which only hold the lock for the duration of each operation.
+pub(crate) fn spin_acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
+    FileGuard::spin_lock(recv_lock_filename(base.as_ref()))
+}
+
 /// Acquire the receiver lock for a queue, awaiting if locked.
 pub(crate) async fn acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
-    FileGuard::lock(recv_lock_filename(base.as_ref())).await
+    acquire_group_recv_lock(base, None).await
+}
+
+/// Acquire the receiver lock of a consumer group for a queue, awaiting if
+/// locked.
+pub(crate) async fn acquire_group_recv_lock<P: AsRef<Path>>(
+    base: P,
+    group: Option<&str>,
+) -> io::Result<FileGuard> {
+    FileGuard::lock(group_recv_lock_filename(base.as_ref(), group)).await
 }
 
 /// A builder for the receiver side of the queue. Use this if you want to have
@@ -46,6 +128,15 @@ pub(crate) async fn acquire_recv_lock<P: AsRef<Path>>(base: P) -> io::Result<Fil
 pub struct ReceiverBuilder {
     save_every_nth: Option<usize>,
     save_every: Option<Duration>,
+    durability: Durability,
+    group: Option<String>,
+    archive: Option<Archive>,
+    record_format: Option<RecordFormat>,
+    max_in_flight: NonZeroUsize,
+    dead_letter: Option<DeadLetterQueue>,
+    keyring: Option<Keyring>,
+    schema: Option<Schema>,
+    metrics: Metrics,
 }
 
 impl Default for ReceiverBuilder {
@@ -53,6 +144,15 @@ impl Default for ReceiverBuilder {
         ReceiverBuilder {
             save_every_nth: Some(250),
             save_every: Some(Duration::from_millis(350)),
+            durability: Durability::default(),
+            group: None,
+            archive: None,
+            record_format: None,
+            max_in_flight: NonZeroUsize::new(1).expect("not zero"),
+            dead_letter: None,
+            keyring: None,
+            schema: None,
+            metrics: Metrics::default(),
         }
     }
 }
@@ -86,13 +186,137 @@ impl ReceiverBuilder {
         self
     }
 
+    /// Sets how hard the receiver tries to get its state onto the disk each
+    /// time it is saved. With [`Durability::Flush`], a power loss may revert
+    /// the receiver to an older state, replaying elements. With
+    /// [`Durability::Sync`] every save is synced to the disk and with
+    /// [`Durability::Periodic`] saves are synced at most once every interval:
+    /// the last saves stay unsynced until the next save after the interval or
+    /// until the receiver is dropped.
+    ///
+    /// Default value: `Durability::Flush`.
+    pub fn durability(mut self, durability: Durability) -> ReceiverBuilder {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the consumer group this receiver belongs to. Each group has its
+    /// own state, in the file `recv-metadata-<group>`, and its own lock, in the
+    /// file `recv-<group>.lock`. Therefore, many groups can receive the same
+    /// elements independently of each other. A segment is only deleted once
+    /// all groups have moved past it. A group that did not exist yet starts
+    /// from the oldest element still in the queue.
+    ///
+    /// Group names may contain only ASCII letters, digits, `-` and `_`. The
+    /// default receiver (with no group) is a group like any other, whose state
+    /// is in the file `recv-metadata`.
+    ///
+    /// Default value: `None`.
+    pub fn group<S: Into<String>>(mut self, group: S) -> ReceiverBuilder {
+        self.group = Some(group.into());
+        self
+    }
+
+    /// Sets the receiver to archive the segments it has fully consumed, instead
+    /// of deleting them. See [`Archive`] for the details. Note that only this
+    /// receiver archives segments: receivers of other consumer groups still
+    /// delete the segments they are the last to move past, unless they are
+    /// given an archive policy too.
+    ///
+    /// Default value: `None` (segments are deleted).
+    pub fn archive(mut self, archive: Archive) -> ReceiverBuilder {
+        self.archive = Some(archive);
+        self
+    }
+
+    /// The format of the records in the queue. Set this to `None` to use
+    /// whatever format the queue already has (or [`RecordFormat::V1`], for new
+    /// queues). See [`crate::SenderBuilder::record_format`].
+    ///
+    /// Default value: `None`.
+    pub fn record_format(mut self, format: Option<RecordFormat>) -> ReceiverBuilder {
+        self.record_format = format;
+        self
+    }
+
+    /// Sets how many [`OwnedRecvGuard`]s can be out at a time (see
+    /// [`Receiver::recv_owned`]). They can be committed in any order, but the
+    /// saved state only moves past an element once it and all the elements
+    /// before it are committed.
+    ///
+    /// Default value: `1`.
+    ///
+    /// # Panics
+    ///
+    /// This function panics if `max` is zero.
+    pub fn max_in_flight(mut self, max: usize) -> ReceiverBuilder {
+        self.max_in_flight = NonZeroUsize::new(max).expect("got max_in_flight=0");
+        self
+    }
+
+    /// Sets the receiver to move elements that were negatively acknowledged
+    /// too many times (see [`RecvGuard::nack`]) to a dead-letter queue. See
+    /// [`DeadLetterQueue`] for the details.
+    ///
+    /// Default value: `None` (elements are retried forever).
+    pub fn dead_letter(mut self, dead_letter: DeadLetterQueue) -> ReceiverBuilder {
+        self.dead_letter = Some(dead_letter);
+        self
+    }
+
+    /// Sets the keys used to decrypt encrypted elements. See
+    /// [`crate::SenderBuilder::keyring`]. Receiving an element encrypted with
+    /// a key that is not in the keyring returns an error of kind
+    /// `PermissionDenied` and the receiver stays put.
+    ///
+    /// The current key of the keyring also encrypts what the receiver writes:
+    /// elements set aside for later (see
+    /// [`crate::queue::RecvGuard::retry_after`]) and dead letters (see
+    /// [`crate::queue::DeadLetterQueue`]). This needs the `encryption`
+    /// feature.
+    ///
+    /// Default value: `None` (encrypted elements cannot be received).
+    pub fn keyring(mut self, keyring: Keyring) -> ReceiverBuilder {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Upgrades the elements sent with an older schema version (see
+    /// [`crate::SenderBuilder::schema_version`]) to the current version of
+    /// the schema as they are received. Receiving an element of a version
+    /// the schema has no upgrade from returns an error of kind `InvalidData`
+    /// and the receiver stays put, just like for any error of the upgrade
+    /// itself.
+    ///
+    /// Default value: `None` (elements are received as they were sent).
+    pub fn schema(mut self, schema: Schema) -> ReceiverBuilder {
+        self.schema = Some(schema);
+        self
+    }
+
+    /// Sets where the metrics of the receiver go: elements and bytes
+    /// received, commits, rollbacks, segments deleted, how long saving the
+    /// state takes and how far behind the receiver is. See
+    /// [`crate::metrics`].
+    ///
+    /// Default value: no recorder.
+    #[cfg(feature = "metrics")]
+    pub fn recorder(mut self, recorder: Arc<dyn Recorder>) -> ReceiverBuilder {
+        self.metrics = Metrics::new(Some(recorder));
+        self
+    }
+
     /// Opens a queue for reading. The access will be exclusive, based on the
-    /// existence of the temporary file `recv.lock` inside the queue folder.
+    /// existence of the temporary file `recv.lock` (or `recv-<group>.lock`, for
+    /// a consumer group) inside the queue folder.
     ///
     /// # Errors
     ///
     /// This function will return an IO error if the queue is already in use for
-    /// receiving, which is indicated by a lock file. Also, any other IO error
+    /// receiving, which is indicated by a lock file, if the consumer group
+    /// name is not valid or if the queue has a record format other than the
+    /// one requested. An error of kind `Unsupported` is returned if a keyring
+    /// was given without the `encryption` feature. Also, any other IO error
     /// encountered while opening will be sent.
     ///
     /// # Panics
@@ -100,17 +324,27 @@ impl ReceiverBuilder {
     /// This function will panic if it is not able to set up the notification
     /// handler to watch for file changes.
     pub fn open<P: AsRef<Path>>(self, base: P) -> io::Result<Receiver> {
+        if let Some(group) = &self.group {
+            check_group_name(group)?;
+        }
+
+        if self.keyring.is_some() {
+            encryption::check_supported()?;
+        }
+
         // Guarantee that the queue exists:
         create_dir_all(base.as_ref())?;
 
         log::trace!("created queue directory");
 
         // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
-        check_queue_version(base.as_ref())?;
+        let record_format = check_queue_version(base.as_ref(), self.record_format)?;
 
         // Acquire guard and state:
-        let file_guard = try_acquire_recv_lock(base.as_ref())?;
+        let file_guard = try_acquire_group_recv_lock(base.as_ref(), self.group.as_deref())?;
         let mut persistence = QueueStatePersistence::new();
+        persistence.set_durability(self.durability);
+        persistence.set_group(self.group.clone());
         let state = persistence.open(base.as_ref())?;
 
         log::trace!("receiver lock acquired. Receiver state now is {:?}", state);
@@ -121,18 +355,39 @@ impl ReceiverBuilder {
 
         log::trace!("last segment opened fo reading");
 
+        let next_due = delayed::first(&delayed_dirname(base.as_ref(), self.group.as_deref()))?
+            .map(|first| first.due);
+
         Ok(Receiver {
             _file_guard: file_guard,
             tail_follower,
             maybe_header: None,
+            partial: None,
             state,
             initial_state: state,
             base: PathBuf::from(base.as_ref()),
+            group: self.group,
+            archive: self.archive,
+            record_format,
             persistence,
             read_and_unused: VecDeque::new(),
             save_every: self.save_every,
             save_every_nth: self.save_every_nth,
             n_reads: 0,
+            n_expired: 0,
+            expired: VecDeque::new(),
+            in_flight: VecDeque::new(),
+            max_in_flight: self.max_in_flight,
+            dead_letter: self.dead_letter,
+            dead_letter_sender: None,
+            keyring: self.keyring,
+            schema: self.schema,
+            metrics: self.metrics,
+            attempts: None,
+            next_due,
+            last_scanned_at: Instant::now(),
+            delayed: None,
+            due_checked_at: None,
             last_saved_at: Instant::now(),
         })
     }
@@ -143,12 +398,21 @@ impl ReceiverBuilder {
 pub struct Receiver {
     /// The path to the folder holding the queue.
     base: PathBuf,
+    /// The consumer group of this receiver, if not the default one.
+    group: Option<String>,
+    /// Where consumed segments go, if not deleted.
+    archive: Option<Archive>,
+    /// The format of the records in the queue.
+    record_format: RecordFormat,
     /// The acquired receiver lock file for this queue.
     _file_guard: FileGuard,
     /// The current segment being tailed.
     tail_follower: TailFollower,
     /// The last header read from the queue.
-    maybe_header: Option<[u8; 4]>,
+    maybe_header: Option<Header>,
+    /// The chunks of an element read so far, if it is split in many records,
+    /// together with where the first chunk starts.
+    partial: Option<(QueueState, Vec<u8>)>,
     /// The current queue state.
     state: QueueState,
     /// The queue state as it was in the begining of the current transaction.
@@ -159,8 +423,8 @@ pub struct Receiver {
     /// asynchronous context". We need to backup the state of the queue before
     /// the read so as to restore it as the "initial state" (the _actual_ state
     /// of the queue) at the end of a transaction. Otherwise, dataloss would
-    /// occur.
-    read_and_unused: VecDeque<Vec<u8>>,
+    /// occur. Each element comes with its metadata and expiry time, if any.
+    read_and_unused: VecDeque<(Vec<u8>, Extras)>,
     /// Save the queue every n operations
     save_every_nth: Option<usize>,
     /// Save the queue every interval of time. This will be enforced 
@@ -168,8 +432,63 @@ pub struct Receiver {
     save_every: Option<Duration>,
     /// Number of operations done in this `Receiver`
     n_reads: usize,
+    /// Number of expired elements found by this `Receiver`.
+    n_expired: u64,
+    /// The expired elements skipped since the initial state, each with the
+    /// position right after it. They are only gotten rid of once a commit moves
+    /// past them, so that a rollback does not dead-letter them twice.
+    expired: VecDeque<(QueueState, Vec<u8>)>,
     /// Last time the queue was saved:
     last_saved_at: Instant,
+    /// The elements handed out in [`OwnedRecvGuard`]s that the initial state
+    /// has not moved past yet, in the order they were received.
+    in_flight: VecDeque<InFlight>,
+    /// How many owned guards can be out at a time.
+    max_in_flight: NonZeroUsize,
+    /// Where elements negatively acknowledged too many times go, if anywhere.
+    dead_letter: Option<DeadLetterQueue>,
+    /// The sender side of the dead-letter queue.
+    dead_letter_sender: Option<Sender>, // lazy inited!
+    /// The keys to decrypt encrypted elements with, if any.
+    keyring: Option<Keyring>,
+    /// How to upgrade elements of older schema versions, if at all.
+    schema: Option<Schema>,
+    /// Where the metrics go, if anywhere.
+    metrics: Metrics,
+    /// How many times the element at a given state was negatively acknowledged.
+    attempts: Option<(QueueState, u32)>,
+    /// When the first delayed element is due, as far as we know. If this is in
+    /// the past, the delayed folder has to be looked at again.
+    next_due: Option<SystemTime>,
+    /// Last time the delayed folder was looked at.
+    last_scanned_at: Instant,
+    /// The delayed element received in the current transaction, if any, and
+    /// how many attempts it had.
+    delayed: Option<(PathBuf, u32)>,
+    /// Last time a receive method that skips delayed elements looked for due
+    /// ones, to warn about them.
+    due_checked_at: Option<Instant>,
+}
+
+/// What an element was sent with, besides the element itself.
+#[derive(Debug, Default)]
+struct Extras {
+    /// The metadata of the element, if any.
+    metadata: Option<Metadata>,
+    /// When the element expires, if ever.
+    expires_at: Option<SystemTime>,
+    /// The schema version of the element (after any upgrade), if any.
+    schema_version: Option<u32>,
+}
+
+/// An element handed out in an [`OwnedRecvGuard`].
+struct InFlight {
+    /// The queue state right after the element.
+    end: QueueState,
+    /// Where the outcome comes from.
+    outcome: oneshot::Receiver<bool>,
+    /// The outcome, once it arrives: `true` for a commit.
+    is_commit: Option<bool>,
 }
 
 impl Receiver {
@@ -190,9 +509,13 @@ impl Receiver {
         ReceiverBuilder::default().open(base)
     }
 
-    /// Starts a transaction in the queue.
-    fn begin(&mut self) {
+    /// Starts a transaction in the queue. If an [`OwnedRecvGuard`] is still
+    /// out, this waits for it to be committed or rolled back first.
+    async fn begin(&mut self) -> io::Result<()> {
+        self.settle().await?;
         log::debug!("begin transaction in {:?} at {:?}", self.base, self.state);
+
+        Ok(())
     }
 
     /// Puts the queue in another position in another segment. This forcibly
@@ -216,25 +539,61 @@ impl Receiver {
         Ok(())
     }
 
+    /// Goes back to the initial state, so that everything read since then is
+    /// read again, and ends the transaction.
+    fn undo(&mut self) -> io::Result<()> {
+        // Chunks and elements read after the initial state will be read again
+        // (and a delayed element will be received again):
+        self.delayed = None;
+        self.partial = None;
+        self.read_and_unused.clear();
+        self.expired.clear();
+        self.go_to(self.initial_state)?;
+        self.end()
+    }
+
     /// Deletes old segments from a given point in time and makes the current
     /// state the initial state.
     fn end(&mut self) -> io::Result<()> {
+        // A delayed element was received instead of one from the queue:
+        if let Some((path, _)) = self.delayed.take() {
+            log::debug!("end transaction in {:?} at {:?}", self.base, path);
+            return delayed::remove(&path);
+        }
+
+        self.end_at(self.state)
+    }
+
+    /// Deletes old segments from a given point in time and makes the given
+    /// state (the current one or one before it) the initial state.
+    fn end_at(&mut self, state: QueueState) -> io::Result<()> {
         assert!(
-            self.state.segment >= self.initial_state.segment,
+            state.segment >= self.initial_state.segment,
             "advanced to a past position. Initial was {:?}; current is {:?}",
             self.initial_state,
-            self.state
+            state
         );
 
-        for segment_id in self.initial_state.segment..self.state.segment {
-            log::debug!("removing segment {} from {:?}", segment_id, self.base);
-            remove_file(segment_filename(&self.base, segment_id))?;
+        // The expired elements before the state are now gone for good:
+        while matches!(self.expired.front(), Some((end, _)) if *end <= state) {
+            let (_, data) = self.expired.pop_front().expect("there is a front");
+            self.expire(data)?;
+        }
+
+        if state.segment > self.initial_state.segment {
+            let removed = remove_consumed_segments(
+                &self.base,
+                Some((self.group.as_deref(), state)),
+                self.archive.as_ref(),
+            )?;
+            self.metrics
+                .increment(&self.base, Counter::SegmentsDeleted, removed);
         }
 
         log::debug!(
             "end transaction in {:?} at {:?} (from {:?})",
             self.base,
-            self.state,
+            state,
             self.initial_state
         );
 
@@ -250,12 +609,13 @@ impl Receiver {
         // };
 
         // Everything you read has to be used. Otherwise, setting initial state to state loses data.
+        // (Read and unused items come after the current state, so ending before it is fine.)
         assert!(
-            self.read_and_unused.is_empty(),
+            state != self.state || self.read_and_unused.is_empty(),
             "There were read and unused items at the end of transaction. Read and unused queue: {:?}",
             self.read_and_unused
         );
-        self.initial_state = self.state;
+        self.initial_state = state;
 
         // Alternatively... if you make read and unused VecDeque<(Vec<u8>, QueueState)> to backup the
         // state, you can do the following (deprecated code):
@@ -281,7 +641,7 @@ impl Receiver {
     async fn read_header(&mut self) -> io::Result<Header> {
         // If the header was already read (by an incomplete operation), use it!
         if let Some(header) = self.maybe_header {
-            return Ok(Header::decode(header));
+            return Ok(header);
         }
 
         // Read header:
@@ -300,9 +660,14 @@ impl Receiver {
             self.tail_follower.read_exact(&mut header).await?;
         }
 
+        // A bad length would send us to the middle of nowhere. So, stop here:
+        let (segment, position) = (self.state.segment, self.state.position);
+        let decoded = self
+            .record_format
+            .decode_header(header, segment, position)?;
+
         // Now, you set the header!
-        self.maybe_header = Some(header.clone());
-        let decoded = Header::decode(header);
+        self.maybe_header = Some(decoded);
         self.state.advance_position(4);
 
         log::trace!("got header {:?} (read {} bytes)", header, decoded.len());
@@ -320,28 +685,112 @@ impl Receiver {
     /// polled to completion, as, e.g., when calling `select`, the operation
     /// will count as not done.
     async fn read_one(&mut self) -> io::Result<()> {
-        // Get the length:
-        let header = self.read_header().await?;
+        loop {
+            // Get the length:
+            let header = match self.read_header().await {
+                Ok(header) => header,
+                Err(err) => {
+                    self.go_back_to_element(self.state)?;
+                    return Err(err);
+                }
+            };
+
+            // Where the record starts (the header may have moved us to a new segment):
+            let record_state = QueueState {
+                position: self.state.position - 4,
+                ..self.state
+            };
+
+            // With the length, read the data:
+            let mut data = vec![0; header.len() as usize];
+            if let Err(err) = self.tail_follower.read_exact(&mut data).await {
+                self.go_back_to_element(record_state)?;
+                return Err(err);
+            }
 
-        // With the length, read the data:
-        let mut data = vec![0; header.len() as usize];
-        self.tail_follower
-            .read_exact(&mut data)
-            .await
-            .expect("poisoned queue");
+            self.state.advance_position(data.len() as u64);
 
-        self.state.advance_position(data.len() as u64);
+            // We are done! Unset header:
+            self.maybe_header = None;
 
-        // We are done! Unset header:
-        self.maybe_header = None;
+            // Check the record:
+            let (segment, position) = (record_state.segment, record_state.position);
+            let record = match self.record_format.decode(data, segment, position) {
+                Ok(record) => record,
+                Err(err) => {
+                    self.go_back_to_element(record_state)?;
+                    return Err(err);
+                }
+            };
+
+            // Only a piece of the element. Keep it and read the next one:
+            if record.is_continued {
+                self.partial
+                    .get_or_insert_with(|| (record_state, vec![]))
+                    .1
+                    .extend(record.payload);
+                continue;
+            }
+
+            let (element_state, data) = match self.partial.take() {
+                Some((element_state, mut data)) => {
+                    data.extend(record.payload);
+                    (element_state, data)
+                }
+                None => (record_state, record.payload),
+            };
+
+            // Compression and encryption cover the whole element, not each chunk:
+            let decoded = record.encoding.decode(data, self.keyring.as_ref());
+            let (data, sealed_metadata) = match decoded {
+                Ok(decoded) => decoded,
+                Err(err) => {
+                    self.go_to(element_state)?;
+                    return Err(err);
+                }
+            };
 
-        // Ready to be used:
-        self.read_and_unused.push_back(data);
+            // Expired elements are never delivered (see `Receiver::end_at`):
+            if let Some(expires_at) = record.expires_at {
+                if expires_at <= SystemTime::now() {
+                    self.expired.push_back((self.state, data));
+                    continue;
+                }
+            }
 
-        // Bookkeeping:
-        self.n_reads += 1;
+            let (data, schema_version) = match self.upgrade(record.schema_version, data) {
+                Ok(upgraded) => upgraded,
+                Err(err) => {
+                    self.go_to(element_state)?;
+                    return Err(err);
+                }
+            };
 
-        Ok(())
+            // Ready to be used:
+            self.count_received(&data);
+            let extras = Extras {
+                metadata: sealed_metadata.or(record.metadata),
+                expires_at: record.expires_at,
+                schema_version,
+            };
+            self.read_and_unused.push_back((data, extras));
+
+            // Bookkeeping:
+            self.n_reads += 1;
+
+            return Ok(());
+        }
+    }
+
+    /// After an error, goes back to where the element being read starts (the
+    /// given record or the first chunk read so far). If the error was due to a
+    /// corrupted record, this means we stay put, so that the error does not go
+    /// away by just trying again.
+    fn go_back_to_element(&mut self, record_state: QueueState) -> io::Result<()> {
+        let element_state = self.partial.take().map_or(record_state, |(start, _)| start);
+        self.maybe_header = None;
+
+        self.go_to(element_state)
     }
 
     /// Reads one element from the queue until a future elapses. If the future
@@ -362,6 +811,19 @@ impl Receiver {
         }
     }
 
+    /// Takes the next element from the "read and unused" queue, reading one
+    /// from the disk if there is none. This operation is atomic.
+    async fn pop_one(&mut self) -> io::Result<(Vec<u8>, Extras)> {
+        if self.read_and_unused.is_empty() {
+            self.read_one().await?;
+        }
+
+        Ok(self
+            .read_and_unused
+            .pop_front()
+            .expect("guaranteed to yield an element"))
+    }
+
     /// Drains `n` elements from the "read and unused" queue into a vector. This
     /// operation is "atomic in an async context", since it is not `async`. For a
     /// function to enjoy the same guarantee, this function must only be called
@@ -372,7 +834,7 @@ impl Receiver {
         // (careful! need to check if read something to avoid an eroneous POP
         // from the queue)
         if n > 0 {
-            while let Some(element) = self.read_and_unused.pop_front() {
+            while let Some((element, _)) = self.read_and_unused.pop_front() {
                 data.push(element);
 
                 if data.len() == n {
@@ -396,7 +858,334 @@ impl Receiver {
     /// implemented this way because no errors are allowed to propagate on drop
     /// and panicking will abort the program if drop is called during a panic.
     pub fn save(&mut self) -> io::Result<()> {
-        self.persistence.save(&self.initial_state) // this aviods saving an in-flight
+        let started_at = Instant::now();
+        self.persistence.save(&self.initial_state)?; // this aviods saving an in-flight
+        self.last_saved_at = Instant::now();
+
+        if self.metrics.is_enabled() {
+            self.metrics
+                .timing(&self.base, Timing::Save, started_at.elapsed());
+
+            // The state is saved by now. So, this is no reason to fail:
+            match stats::pending_bytes(&self.base, self.initial_state) {
+                Ok(bytes) => self.metrics.gauge(&self.base, Gauge::LagBytes, bytes),
+                Err(err) => log::warn!("could not measure lag of {:?}: {}", self.base, err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Brings an element to the current version of the schema, if the
+    /// receiver has one. Returns the element and its schema version.
+    fn upgrade(&self, version: Option<u32>, data: Vec<u8>) -> io::Result<(Vec<u8>, Option<u32>)> {
+        match &self.schema {
+            Some(schema) => Ok((schema.apply(version, data)?, Some(schema.current_version()))),
+            None => Ok((data, version)),
+        }
+    }
+
+    /// Counts an element that was just received.
+    fn count_received(&self, data: &[u8]) {
+        self.metrics
+            .increment(&self.base, Counter::MessagesReceived, 1);
+        self.metrics
+            .increment(&self.base, Counter::BytesReceived, data.len() as u64);
+    }
+
+    /// The number of expired elements this receiver found since it was opened
+    /// (see [`Sender::try_send_with_ttl`]). These were dropped or, with a
+    /// dead-letter policy (see [`ReceiverBuilder::dead_letter`]), sent to the
+    /// dead-letter queue instead of being received. Expired elements are only
+    /// counted (and dead-lettered) once a transaction after them is committed.
+    pub fn expired(&self) -> u64 {
+        self.n_expired
+    }
+
+    /// The number of elements in the queue that this receiver has not
+    /// committed yet, in-flight elements included. Delayed elements (see
+    /// [`Sender::send_at`] and [`RecvGuard::retry_after`]) are never counted,
+    /// not even once they are due, since they are kept outside of the queue.
+    /// This reads the header of every pending element, so it takes longer the
+    /// more elements are pending. See also [`crate::queue::stats`].
+    ///
+    /// If the receiver has a recorder (see `ReceiverBuilder::recorder`), the
+    /// count is also recorded as the lag in messages. Saving the state never
+    /// counts the pending elements, so call this every now and then to keep
+    /// that gauge up to date.
+    pub fn len(&self) -> io::Result<u64> {
+        let (elements, _) = stats::pending(&self.base, self.record_format, self.initial_state)?;
+        self.metrics.gauge(&self.base, Gauge::LagMessages, elements);
+        Ok(elements)
+    }
+
+    /// Whether this receiver has committed every element in the queue. See
+    /// [`Receiver::len`].
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Goes back to the oldest element still in the queue, so that everything
+    /// from there on is received again. This is mostly useful for queues with a
+    /// [`crate::queue::Retention`] policy, which keep consumed segments around.
+    /// The new state is saved right away.
+    pub fn rewind(&mut self) -> io::Result<()> {
+        let oldest_segment = segments(&self.base)?
+            .first()
+            .copied()
+            .unwrap_or(self.state.segment);
+        let state = QueueState {
+            segment: u64::min(oldest_segment, self.state.segment),
+            ..QueueState::default()
+        };
+
+        log::debug!("rewinding {:?} to {:?}", self.base, state);
+
+        // Forget anything in the middle of being read (or committed):
+        self.maybe_header = None;
+        self.partial = None;
+        self.read_and_unused.clear();
+        self.expired.clear();
+        self.in_flight.clear();
+
+        self.go_to(state)?;
+        self.initial_state = state;
+        self.save()
+    }
+
+    /// Skips the rest of the current segment and moves on to the next one.
+    /// Use this to get past a corrupted record (see [`crate::Corrupted`]), at
+    /// the cost of losing whatever else is in the same segment. If the segment
+    /// is still being written to, what is sent to it from now on is lost too.
+    ///
+    /// Elements that were already read ahead (e.g., by a batch that stopped at
+    /// the corrupted record) are kept and received next. If there are none and
+    /// no [`OwnedRecvGuard`] is out, the new state is saved right away.
+    /// Otherwise, it is saved on the next commit.
+    pub fn skip_segment(&mut self) -> io::Result<()> {
+        let mut state = self.state;
+        state.advance_segment();
+
+        log::warn!("skipping to segment {} in {:?}", state.segment, self.base);
+
+        // Forget anything in the middle of being read:
+        self.maybe_header = None;
+        self.partial = None;
+
+        self.go_to(state)?;
+
+        if self.read_and_unused.is_empty() && self.in_flight.is_empty() {
+            self.end()?;
+            self.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Moves the current segment to the `quarantine` folder inside the queue
+    /// folder, where it can be inspected later, and then skips it, as in
+    /// [`Receiver::skip_segment`]. Note that the segment is then gone for all
+    /// the consumer groups of the queue, not only for this receiver.
+    pub fn quarantine_segment(&mut self) -> io::Result<()> {
+        let quarantine_dir = quarantine_dirname(&self.base);
+        create_dir_all(&quarantine_dir)?;
+
+        log::warn!(
+            "quarantining segment {} in {:?}",
+            self.state.segment,
+            self.base
+        );
+
+        let outcome = rename(
+            segment_filename(&self.base, self.state.segment),
+            segment_filename(&quarantine_dir, self.state.segment),
+        );
+        match outcome {
+            // Someone else got here first:
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+
+        self.skip_segment()
+    }
+
+    /// Counts one more failed attempt at the element received in the current
+    /// transaction and returns how many there were so far.
+    fn count_attempt(&mut self) -> u32 {
+        // Delayed elements carry their own count:
+        if let Some((_, attempts)) = self.delayed {
+            return attempts + 1;
+        }
+
+        let attempts = match self.attempts {
+            Some((state, attempts)) if state == self.initial_state => attempts + 1,
+            _ => 1,
+        };
+        self.attempts = Some((self.initial_state, attempts));
+
+        attempts
+    }
+
+    /// Sends an element to the dead-letter queue, if there is one and if the
+    /// element had too many attempts. Returns whether it was sent.
+    fn maybe_send_dead_letter(&mut self, dead_letter: &DeadLetter) -> io::Result<bool> {
+        match &self.dead_letter {
+            Some(dead_letter_queue) if dead_letter_queue.is_dead(dead_letter.attempts) => {
+                log::warn!(
+                    "moving element from {:?} to dead-letter queue {:?} after {} attempts: {}",
+                    self.base,
+                    dead_letter_queue.base(),
+                    dead_letter.attempts,
+                    dead_letter.reason
+                );
+
+                self.send_dead_letter(dead_letter)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Sends an element to the dead-letter queue. There has to be one.
+    fn send_dead_letter(&mut self, dead_letter: &DeadLetter) -> io::Result<()> {
+        if self.dead_letter_sender.is_none() {
+            let dead_letter_queue = self.dead_letter.as_ref().expect("has a dead-letter queue");
+            self.dead_letter_sender = Some(dead_letter_queue.open_sender(self.keyring.as_ref())?);
+        }
+
+        let sender = self.dead_letter_sender.as_mut().unwrap(); // because if was not Some, now it is.
+        dead_letter.send_to(sender)
+    }
+
+    /// Gets rid of an expired element: it goes to the dead-letter queue, if
+    /// there is one, or else it is dropped.
+    fn expire(&mut self, data: Vec<u8>) -> io::Result<()> {
+        if self.dead_letter.is_some() {
+            log::debug!("expired element in {:?} is a dead letter", self.base);
+            self.send_dead_letter(&DeadLetter {
+                attempts: 0,
+                reason: "expired".to_owned(),
+                data,
+            })?;
+        } else {
+            log::debug!("dropping expired element from {:?}", self.base);
+        }
+
+        self.n_expired += 1;
+
+        Ok(())
+    }
+
+    /// Puts an element in the delayed folder, together with what it was sent
+    /// with, to be received again once it is due.
+    fn delay(
+        &mut self,
+        data: &[u8],
+        extras: &Extras,
+        attempts: u32,
+        due: SystemTime,
+    ) -> io::Result<()> {
+        log::debug!("delaying element in {:?} until {:?}", self.base, due);
+
+        let envelope = Envelope {
+            expires_at: extras.expires_at,
+            metadata: extras.metadata.as_ref(),
+            schema_version: extras.schema_version,
+            keyring: self.keyring.as_ref(),
+            ..Envelope::default()
+        };
+        let dir = delayed_dirname(&self.base, self.group.as_deref());
+        delayed::put(&dir, data, &envelope, attempts, due)?;
+        self.next_due = Some(self.next_due.map_or(due, |next_due| next_due.min(due)));
+
+        Ok(())
+    }
+
+    /// Takes a delayed element that is due, if any. It is only removed from
+    /// the delayed folder when the transaction is committed. Expired elements
+    /// are never delivered, just like in the queue.
+    fn take_due(&mut self) -> io::Result<Option<(Vec<u8>, Extras)>> {
+        let now = SystemTime::now();
+        let is_due = matches!(self.next_due, Some(due) if due <= now);
+
+        // Senders may put elements there at any time (see `Sender::send_at`).
+        // So, look again every now and then:
+        if !is_due && self.last_scanned_at.elapsed() < delayed::SCAN_INTERVAL {
+            return Ok(None);
+        }
+
+        self.last_scanned_at = Instant::now();
+        let dir = delayed_dirname(&self.base, self.group.as_deref());
+        loop {
+            match delayed::first(&dir)? {
+                Some(first) if first.due <= now => {
+                    let element = delayed::read(&first.path, self.keyring.as_ref())?;
+
+                    if matches!(element.expires_at, Some(expires_at) if expires_at <= now) {
+                        self.expire(element.data)?;
+                        delayed::remove(&first.path)?;
+                        continue;
+                    }
+
+                    let (data, schema_version) =
+                        self.upgrade(element.schema_version, element.data)?;
+
+                    self.delayed = Some((first.path, element.attempts));
+                    self.count_received(&data);
+                    let extras = Extras {
+                        metadata: element.metadata,
+                        expires_at: element.expires_at,
+                        schema_version,
+                    };
+
+                    break Ok(Some((data, extras)));
+                }
+                first => {
+                    self.next_due = first.map(|first| first.due);
+                    break Ok(None);
+                }
+            }
+        }
+    }
+
+    /// Takes the next element, either a delayed element that is due or one
+    /// from the queue, whichever comes first. This operation is atomic.
+    async fn pop_one_or_due(&mut self) -> io::Result<(Vec<u8>, Extras)> {
+        loop {
+            if let Some(element) = self.take_due()? {
+                return Ok(element);
+            }
+
+            // Most of the time, there is something in the queue already:
+            if let Some(data) = self.pop_one().now_or_never() {
+                return data;
+            }
+
+            let due_in = self
+                .next_due
+                .and_then(|due| due.duration_since(SystemTime::now()).ok())
+                .map_or(delayed::SCAN_INTERVAL, |due_in| {
+                    due_in.min(delayed::SCAN_INTERVAL)
+                });
+
+            if let future::Either::Left((data, _)) =
+                future::select(Box::pin(self.pop_one()), Delay::new(due_in)).await
+            {
+                return data;
+            }
+        }
+    }
+
+    /// Warns if there are delayed elements due, which the receive method
+    /// `method` will never take. This looks at the delayed folder at most
+    /// once every `delayed::SCAN_INTERVAL`.
+    fn warn_if_due(&mut self, method: &str) -> io::Result<()> {
+        if matches!(self.due_checked_at, Some(at) if at.elapsed() < delayed::SCAN_INTERVAL) {
+            return Ok(());
+        }
+
+        self.due_checked_at = Some(Instant::now());
+        delayed::warn_if_due(&delayed_dirname(&self.base, self.group.as_deref()), method)
     }
 
     fn maybe_save(&mut self) -> io::Result<()> {
@@ -426,20 +1215,13 @@ impl Receiver {
     /// it is not able to set up the notification handler to watch for file
     /// changes.
     pub async fn recv(&mut self) -> io::Result<RecvGuard<'_, Vec<u8>>> {
-        self.begin();
-
-        let data = if let Some(data) = self.read_and_unused.pop_front() {
-            data
-        } else {
-            self.read_one().await?;
-            self.read_and_unused
-                .pop_front()
-                .expect("guaranteed to yield an element")
-        };
+        self.begin().await?;
+        let (data, extras) = self.pop_one_or_due().await?;
 
         Ok(RecvGuard {
             receiver: self,
             item: Some(data),
+            extras,
             was_finished: false,
         })
     }
@@ -477,23 +1259,18 @@ impl Receiver {
     where
         F: Future<Output = ()> + Unpin,
     {
-        self.begin();
+        self.begin().await?;
 
-        let data = if let Some(data) = self.read_and_unused.pop_front() {
-            data
-        } else {
-            if self.read_one_timeout(timeout).await? {
-                self.read_and_unused
-                    .pop_front()
-                    .expect("guaranteed to yield an element")
-            } else {
-                return Ok(None);
-            }
+        let element = match future::select(Box::pin(self.pop_one_or_due()), timeout).await {
+            future::Either::Left((element, _)) => element?,
+            future::Either::Right(_) => return Ok(None),
         };
+        let (data, extras) = element;
 
         Ok(Some(RecvGuard {
             receiver: self,
             item: Some(data),
+            extras,
             was_finished: false,
         }))
     }
@@ -513,7 +1290,8 @@ impl Receiver {
     /// it is not able to set up the notification handler to watch for file
     /// changes.
     pub async fn recv_batch(&mut self, n: usize) -> io::Result<RecvGuard<'_, Vec<Vec<u8>>>> {
-        self.begin();
+        self.begin().await?;
+        self.warn_if_due("recv_batch")?;
 
         // First, fetch what is missing from the disk:
         if n > self.read_and_unused.len() {
@@ -528,6 +1306,7 @@ impl Receiver {
         Ok(RecvGuard {
             receiver: self,
             item: Some(data),
+            extras: Extras::default(),
             was_finished: false,
         })
     }
@@ -573,7 +1352,8 @@ impl Receiver {
     where
         F: Future<Output = ()> + Unpin,
     {
-        self.begin();
+        self.begin().await?;
+        self.warn_if_due("recv_batch_timeout")?;
         let mut n_read = 0;
 
         // First, fetch what is missing from the disk:
@@ -593,6 +1373,7 @@ impl Receiver {
         Ok(RecvGuard {
             receiver: self,
             item: Some(data),
+            extras: Extras::default(),
             was_finished: false,
         })
     }
@@ -632,7 +1413,8 @@ impl Receiver {
         P: FnMut(Option<&[u8]>) -> Fut,
         Fut: std::future::Future<Output = bool>,
     {
-        self.begin();
+        self.begin().await?;
+        self.warn_if_due("recv_until")?;
         let mut n_read = 0;
 
         // Prepare:
@@ -646,7 +1428,7 @@ impl Receiver {
                 self.read_one().await?;
             }
 
-            let item_ref = &self.read_and_unused[n_read];
+            let item_ref = &self.read_and_unused[n_read].0;
 
             if !predicate(Some(item_ref)).await {
                 n_read += 1;
@@ -661,6 +1443,7 @@ impl Receiver {
         Ok(RecvGuard {
             receiver: self,
             item: Some(data),
+            extras: Extras::default(),
             was_finished: false,
         })
     }
@@ -701,11 +1484,174 @@ impl Receiver {
             .now_or_never(),
         )
     }
+
+    /// Retrieves an element from the queue in a guard that does not borrow
+    /// the receiver. The returned [`OwnedRecvGuard`] is `Send + 'static`, so
+    /// it can be moved into a spawned task or thread and committed (or rolled
+    /// back) from there. The outcome is sent back to the receiver, which
+    /// applies it on the next receive.
+    ///
+    /// Up to [`ReceiverBuilder::max_in_flight`] owned guards (one, by default)
+    /// can be out at a time. Beyond that, this function waits for one of them
+    /// to be committed or rolled back. Guards can be committed in any order,
+    /// but the saved state only moves over the contiguous run of committed
+    /// elements at the front, so that nothing is lost in a crash (elements
+    /// committed after one still out may be received again). A rollback sends
+    /// the receiver back to the first element not committed: everything from
+    /// there on is received again, including the elements in the guards still
+    /// out, whose outcomes are ignored from then on.
+    ///
+    /// The other receive methods first wait for all owned guards to be
+    /// committed or rolled back (and the `try_recv*` methods return
+    /// [`TryRecvError::QueueEmpty`] in the meantime).
+    ///
+    /// This operation is atomic. If the returned future is not polled to
+    /// completion, as, e.g., when calling `select`, the operation will be
+    /// undone.
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Receiver::recv`], this function returns any IO
+    /// error encountered while applying the outcome of the previous owned
+    /// guard.
+    ///
+    /// # Panics
+    ///
+    /// This function will panic if it has to start reading a new segment and
+    /// it is not able to set up the notification handler to watch for file
+    /// changes.
+    pub async fn recv_owned(&mut self) -> io::Result<OwnedRecvGuard> {
+        self.warn_if_due("recv_owned")?;
+
+        // Pick up whatever has arrived, so that the state moves on as soon as
+        // possible, and wait for room if needed:
+        future::poll_fn(|context| self.poll_outcomes(context)).now_or_never();
+        loop {
+            self.apply_outcomes()?;
+
+            let n_out = self
+                .in_flight
+                .iter()
+                .filter(|in_flight| in_flight.is_commit.is_none())
+                .count();
+            if n_out < self.max_in_flight.get() {
+                break;
+            }
+
+            future::poll_fn(|context| self.poll_outcomes(context)).await;
+        }
+
+        let (item, extras) = self.pop_one().await?;
+
+        // The outcome of the transaction will come from the owned guard:
+        let (outcome_sender, outcome) = oneshot::channel();
+        self.in_flight.push_back(InFlight {
+            end: self.state,
+            outcome,
+            is_commit: None,
+        });
+
+        Ok(OwnedRecvGuard {
+            item,
+            metadata: extras.metadata,
+            outcome: Some(outcome_sender),
+        })
+    }
+
+    /// Picks up the outcomes sent by the owned guards since the last time.
+    /// This is ready if there is any new outcome.
+    fn poll_outcomes(&mut self, context: &mut Context<'_>) -> Poll<()> {
+        let mut has_new = false;
+
+        for in_flight in &mut self.in_flight {
+            if in_flight.is_commit.is_none() {
+                if let Poll::Ready(outcome) = in_flight.outcome.poll_unpin(context) {
+                    // A guard that is gone without a word (e.g., in a panic) rolls back:
+                    in_flight.is_commit = Some(outcome.unwrap_or(false));
+                    has_new = true;
+                }
+            }
+        }
+
+        if has_new {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+
+    /// Applies the outcomes picked up so far. The initial state moves to the
+    /// end of the contiguous run of committed elements at the front (the
+    /// watermark). If any element was rolled back, the receiver then goes
+    /// back to the watermark and forgets about all the owned guards still out.
+    fn apply_outcomes(&mut self) -> io::Result<()> {
+        let mut watermark = None;
+        let mut n_commits = 0;
+        while let Some(InFlight {
+            is_commit: Some(true),
+            end,
+            ..
+        }) = self.in_flight.front()
+        {
+            watermark = Some(*end);
+            n_commits += 1;
+            self.in_flight.pop_front();
+        }
+
+        if let Some(watermark) = watermark {
+            self.end_at(watermark)?;
+            self.metrics
+                .increment(&self.base, Counter::Commits, n_commits);
+        }
+
+        let n_rollbacks = self
+            .in_flight
+            .iter()
+            .filter(|in_flight| in_flight.is_commit == Some(false))
+            .count();
+        if n_rollbacks > 0 {
+            self.in_flight.clear();
+            self.undo()?;
+            self.metrics
+                .increment(&self.base, Counter::Rollbacks, n_rollbacks as u64);
+        }
+
+        Ok(())
+    }
+
+    /// Waits for all the owned guards out to be committed or rolled back and
+    /// applies their outcomes.
+    async fn settle(&mut self) -> io::Result<()> {
+        loop {
+            self.apply_outcomes()?;
+
+            if self.in_flight.is_empty() {
+                return Ok(());
+            }
+
+            future::poll_fn(|context| self.poll_outcomes(context)).await;
+        }
+    }
+
+    /// Turns this receiver into a [`futures::Stream`] of elements. Each
+    /// element comes in an [`OwnedRecvGuard`], which has to be committed, just
+    /// like a [`RecvGuard`]. By default, the stream only reads the next element
+    /// after the previous one is committed or rolled back. Set
+    /// [`ReceiverBuilder::max_in_flight`] to have many elements out at a time,
+    /// e.g., to process them with `StreamExt::for_each_concurrent`. See
+    /// [`Receiver::recv_owned`] for how they are committed.
+    ///
+    /// The stream ends right after yielding an error and never ends
+    /// otherwise. See [`RecvStream::into_inner`] for getting past a corrupted
+    /// record.
+    pub fn into_stream(self) -> RecvStream {
+        RecvStream::new(self)
+    }
 }
 
 impl Drop for Receiver {
     fn drop(&mut self) {
-        if let Err(err) = self.save() {
+        if let Err(err) = self.save().and_then(|_| self.persistence.sync_pending()) {
             log::error!(
                 "(probably) could not save queue state during `Drop`: {}",
                 err
@@ -728,6 +1674,7 @@ impl Drop for Receiver {
 pub struct RecvGuard<'a, T> {
     receiver: &'a mut Receiver,
     item: Option<T>,
+    extras: Extras, // only for single elements!
     was_finished: bool,
 }
 
@@ -768,15 +1715,20 @@ impl<'a, T> RecvGuard<'a, T> {
     pub fn commit(mut self) -> io::Result<()> {
         self.receiver.end()?;
         self.was_finished = true;
+        self.receiver
+            .metrics
+            .increment(&self.receiver.base, Counter::Commits, 1);
 
         Ok(())
     }
 
     /// Same as rollback, but doesn't consume the guard. This is for internal use only.
     fn rollback_mut(&mut self) -> io::Result<()> {
-        self.receiver.go_to(self.receiver.initial_state)?;
-        self.receiver.end()?;
+        self.receiver.undo()?;
         self.was_finished = true;
+        self.receiver
+            .metrics
+            .increment(&self.receiver.base, Counter::Rollbacks, 1);
 
         Ok(())
     }
@@ -794,3 +1746,166 @@ impl<'a, T> RecvGuard<'a, T> {
         self.rollback_mut()
     }
 }
+
+impl<'a> RecvGuard<'a, Vec<u8>> {
+    /// The metadata of the element, if it was sent with any (see
+    /// [`crate::queue::SenderBuilder::metadata`]). Elements set aside with
+    /// [`RecvGuard::retry_after`] keep theirs. Elements sent with
+    /// [`Sender::send_at`] have none.
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.extras.metadata.as_ref()
+    }
+
+    /// Negatively acknowledges the element, saying why it could not be
+    /// processed. Usually, this is just a rollback, but the receiver counts
+    /// how many times in a row this was done to the same element. If the
+    /// receiver has a dead-letter policy (see [`ReceiverBuilder::dead_letter`])
+    /// and the element was negatively acknowledged too many times, it is moved
+    /// to the dead-letter queue together with the reason and the transaction
+    /// is committed, so that the receiver moves on.
+    ///
+    /// The counts are kept in memory and start over when the queue is opened
+    /// again.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any IO error encountered while rolling back or
+    /// while sending the element to the dead-letter queue and committing. If
+    /// sending to the dead-letter queue fails, the transaction is rolled back.
+    pub fn nack(mut self, reason: &str) -> io::Result<()> {
+        let dead_letter = DeadLetter {
+            attempts: self.receiver.count_attempt(),
+            reason: reason.to_owned(),
+            data: self.item.take().expect("unreachable"),
+        };
+
+        if self.receiver.maybe_send_dead_letter(&dead_letter)? {
+            self.commit()
+        } else if self.receiver.delayed.is_some() {
+            // A delayed element is put back with its new count, due right away:
+            let now = SystemTime::now();
+            self.receiver
+                .delay(&dead_letter.data, &self.extras, dead_letter.attempts, now)?;
+            self.commit()
+        } else {
+            self.rollback()
+        }
+    }
+
+    /// Commits the transaction, but keeps the element aside, to be received
+    /// again once the given delay is over, instead of right away, as with a
+    /// rollback. The delay doubles with every attempt: the `n`-th
+    /// retry of the same element waits `delay * 2^(n - 1)` (up to `n = 17`).
+    /// Meanwhile, the receiver goes on with the next elements. Use this to
+    /// back off from dependencies with transient outages.
+    ///
+    /// Delayed elements are kept in the folder `delayed` (or
+    /// `delayed-<group>`, for a consumer group) inside the queue folder, one
+    /// file per element. They are received by [`Receiver::recv`],
+    /// [`Receiver::try_recv`] and [`Receiver::recv_timeout`] as soon as they
+    /// are due, ahead of the elements in the queue, but never by the other
+    /// receive methods, which only log a warning when some are due. Retries
+    /// count as attempts for the dead-letter policy (see [`RecvGuard::nack`]):
+    /// after too many of them, the element goes to the dead-letter queue
+    /// instead. The element keeps its metadata and its
+    /// time to live (see [`Sender::try_send_with_ttl`]): if it expires while
+    /// set aside, it is not received again.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any IO error encountered while delaying the
+    /// element and committing. If delaying fails, the transaction is rolled
+    /// back.
+    pub fn retry_after(mut self, delay: Duration) -> io::Result<()> {
+        let dead_letter = DeadLetter {
+            attempts: self.receiver.count_attempt(),
+            reason: "retried too many times".to_owned(),
+            data: self.item.take().expect("unreachable"),
+        };
+
+        if !self.receiver.maybe_send_dead_letter(&dead_letter)? {
+            let backoff = delay.saturating_mul(1 << u32::min(dead_letter.attempts - 1, 16));
+            let due = SystemTime::now().checked_add(backoff).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("delay of {:?} is too long", backoff),
+                )
+            })?;
+
+            self.receiver
+                .delay(&dead_letter.data, &self.extras, dead_letter.attempts, due)?;
+        }
+
+        self.commit()
+    }
+}
+
+/// An element received from the queue, owned by this guard. This is the same
+/// as a [`RecvGuard`], except that it does not borrow the [`Receiver`] and so
+/// can be moved around freely, e.g., into another task. Committing or rolling
+/// back (which is also done on drop) sends the outcome back to the receiver,
+/// which applies it before receiving the next element. Any IO error doing so
+/// comes up there. See [`Receiver::recv_owned`] for how the outcomes of many
+/// guards out at a time are applied.
+///
+/// This struct implements `Deref` and `DerefMut`. If you really, really want
+/// ownership, there is `OwnedRecvGuard::into_inner`.
+#[derive(Debug)]
+pub struct OwnedRecvGuard {
+    item: Vec<u8>,
+    metadata: Option<Metadata>,
+    outcome: Option<oneshot::Sender<bool>>,
+}
+
+impl Drop for OwnedRecvGuard {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+impl Deref for OwnedRecvGuard {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        &self.item
+    }
+}
+
+impl DerefMut for OwnedRecvGuard {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.item
+    }
+}
+
+impl OwnedRecvGuard {
+    /// Sends the outcome to the receiver, if not sent yet. The receiver may
+    /// be gone by now. Then, the element will just be received again.
+    fn finish(&mut self, is_commit: bool) {
+        if let Some(outcome) = self.outcome.take() {
+            outcome.send(is_commit).ok();
+        }
+    }
+
+    /// Commits the element and returns it. If you accidentally lose this value
+    /// from now on, it's your own fault!
+    pub fn into_inner(mut self) -> Vec<u8> {
+        self.finish(true);
+        std::mem::take(&mut self.item)
+    }
+
+    /// The metadata of the element, if it was sent with any. See
+    /// [`RecvGuard::metadata`].
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Commits the element, consuming this guard.
+    pub fn commit(mut self) {
+        self.finish(true);
+    }
+
+    /// Rolls the element back, so that it is received again. This is also done
+    /// on drop.
+    pub fn rollback(mut self) {
+        self.finish(false);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fs::*;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::{remove_segment, segment_filename, segments, slowest_segment};
+
+/// The name of the retention policy file in the queue folder.
+fn retention_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("retention")
+}
+
+/// Encodes an optional limit, using `u64::MAX` for "no limit".
+fn encode_limit(limit: Option<u64>) -> [u8; 8] {
+    limit.unwrap_or(u64::MAX).to_be_bytes()
+}
+
+/// Decodes an optional limit, using `u64::MAX` for "no limit".
+fn decode_limit(encoded: &[u8]) -> Option<u64> {
+    let mut buffer = [0; 8];
+    buffer.copy_from_slice(encoded);
+    Some(u64::from_be_bytes(buffer)).filter(|&limit| limit != u64::MAX)
+}
+
+/// A retention policy for a queue. When a queue has a retention policy, its
+/// segments are not deleted as soon as they are consumed. Instead, they are
+/// kept until they break one of the limits of the policy and are then deleted
+/// by [`sweep`]. This turns the queue into a small log, whose recent history
+/// can be received again (see [`crate::Receiver::rewind`]).
+///
+/// A segment is only deleted when _all_ consumer groups have moved past it, no
+/// matter the policy. Also, the segment being currently written is never
+/// deleted. Therefore, the limits are not strict.
+///
+/// A policy with no limits keeps everything forever.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Retention {
+    max_age: Option<Duration>,
+    max_bytes: Option<u64>,
+    max_segments: Option<u64>,
+}
+
+impl Retention {
+    pub fn new() -> Retention {
+        Retention::default()
+    }
+
+    /// Keeps a segment for at least this long after it was last written to.
+    pub fn max_age(mut self, max_age: Duration) -> Retention {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Keeps up to this many bytes of segments in the queue.
+    pub fn max_bytes(mut self, max_bytes: u64) -> Retention {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Keeps up to this many segments in the queue.
+    pub fn max_segments(mut self, max_segments: u64) -> Retention {
+        self.max_segments = Some(max_segments);
+        self
+    }
+
+    fn encode(&self) -> [u8; 24] {
+        let mut encoded = [0; 24];
+
+        encoded[0..8].copy_from_slice(&encode_limit(
+            self.max_age.map(|max_age| max_age.as_millis() as u64),
+        ));
+        encoded[8..16].copy_from_slice(&encode_limit(self.max_bytes));
+        encoded[16..24].copy_from_slice(&encode_limit(self.max_segments));
+
+        encoded
+    }
+
+    fn decode(encoded: &[u8; 24]) -> Retention {
+        Retention {
+            max_age: decode_limit(&encoded[0..8]).map(Duration::from_millis),
+            max_bytes: decode_limit(&encoded[8..16]),
+            max_segments: decode_limit(&encoded[16..24]),
+        }
+    }
+}
+
+/// Gets the retention policy of a queue, if it has one.
+pub fn retention<P: AsRef<Path>>(base: P) -> io::Result<Option<Retention>> {
+    match File::open(retention_filename(base)) {
+        Ok(mut file) => {
+            let mut encoded = [0; 24];
+            file.read_exact(&mut encoded)?;
+            Ok(Some(Retention::decode(&encoded)))
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Sets the retention policy of a queue. Use `None` to go back to deleting
+/// segments as soon as they are consumed. The queue folder is created, if it
+/// does not exist.
+///
+/// All senders and receivers see the change right away. No need to reopen
+/// them.
+pub fn set_retention<P: AsRef<Path>>(base: P, retention: Option<Retention>) -> io::Result<()> {
+    let path = retention_filename(base.as_ref());
+
+    if let Some(retention) = retention {
+        create_dir_all(base.as_ref())?;
+
+        // Replace atomically, since receivers may be reading it:
+        let temp_path = path.with_extension("tmp");
+        File::create(&temp_path)?.write_all(&retention.encode())?;
+        rename(temp_path, path)
+    } else {
+        match remove_file(path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Deletes the segments of a queue that break its retention policy, oldest
+/// first. Returns how many segments were deleted. If the queue has no retention
+/// policy, this does nothing, since receivers delete segments themselves.
+///
+/// Run this every so often, using your favorite timer implementation. It is
+/// safe to run it concurrently with senders and receivers, even in another
+/// process.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+pub fn sweep<P: AsRef<Path>>(base: P) -> io::Result<usize> {
+    let retention = if let Some(retention) = retention(base.as_ref())? {
+        retention
+    } else {
+        return Ok(0);
+    };
+
+    // Nobody has consumed anything yet:
+    let slowest = if let Some(slowest) = slowest_segment(base.as_ref(), None)? {
+        slowest
+    } else {
+        return Ok(0);
+    };
+
+    let segments = segments(base.as_ref())?
+        .into_iter()
+        .map(|segment| {
+            let metadata = metadata(segment_filename(base.as_ref(), segment))?;
+            Ok((segment, metadata.len(), metadata.modified()?))
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let now = SystemTime::now();
+    let mut n_segments = segments.len() as u64;
+    let mut n_bytes = segments.iter().map(|&(_, len, _)| len).sum::<u64>();
+    let mut n_removed = 0;
+
+    // Never the last one, since the sender is writing to it:
+    for &(segment, len, modified) in &segments[..segments.len().saturating_sub(1)] {
+        if segment >= slowest {
+            break;
+        }
+
+        let is_too_old = retention
+            .max_age
+            .map(|max_age| {
+                now.duration_since(modified)
+                    .map(|age| age > max_age)
+                    .unwrap_or(false)
+            })
+            .unwrap_or(false);
+        let is_too_big = retention
+            .max_bytes
+            .map(|max_bytes| n_bytes > max_bytes)
+            .unwrap_or(false);
+        let is_too_many = retention
+            .max_segments
+            .map(|max_segments| n_segments > max_segments)
+            .unwrap_or(false);
+
+        if !is_too_old && !is_too_big && !is_too_many {
+            break;
+        }
+
+        remove_segment(base.as_ref(), segment)?;
+        n_segments -= 1;
+        n_bytes -= len;
+        n_removed += 1;
+    }
+
+    Ok(n_removed)
+}
@@ -1,15 +1,30 @@
+use futures::{future, ready, FutureExt, Sink};
+use futures_timer::Delay;
+use std::collections::BTreeMap;
 use std::fs::*;
-use std::io::{self, Write};
+use std::io::{self, Read, Seek, Write};
 use std::num::NonZeroU64;
 use std::path::{Path, PathBuf};
+use std::pin::Pin;
+#[cfg(feature = "metrics")]
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime};
 
+use crate::durability::{sync_dir, Durability, Syncer};
+use crate::encryption::{self, Keyring};
 use crate::error::TrySendError;
-use crate::header::Header;
-use crate::state::QueueState;
-use crate::sync::{DeletionEvent, FileGuard};
+#[cfg(feature = "metrics")]
+use crate::metrics::Recorder;
+use crate::metrics::{Counter, Metrics, Timing};
+use crate::record::{Compression, Envelope, Metadata, RecordFormat};
+use crate::state::{QueueState, QueueStatePersistence};
+use crate::sync::{DeletionEvent, FileGuard, LOCK_RETRY_INTERVAL, SPIN_LOCK_TIMEOUT};
 use crate::version::check_queue_version;
 
-use super::{segment_filename, HEADER_EOF};
+use super::delayed::{self, delayed_dirname};
+use super::stats;
+use super::{segment_filename, GroupSender, HEADER_EOF};
 
 /// The name of the sender lock in the queue folder.
 pub(crate) fn send_lock_filename<P: AsRef<Path>>(base: P) -> PathBuf {
@@ -29,6 +44,13 @@ pub(crate) fn try_acquire_send_lock<P: AsRef<Path>>(base: P) -> io::Result<FileG
     })
 }
 
+/// Acquires the sender lock for a queue, spinning for a while if locked. This
+/// is used in multi-producer mode, where the lock is only held for the duration
+/// of each append.
+fn spin_acquire_send_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
+    FileGuard::spin_lock(send_lock_filename(base.as_ref()))
+}
+
 /// Acquire the sender lock for a queue, awaiting if locked.
 pub(crate) async fn acquire_send_lock<P: AsRef<Path>>(base: P) -> io::Result<FileGuard> {
     FileGuard::lock(send_lock_filename(base.as_ref())).await
@@ -49,7 +71,14 @@ pub(crate) fn get_queue_size<P: AsRef<Path>>(base: P) -> io::Result<QueueSize> {
 
         if let Some(extension) = dir_entry.path().extension() {
             if extension == "q" {
-                in_bytes += dir_entry.metadata()?.len();
+                let metadata = match dir_entry.metadata() {
+                    Ok(metadata) => metadata,
+                    // Consumed in the meantime:
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                };
+
+                in_bytes += metadata.len();
                 in_segments += 1;
             }
         }
@@ -61,6 +90,39 @@ pub(crate) fn get_queue_size<P: AsRef<Path>>(base: P) -> io::Result<QueueSize> {
     })
 }
 
+/// Guesses the sequence number of the next element with metadata, looking for
+/// the last one in the current segment or, if there is none there, in the one
+/// before it.
+fn guess_next_sequence(
+    base: &Path,
+    state: &QueueState,
+    record_format: RecordFormat,
+    keyring: Option<&Keyring>,
+) -> io::Result<u64> {
+    for segment in (state.segment.saturating_sub(1)..=state.segment).rev() {
+        let records = match read(segment_filename(base, segment)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+
+        if let Some(sequence) = record_format.last_sequence(&records, keyring) {
+            return Ok(sequence + 1);
+        }
+    }
+
+    Ok(0)
+}
+
+/// When an element sent now with the given time to live expires.
+fn expiry(ttl: Duration) -> io::Result<SystemTime> {
+    SystemTime::now().checked_add(ttl).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("time to live of {:?} is too long", ttl),
+        )
+    })
+}
+
 /// A builder for the sender side of the queue. Use this if you want to have fine-grained control
 /// over the configuration of the queue. Most defaults sould be ok of most applications.
 pub struct SenderBuilder {
@@ -81,6 +143,47 @@ pub struct SenderBuilder {
     ///
     /// Default value: None
     max_queue_size: Option<NonZeroU64>,
+
+    /// How hard the sender tries to get each send onto the disk. This also
+    /// covers syncing the queue directory when a new segment is created.
+    ///
+    /// Default value: `Durability::Flush`
+    durability: Durability,
+
+    /// Whether many senders can append to the same queue at the same time.
+    ///
+    /// Default value: `false`
+    multi_producer: bool,
+
+    /// The record format to create the queue with, or to expect the queue to have.
+    ///
+    /// Default value: `None`
+    record_format: Option<RecordFormat>,
+
+    /// Whether every element is sent with its metadata.
+    ///
+    /// Default value: `false`
+    metadata: bool,
+
+    /// The schema version every element is tagged with, if any.
+    ///
+    /// Default value: `None`
+    schema_version: Option<u32>,
+
+    /// How every element is compressed, if at all.
+    ///
+    /// Default value: `None`
+    compression: Option<Compression>,
+
+    /// The keys to encrypt every element with, if any.
+    ///
+    /// Default value: `None`
+    keyring: Option<Keyring>,
+
+    /// Where the metrics of the sender go, if anywhere.
+    ///
+    /// Default value: no recorder
+    metrics: Metrics,
 }
 
 impl Default for SenderBuilder {
@@ -88,6 +191,14 @@ impl Default for SenderBuilder {
         SenderBuilder {
             segment_size: NonZeroU64::new(1024 * 1024 * 4).expect("impossible"), // 4MB
             max_queue_size: None,
+            durability: Durability::default(),
+            multi_producer: false,
+            record_format: None,
+            metadata: false,
+            schema_version: None,
+            compression: None,
+            keyring: None,
+            metrics: Metrics::default(),
         }
     }
 }
@@ -132,14 +243,155 @@ impl SenderBuilder {
         self
     }
 
+    /// How hard the sender tries to get each send onto the disk. With
+    /// [`Durability::Flush`], a send that returned successfully may still be
+    /// lost on a power loss. With [`Durability::Sync`], every send is synced to
+    /// the disk before returning and with [`Durability::Periodic`], a send
+    /// syncs everything sent before it if the interval has passed since the
+    /// last sync. Nothing is synced between sends, so the sends after the last
+    /// sync are at risk until the next send after the interval or until the
+    /// sender is dropped. Call [`Sender::sync`] when the sender goes idle. If
+    /// the policy is not `Flush`, the queue directory is also synced whenever
+    /// a new segment is created.
+    ///
+    /// Default value: `Durability::Flush`
+    pub fn durability(mut self, durability: Durability) -> SenderBuilder {
+        self.durability = durability;
+        self
+    }
+
+    /// Lets many senders append to the same queue at the same time, be they in
+    /// the same process (see [`Sender::try_clone`]) or in different processes.
+    /// Instead of holding the `send.lock` file for its whole lifetime, each
+    /// multi-producer sender holds it only while appending. Therefore, each
+    /// send (or batch) is still written as a whole, never interleaved with
+    /// other senders' data.
+    ///
+    /// This comes at a cost: every send creates and removes the lock file and
+    /// a send (or the [`Sink`]) waits on a timer while another sender is
+    /// appending, without blocking the thread (the `try_` sends fail with an
+    /// error of kind `WouldBlock` instead). Also, all senders to
+    /// a queue have to be multi-producer: a single-producer sender holds the
+    /// lock all the time, so multi-producer sends would time out waiting for
+    /// it, with an error of kind `WouldBlock`. If a process dies while
+    /// appending, use [`crate::recovery::unlock_for_sending`] to release the
+    /// lock.
+    ///
+    /// Default value: `false`
+    pub fn multi_producer(mut self, multi_producer: bool) -> SenderBuilder {
+        self.multi_producer = multi_producer;
+        self
+    }
+
+    /// The format of the records in the queue. The format is chosen when the
+    /// queue is created and cannot be changed afterwards. With
+    /// [`RecordFormat::V2`], each record carries a checksum of its payload and
+    /// corrupted records are reported as errors when received.
+    ///
+    /// The format also sets how big an element can be. In [`RecordFormat::V1`],
+    /// elements can have at most 64MiB (2^26 - 1 bytes) and bigger ones are
+    /// refused. In [`RecordFormat::V2`], bigger elements are split into many
+    /// records when sent and put back together when received. Set this to
+    /// `None` to use whatever format the queue already has (or
+    /// [`RecordFormat::V1`], for new queues).
+    ///
+    /// Default value: `None`
+    pub fn record_format(mut self, format: Option<RecordFormat>) -> SenderBuilder {
+        self.record_format = format;
+        self
+    }
+
+    /// Sends every element with its [`Metadata`]: a sequence number, the time
+    /// it was sent and user headers, if any (see
+    /// [`Sender::try_send_with_headers`]). Receivers get it with
+    /// [`crate::queue::RecvGuard::metadata`]. This needs record format `v2`.
+    ///
+    /// Sequence numbers are only stored in the elements themselves. When it is
+    /// opened, the sender looks for the last element with metadata in the
+    /// last two segments of the queue and goes on from there. So, numbering
+    /// only starts over if there is none, e.g., in a new queue. In
+    /// multi-producer mode, each sender also catches up with the numbers used
+    /// by the others before appending.
+    ///
+    /// Default value: `false`
+    pub fn metadata(mut self, metadata: bool) -> SenderBuilder {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Tags every element with the version of its schema, so that receivers
+    /// can upgrade elements sent with older versions (see [`crate::Schema`]).
+    /// Elements sent without a version count as version `0`. This needs
+    /// record format `v2`.
+    ///
+    /// Default value: `None`
+    pub fn schema_version(mut self, version: u32) -> SenderBuilder {
+        self.schema_version = Some(version);
+        self
+    }
+
+    /// Compresses every element before writing it, with the given algorithm
+    /// (see [`Compression`] for the features each one needs). Receivers
+    /// decompress elements on their own, so they need the feature too, but no
+    /// configuration. This needs record format `v2`.
+    ///
+    /// Each element is compressed on its own, so small elements will barely
+    /// shrink. Elements that do not get any smaller are written as they are.
+    ///
+    /// Default value: `None`
+    pub fn compression(mut self, compression: Option<Compression>) -> SenderBuilder {
+        self.compression = compression;
+        self
+    }
+
+    /// Encrypts every element (after compressing it, if it is) with the
+    /// current key of the keyring, using XChaCha20-Poly1305. The id of the key
+    /// goes with each element, so receivers pick the right key out of their
+    /// own keyring (see [`crate::ReceiverBuilder::keyring`]). The metadata of
+    /// the element (see [`SenderBuilder::metadata`]) is encrypted with it.
+    /// Tampering with an element, its expiry time or its schema version is
+    /// detected on receive. This needs record format `v2` and the `encryption`
+    /// feature.
+    ///
+    /// Not everything is secret, though. The expiry time (see
+    /// [`Sender::try_send_with_ttl`]) and the schema version (see
+    /// [`SenderBuilder::schema_version`]) are written in the clear. So is the
+    /// length of each element, after compression: with
+    /// [`SenderBuilder::compression`], how much an element shrinks tells
+    /// something about what is in it. Leave compression off if attackers may
+    /// control part of what is sent together with secrets.
+    ///
+    /// Elements sent for later (see [`Sender::send_at`]) are encrypted with
+    /// the same keyring.
+    ///
+    /// Default value: `None`
+    pub fn keyring(mut self, keyring: Keyring) -> SenderBuilder {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Sets where the metrics of the sender go: elements and bytes sent,
+    /// segments created and how long sends waited for room in a full queue.
+    /// See [`crate::metrics`].
+    ///
+    /// Default value: no recorder
+    #[cfg(feature = "metrics")]
+    pub fn recorder(mut self, recorder: Arc<dyn Recorder>) -> SenderBuilder {
+        self.metrics = Metrics::new(Some(recorder));
+        self
+    }
+
     /// Opens a queue on a folder indicated by the `base` path for sending. The
     /// folder will be created if it does not already exist.
     ///
     /// # Errors
     ///
     /// This function will return an IO error if the queue is already in use for
-    /// sending, which is indicated by a lock file. Also, any other IO error
-    /// encountered while opening will be sent.
+    /// sending, which is indicated by a lock file, or if the queue has a record
+    /// format other than the one requested. An error of kind `InvalidInput` is
+    /// returned if metadata, a schema version, compression or encryption was
+    /// requested, but the queue is in record format `v1`, and one of kind `Unsupported` if
+    /// encryption was requested without the `encryption` feature. Also, any other IO error encountered while opening will be sent.
     pub fn open<P: AsRef<Path>>(self, base: P) -> io::Result<Sender> {
         // Guarantee that the queue exists:
         create_dir_all(base.as_ref())?;
@@ -147,10 +399,59 @@ impl SenderBuilder {
         log::trace!("created queue directory");
 
         // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
-        check_queue_version(base.as_ref())?;
+        let record_format = check_queue_version(base.as_ref(), self.record_format)?;
+
+        if self.metadata && record_format == RecordFormat::V1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "queue `{}` is in record format v1, which has no room for metadata",
+                    base.as_ref().to_string_lossy()
+                ),
+            ));
+        }
+
+        if self.schema_version.is_some() && record_format == RecordFormat::V1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "queue `{}` is in record format v1, which has no room for schema versions",
+                    base.as_ref().to_string_lossy()
+                ),
+            ));
+        }
 
-        // Acquire lock and guess statestate:
-        let file_guard = try_acquire_send_lock(base.as_ref())?;
+        if self.compression.is_some() && record_format == RecordFormat::V1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "queue `{}` is in record format v1, which cannot be compressed",
+                    base.as_ref().to_string_lossy()
+                ),
+            ));
+        }
+
+        if self.keyring.is_some() {
+            encryption::check_supported()?;
+
+            if record_format == RecordFormat::V1 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "queue `{}` is in record format v1, which cannot be encrypted",
+                        base.as_ref().to_string_lossy()
+                    ),
+                ));
+            }
+        }
+
+        // Acquire lock and guess statestate. In multi-producer mode, the lock is
+        // only held while opening (see `Sender::try_lock_for_append`):
+        let (file_guard, append_guard) = if self.multi_producer {
+            (None, Some(spin_acquire_send_lock(base.as_ref())?))
+        } else {
+            (Some(try_acquire_send_lock(base.as_ref())?), None)
+        };
         let state = QueueState::for_send_metadata(base.as_ref())?;
 
         log::trace!("sender lock acquired. Sender state now is {:?}", state);
@@ -165,12 +466,42 @@ impl SenderBuilder {
 
         log::trace!("last segment opened for appending");
 
+        let next_sequence = if self.metadata {
+            Some(guess_next_sequence(
+                base.as_ref(),
+                &state,
+                record_format,
+                self.keyring.as_ref(),
+            )?)
+        } else {
+            None
+        };
+
+        drop(append_guard);
+
+        // The segment may have just been created:
+        let syncer = Syncer::new(self.durability);
+        if syncer.is_enabled() {
+            sync_dir(base.as_ref())?;
+        }
+
         Ok(Sender {
             segment_size: self.segment_size,
             max_queue_size: self.max_queue_size,
             _file_guard: file_guard,
+            multi_producer: self.multi_producer,
+            record_format,
             file,
+            syncer,
             state,
+            next_sequence,
+            schema_version: self.schema_version,
+            compression: self.compression,
+            keyring: self.keyring,
+            metrics: self.metrics,
+            blocked_since: None,
+            sink_guard: None,
+            lock_retry: None,
             deletion_stream: None,
             base: PathBuf::from(base.as_ref()),
         })
@@ -182,13 +513,37 @@ impl SenderBuilder {
 pub struct Sender {
     segment_size: NonZeroU64,
     max_queue_size: Option<NonZeroU64>,
-    _file_guard: FileGuard,
+    _file_guard: Option<FileGuard>, // none if multi-producer!
+    multi_producer: bool,
+    record_format: RecordFormat,
     file: io::BufWriter<File>,
+    syncer: Syncer,
     state: QueueState,
+    schema_version: Option<u32>,
+    compression: Option<Compression>,
+    keyring: Option<Keyring>,
+    metrics: Metrics,
+    blocked_since: Option<Instant>,         // only for the sink!
+    sink_guard: Option<FileGuard>,          // only for the sink!
+    lock_retry: Option<(Instant, Delay)>,   // only while waiting for the lock!
+    next_sequence: Option<u64>,             // none if no metadata!
     deletion_stream: Option<DeletionEvent>, // lazy inited!
     base: PathBuf,
 }
 
+impl Drop for Sender {
+    fn drop(&mut self) {
+        let outcome = self
+            .file
+            .flush()
+            .and_then(|_| self.syncer.sync_if_dirty(self.file.get_ref()));
+
+        if let Err(err) = outcome {
+            log::error!("could not sync queue segment during `Drop`: {}", err);
+        }
+    }
+}
+
 impl Sender {
     /// Opens a queue on a folder indicated by the `base` path for sending. The
     /// folder will be created if it does not already exist.
@@ -221,18 +576,249 @@ impl Sender {
         Ok(())
     }
 
+    /// Syncs to the disk whatever a [`Durability::Periodic`] policy has left
+    /// unsynced (see [`SenderBuilder::durability`]). Call this when the sender
+    /// goes idle for a while. With the other policies, this does nothing.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while syncing.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.syncer.sync_if_dirty(self.file.get_ref())
+    }
+
+    /// Opens another sender to the same queue, with the same configuration.
+    /// This is only possible if this sender is multi-producer (see
+    /// [`SenderBuilder::multi_producer`]).
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error if this sender is not multi-producer or
+    /// if any IO error happens while opening the new sender.
+    pub fn try_clone(&self) -> io::Result<Sender> {
+        if !self.multi_producer {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "sender for queue `{}` is not multi-producer",
+                    self.base.to_string_lossy()
+                ),
+            ));
+        }
+
+        SenderBuilder {
+            segment_size: self.segment_size,
+            max_queue_size: self.max_queue_size,
+            durability: self.syncer.durability(),
+            multi_producer: true,
+            record_format: Some(self.record_format),
+            metadata: self.next_sequence.is_some(),
+            schema_version: self.schema_version,
+            compression: self.compression,
+            keyring: self.keyring.clone(),
+            metrics: self.metrics.clone(),
+        }
+        .open(&self.base)
+    }
+
+    /// In multi-producer mode, acquires the sender lock and catches up with
+    /// whatever other senders have appended to the queue since the last time.
+    /// Hold the returned guard while writing and flushing. In single-producer
+    /// mode, this does nothing: the lock is already ours.
+    ///
+    /// This fails with an error of kind `WouldBlock` right away if another
+    /// sender is appending. See [`Sender::poll_lock_for_append`] for waiting.
+    fn try_lock_for_append(&mut self) -> io::Result<Option<FileGuard>> {
+        if !self.multi_producer {
+            return Ok(None);
+        }
+
+        let append_guard =
+            FileGuard::try_lock(send_lock_filename(&self.base))?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "queue `{}` is being appended to by another sender",
+                        self.base.to_string_lossy()
+                    ),
+                )
+            })?;
+        self.catch_up()?;
+
+        Ok(Some(append_guard))
+    }
+
+    /// Same as [`Sender::try_lock_for_append`], but waits on a timer while
+    /// another sender is appending, instead of failing. Gives up with an error
+    /// of kind `WouldBlock` after [`SPIN_LOCK_TIMEOUT`].
+    fn poll_lock_for_append(
+        &mut self,
+        context: &mut Context<'_>,
+    ) -> Poll<io::Result<Option<FileGuard>>> {
+        if !self.multi_producer {
+            return Poll::Ready(Ok(None));
+        }
+
+        let append_guard = loop {
+            if let Some(append_guard) = FileGuard::try_lock(send_lock_filename(&self.base))? {
+                break append_guard;
+            }
+
+            let (started_at, delay) = self
+                .lock_retry
+                .get_or_insert_with(|| (Instant::now(), Delay::new(LOCK_RETRY_INTERVAL)));
+
+            if started_at.elapsed() >= SPIN_LOCK_TIMEOUT {
+                self.lock_retry = None;
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "queue `{}` still locked by another sender after {:?}",
+                        self.base.to_string_lossy(),
+                        SPIN_LOCK_TIMEOUT
+                    ),
+                )));
+            }
+
+            ready!(delay.poll_unpin(context));
+            delay.reset(LOCK_RETRY_INTERVAL);
+        };
+
+        self.lock_retry = None;
+        self.catch_up()?;
+
+        Poll::Ready(Ok(Some(append_guard)))
+    }
+
+    /// See [`Sender::poll_lock_for_append`].
+    async fn lock_for_append(&mut self) -> io::Result<Option<FileGuard>> {
+        future::poll_fn(|context| self.poll_lock_for_append(context)).await
+    }
+
+    /// Finds the tail of the queue again, after taking the sender lock.
+    fn catch_up(&mut self) -> io::Result<()> {
+        // Others may have moved on by any number of segments meanwhile and the
+        // receiver may have even deleted the segment open here. So, the tail
+        // has to be found again. If it is still in the same segment, only the
+        // position changed.
+        let tail = QueueState::for_send_metadata(&self.base)?;
+
+        if tail.segment != self.state.segment {
+            self.state = tail;
+            // Preserves the already allocated (and flushed) buffer:
+            *self.file.get_mut() = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_filename(&self.base, self.state.segment))?;
+
+            if self.next_sequence.is_some() {
+                let next_sequence = guess_next_sequence(
+                    &self.base,
+                    &self.state,
+                    self.record_format,
+                    self.keyring.as_ref(),
+                )?;
+                self.next_sequence = Some(next_sequence);
+            }
+        } else {
+            let position = tail.position;
+
+            // The others may have sent elements with metadata meanwhile:
+            if self.next_sequence.is_some() && position > self.state.position {
+                if let Some(sequence) = self.last_sequence_since(self.state.position)? {
+                    self.next_sequence = Some(sequence + 1);
+                }
+            }
+
+            self.state.position = position;
+        }
+
+        log::trace!("append lock acquired. Sender state now is {:?}", self.state);
+
+        Ok(())
+    }
+
+    /// The format of the records in the queue.
+    pub(crate) fn record_format(&self) -> RecordFormat {
+        self.record_format
+    }
+
+    /// The number of elements in the queue that the slowest receiver has not
+    /// committed yet, as of the last time it saved its state. See
+    /// [`crate::queue::stats`] for the details.
+    pub fn len(&self) -> io::Result<u64> {
+        Ok(stats::stats(&self.base)?.pending_elements)
+    }
+
+    /// Whether every element in the queue was committed by the slowest
+    /// receiver. See [`Sender::len`].
+    pub fn is_empty(&self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Finds the sequence number of the last element with metadata in the
+    /// current segment, from a given position on.
+    fn last_sequence_since(&self, position: u64) -> io::Result<Option<u64>> {
+        let mut file = File::open(segment_filename(&self.base, self.state.segment))?;
+        file.seek(io::SeekFrom::Start(position))?;
+
+        let mut records = vec![];
+        file.read_to_end(&mut records)?;
+
+        Ok(self
+            .record_format
+            .last_sequence(&records, self.keyring.as_ref()))
+    }
+
     /// Just writes to the internal buffer, but doesn't flush it.
     fn write(&mut self, data: &[u8]) -> io::Result<u64> {
-        // Get length of the data and write the header:
-        let len = data.as_ref().len();
-        assert!(len < std::u64::MAX as usize);
-        let header = Header::new(len as u32).encode();
+        self.write_enveloped(data, None, None)
+    }
+
+    /// Same as `write`, but with an expiry time and user headers. The
+    /// metadata, if this sender sends it, is filled in here.
+    fn write_enveloped(
+        &mut self,
+        data: &[u8],
+        expires_at: Option<SystemTime>,
+        headers: Option<&BTreeMap<String, String>>,
+    ) -> io::Result<u64> {
+        let metadata = match self.next_sequence {
+            Some(sequence) => Some(Metadata {
+                sequence,
+                timestamp: SystemTime::now(),
+                headers: headers.cloned().unwrap_or_default(),
+            }),
+            None if headers.is_some() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "headers need a sender with metadata (see `SenderBuilder::metadata`)",
+                ))
+            }
+            None => None,
+        };
 
-        // Write stuff to the file:
-        self.file.write_all(&header)?;
-        self.file.write_all(data.as_ref())?;
+        let envelope = Envelope {
+            expires_at,
+            metadata: metadata.as_ref(),
+            schema_version: self.schema_version,
+            compression: self.compression,
+            keyring: self.keyring.as_ref(),
+        };
+        let written = self
+            .record_format
+            .write_enveloped(&mut self.file, data, &envelope)?;
 
-        Ok(4 + len as u64)
+        if let Some(sequence) = &mut self.next_sequence {
+            *sequence += 1;
+        }
+
+        self.metrics.increment(&self.base, Counter::MessagesSent, 1);
+        self.metrics
+            .increment(&self.base, Counter::BytesSent, data.len() as u64);
+
+        Ok(written)
     }
 
     /// Tests whether the queue is past the end of the current segment.
@@ -266,12 +852,26 @@ impl Sender {
         self.file.write(&HEADER_EOF)?;
         self.file.flush()?;
 
+        // Nothing else will be written to the old segment. So, settle it now
+        // (a missing EOF header would leave the receiver stuck after a crash):
+        if self.syncer.is_enabled() {
+            self.syncer.sync(self.file.get_ref())?;
+        }
+
         // Preserves the already allocated buffer:
         *self.file.get_mut() = OpenOptions::new()
             .create(true)
             .append(true)
             .open(segment_filename(&self.base, self.state.advance_segment()))?;
 
+        // And make sure the new segment will be found after a crash:
+        if self.syncer.is_enabled() {
+            sync_dir(&self.base)?;
+        }
+
+        self.metrics
+            .increment(&self.base, Counter::SegmentsCreated, 1);
+
         Ok(true)
     }
 
@@ -315,13 +915,75 @@ impl Sender {
     ///
     /// This function returns any underlying errors encountered while writing or
     /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
-    /// queue is too big.
+    /// queue is too big. An error of kind `InvalidInput` is returned if the
+    /// data does not fit in a record (see [`SenderBuilder::record_format`]).
+    /// In multi-producer mode, an error of kind `WouldBlock` is returned if
+    /// another sender is appending at the same time.
     pub fn try_send<D: AsRef<[u8]>>(&mut self, data: D) -> Result<(), TrySendError<D>> {
+        self.try_send_enveloped(data, None, None)
+    }
+
+    /// Same as [`Sender::try_send`], but the element expires after the given
+    /// time to live. An element found expired when received is never
+    /// delivered: it is dropped or, with a dead-letter policy (see
+    /// [`crate::queue::ReceiverBuilder::dead_letter`]), sent to the
+    /// dead-letter queue. See [`crate::Receiver::expired`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Sender::try_send`], this function returns an
+    /// error of kind `InvalidInput` if the queue uses record format `v1`,
+    /// which has no room for the expiry time, or if the time to live is too
+    /// long to be represented.
+    pub fn try_send_with_ttl<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        ttl: Duration,
+    ) -> Result<(), TrySendError<D>> {
+        let expires_at = expiry(ttl)?;
+        self.try_send_enveloped(data, Some(expires_at), None)
+    }
+
+    /// Same as [`Sender::try_send`], but with user headers in the metadata of
+    /// the element, such as a trace id or a content type. See
+    /// [`SenderBuilder::metadata`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Sender::try_send`], this function returns an
+    /// error of kind `InvalidInput` if this sender does not send metadata or if
+    /// the headers are too big (see [`Metadata::MAX_LEN`]).
+    pub fn try_send_with_headers<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        headers: &BTreeMap<String, String>,
+    ) -> Result<(), TrySendError<D>> {
+        self.try_send_enveloped(data, None, Some(headers))
+    }
+
+    fn try_send_enveloped<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        expires_at: Option<SystemTime>,
+        headers: Option<&BTreeMap<String, String>>,
+    ) -> Result<(), TrySendError<D>> {
+        let _append_guard = self.try_lock_for_append()?;
+        self.send_enveloped_locked(data, expires_at, headers)
+    }
+
+    /// Sends an element, with the sender lock already taken.
+    fn send_enveloped_locked<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        expires_at: Option<SystemTime>,
+        headers: Option<&BTreeMap<String, String>>,
+    ) -> Result<(), TrySendError<D>> {
         let data = self.maybe_cap_off_and_move(data)?;
 
         // Write to the queue and flush:
-        let written = self.write(data.as_ref())?;
+        let written = self.write_enveloped(data.as_ref(), expires_at, headers)?;
         self.file.flush()?; // guarantees atomic operation. See `new`.
+        self.syncer.maybe_sync(self.file.get_ref())?;
         self.state.advance_position(written);
 
         Ok(())
@@ -334,19 +996,72 @@ impl Sender {
     /// # Errors
     ///
     /// This function returns any underlying errors encountered while writing or
-    /// flushing the queue.
+    /// flushing the queue. An error of kind `InvalidInput` is returned if the
+    /// data does not fit in a record (see [`SenderBuilder::record_format`]).
+    ///
+    pub async fn send<D: AsRef<[u8]>>(&mut self, data: D) -> io::Result<()> {
+        self.send_enveloped(data, None, None).await
+    }
+
+    /// Same as [`Sender::send`], but the element expires after the given time
+    /// to live. The time to live counts from when this function is called,
+    /// not from when the element gets into the queue. See
+    /// [`Sender::try_send_with_ttl`].
+    ///
+    /// # Errors
+    ///
+    /// Besides the errors of [`Sender::send`], this function returns an error
+    /// of kind `InvalidInput` if the queue uses record format `v1` or if the
+    /// time to live is too long to be represented.
+    pub async fn send_with_ttl<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        ttl: Duration,
+    ) -> io::Result<()> {
+        let expires_at = expiry(ttl)?;
+        self.send_enveloped(data, Some(expires_at), None).await
+    }
+
+    /// Same as [`Sender::send`], but with user headers in the metadata of the
+    /// element. See [`Sender::try_send_with_headers`].
+    ///
+    /// # Errors
     ///
-    pub async fn send<D: AsRef<[u8]>>(&mut self, mut data: D) -> io::Result<()> {
-        loop {
-            match self.try_send(data) {
+    /// Besides the errors of [`Sender::send`], this function returns an error
+    /// of kind `InvalidInput` if this sender does not send metadata or if the
+    /// headers are too big (see [`Metadata::MAX_LEN`]).
+    pub async fn send_with_headers<D: AsRef<[u8]>>(
+        &mut self,
+        data: D,
+        headers: &BTreeMap<String, String>,
+    ) -> io::Result<()> {
+        self.send_enveloped(data, None, Some(headers)).await
+    }
+
+    async fn send_enveloped<D: AsRef<[u8]>>(
+        &mut self,
+        mut data: D,
+        expires_at: Option<SystemTime>,
+        headers: Option<&BTreeMap<String, String>>,
+    ) -> io::Result<()> {
+        let mut blocked_since = None;
+        let outcome = loop {
+            let append_guard = self.lock_for_append().await?;
+
+            match self.send_enveloped_locked(data, expires_at, headers) {
                 Ok(()) => break Ok(()),
                 Err(TrySendError::Io(err)) => break Err(err),
                 Err(TrySendError::QueueFull { item, .. }) => {
+                    drop(append_guard); // lets the others append meanwhile
                     data = item; // the "unmove"!
+                    blocked_since.get_or_insert_with(Instant::now);
                     self.deletion_stream().await // prevents spinlock
                 }
             }
-        }
+        };
+
+        self.record_blocked(blocked_since);
+        outcome
     }
 
     /// Tries to send all the contents of an iterable into the queue. If the
@@ -360,21 +1075,42 @@ impl Sender {
     ///
     /// This function returns any underlying errors encountered while writing or
     /// flushing the queue. Also, it returns [`TrySendError::QueueFull`] if the
-    /// queue is too big.
+    /// queue is too big. An error of kind `InvalidInput` is returned if any of
+    /// the items does not fit in a record (see [`SenderBuilder::record_format`]).
+    /// In this case, nothing is sent. In multi-producer mode, an error of kind
+    /// `WouldBlock` is returned if another sender is appending at the same
+    /// time.
     pub fn try_send_batch<I>(&mut self, it: I) -> Result<(), TrySendError<I>>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        let _append_guard = self.try_lock_for_append()?;
+        self.send_batch_locked(it)
+    }
+
+    /// Sends a batch, with the sender lock already taken.
+    fn send_batch_locked<I>(&mut self, it: I) -> Result<(), TrySendError<I>>
     where
         I: IntoIterator,
         I::Item: AsRef<[u8]>,
     {
         let it = self.maybe_cap_off_and_move(it)?;
 
+        // Refuse the whole batch before anything gets into the buffer:
+        let items = it.into_iter().collect::<Vec<_>>();
+        for item in &items {
+            self.record_format.check_len(item.as_ref().len())?;
+        }
+
         let mut written = 0;
         // Drain iterator into the buffer.
-        for item in it {
+        for item in items {
             written += self.write(item.as_ref())?;
         }
 
         self.file.flush()?; // guarantees atomic operation. See `new`.
+        self.syncer.maybe_sync(self.file.get_ref())?;
         self.state.advance_position(written);
 
         Ok(())
@@ -390,21 +1126,208 @@ impl Sender {
     /// # Errors
     ///
     /// This function returns any underlying errors encountered while writing or
-    /// flushing the queue.
+    /// flushing the queue. An error of kind `InvalidInput` is returned if any of
+    /// the items does not fit in a record (see [`SenderBuilder::record_format`]).
+    /// In this case, nothing is sent.
     pub async fn send_batch<I>(&mut self, mut it: I) -> io::Result<()>
     where
         I: IntoIterator,
         I::Item: AsRef<[u8]>,
     {
-        loop {
-            match self.try_send_batch(it) {
+        let mut blocked_since = None;
+        let outcome = loop {
+            let append_guard = self.lock_for_append().await?;
+
+            match self.send_batch_locked(it) {
                 Ok(()) => break Ok(()),
                 Err(TrySendError::Io(err)) => break Err(err),
                 Err(TrySendError::QueueFull { item, .. }) => {
+                    drop(append_guard); // lets the others append meanwhile
                     it = item; // the "unmove"!
+                    blocked_since.get_or_insert_with(Instant::now);
                     self.deletion_stream().await // prevents spinlock
                 }
             }
+        };
+
+        self.record_blocked(blocked_since);
+        outcome
+    }
+
+    /// Records how long a send waited for room in the queue, if it did.
+    fn record_blocked(&self, blocked_since: Option<Instant>) {
+        if let Some(blocked_since) = blocked_since {
+            self.metrics
+                .timing(&self.base, Timing::QueueFull, blocked_since.elapsed());
+        }
+    }
+
+    /// Sends some data into the queue, to be received only once the given time
+    /// has come. Meanwhile, the other elements in the queue are received as
+    /// usual. Each element sent this way is kept in a file of its own (see
+    /// [`crate::queue::RecvGuard::retry_after`]), which receivers look for
+    /// about every second, so this is meant for things like jobs to be run
+    /// later, not for huge amounts of data.
+    ///
+    /// The element is delivered to every consumer group of the queue that has
+    /// ever been opened (or to the default receiver, if there is none), by
+    /// [`crate::Receiver::recv`], [`crate::Receiver::try_recv`] and
+    /// [`crate::Receiver::recv_timeout`]. The other receive methods (batches,
+    /// owned guards, streams) and [`crate::Worker`] never see it: they only
+    /// log a warning when there are elements due. A time in the past means the
+    /// element is due right away.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing
+    /// the element. If that happens with many consumer groups, some of them
+    /// may get the element and others not.
+    pub fn send_at<D: AsRef<[u8]>>(&mut self, data: D, at: SystemTime) -> io::Result<()> {
+        let mut groups = QueueStatePersistence::group_states(&self.base)?
+            .into_iter()
+            .map(|(group, _)| group)
+            .collect::<Vec<_>>();
+        if groups.is_empty() {
+            groups.push(None);
+        }
+
+        for group in groups {
+            let dir = delayed_dirname(&self.base, group.as_deref());
+            let envelope = Envelope {
+                schema_version: self.schema_version,
+                keyring: self.keyring.as_ref(),
+                ..Envelope::default()
+            };
+            delayed::put(&dir, data.as_ref(), &envelope, 0, at)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends some data into the queue, to be received only after the given
+    /// delay. See [`Sender::send_at`] for the details.
+    ///
+    /// # Errors
+    ///
+    /// This function returns any underlying errors encountered while writing
+    /// the element. An error of kind `InvalidInput` is returned if the delay
+    /// is too long to be represented.
+    pub fn send_after<D: AsRef<[u8]>>(&mut self, data: D, delay: Duration) -> io::Result<()> {
+        let at = SystemTime::now().checked_add(delay).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("delay of {:?} is too long", delay),
+            )
+        })?;
+
+        self.send_at(data, at)
+    }
+
+    /// Turns this sender into a [`GroupSender`], which commits all the sends
+    /// issued within `window` of the first one in a single write. A group is
+    /// committed early if its size reaches `max_batch_bytes`.
+    pub fn into_group(self, window: Duration, max_batch_bytes: usize) -> GroupSender {
+        GroupSender::new(self, window, max_batch_bytes)
+    }
+
+    /// Checks whether there is room in the queue for one more element, moving
+    /// to a new segment if needed. If the queue is full, this flushes what is
+    /// buffered and waits for the receiver to delete a segment.
+    ///
+    /// In multi-producer mode, the sender lock is taken first and, if there is
+    /// room, it is kept until the element is written (see
+    /// [`Sender::write_buffered`]). Otherwise, it is let go.
+    fn poll_room(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let outcome = self.poll_room_locked(context);
+
+        if !matches!(outcome, Poll::Ready(Ok(()))) {
+            self.sink_guard = None;
+        }
+
+        outcome
+    }
+
+    fn poll_room_locked(&mut self, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.sink_guard.is_none() {
+            self.sink_guard = ready!(self.poll_lock_for_append(context))?;
+        }
+
+        if !self.is_past_end() {
+            return Poll::Ready(Ok(()));
         }
+
+        // Listen to deletions before looking, so that none goes unnoticed:
+        let _ = self.deletion_stream().poll_unpin(context);
+
+        if self.try_cap_off_and_move()? {
+            let blocked_since = self.blocked_since.take();
+            self.record_blocked(blocked_since);
+            Poll::Ready(Ok(()))
+        } else {
+            // The receiver might be waiting for this:
+            self.flush_buffered()?;
+            self.blocked_since.get_or_insert_with(Instant::now);
+            Poll::Pending
+        }
+    }
+
+    /// Writes an element to the internal buffer. In multi-producer mode, it is
+    /// flushed right away, since the buffer cannot outlive the append lock.
+    fn write_buffered(&mut self, data: &[u8]) -> io::Result<()> {
+        // Taken by `poll_room`, unless the sink was not polled for readiness:
+        let _append_guard = match self.sink_guard.take() {
+            Some(append_guard) => Some(append_guard),
+            None => self.try_lock_for_append()?,
+        };
+        let written = self.write(data)?;
+
+        if self.multi_producer {
+            self.flush_buffered()?;
+        }
+
+        self.state.advance_position(written);
+
+        Ok(())
+    }
+
+    /// Flushes the internal buffer, syncing if the durability policy says so.
+    fn flush_buffered(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        self.syncer.maybe_sync(self.file.get_ref())
+    }
+}
+
+/// A sender is also a [`Sink`], which is handy for forwarding a stream into a
+/// queue. Elements are buffered and written to the queue when the sink is
+/// flushed (and synced then, if you have set a [`Durability`] policy), except
+/// in multi-producer mode, where every element is flushed as it is sent.
+/// Unlike a batch, what is sent between two flushes is not written atomically:
+/// whenever the buffer fills up, what is in it is written right away, so
+/// receivers may see some of the elements before the flush. When the queue is
+/// full (see [`SenderBuilder::max_queue_size`]), the sink waits for the
+/// receiver to consume enough segments, just like [`Sender::send`].
+///
+/// # Errors
+///
+/// Any IO error encountered while writing or flushing is returned. An error of
+/// kind `InvalidInput` is returned if an element does not fit in a record (see
+/// [`SenderBuilder::record_format`]).
+impl<D: AsRef<[u8]>> Sink<D> for Sender {
+    type Error = io::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.get_mut().poll_room(context)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: D) -> io::Result<()> {
+        self.get_mut().write_buffered(item.as_ref())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.get_mut().flush_buffered())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, context: &mut Context<'_>) -> Poll<io::Result<()>> {
+        <Self as Sink<D>>::poll_flush(self, context)
     }
 }
@@ -0,0 +1,164 @@
+use std::fs::*;
+use std::io::{self, BufReader, Seek};
+use std::path::Path;
+
+use crate::record::RecordFormat;
+use crate::state::{QueueState, QueueStatePersistence};
+use crate::version::check_queue_version;
+
+use super::{segment_filename, segments, HEADER_EOF};
+
+/// A snapshot of how a queue is doing. See [`stats`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Stats {
+    /// How many elements were sent, but not received and committed yet by the
+    /// slowest receiver.
+    pub pending_elements: u64,
+    /// How many bytes the pending elements take on disk, record headers
+    /// included.
+    pub pending_bytes: u64,
+    /// How many segments there are in the queue folder, including the ones
+    /// kept by a retention policy (see [`crate::queue::Retention`]).
+    pub segments: u64,
+    /// The segment the sender is appending to.
+    pub sender_segment: u64,
+    /// Where the next element will be appended in the sender segment.
+    pub sender_position: u64,
+    /// The segment the slowest receiver is reading from.
+    pub receiver_segment: u64,
+    /// Where the slowest receiver is in its segment.
+    pub receiver_position: u64,
+}
+
+/// Gets the [`Stats`] of a queue, without taking the sender or the receiver
+/// locks, so that it can be called at any time, from any process. Elements are
+/// counted exactly, by reading the header of each pending record, so this
+/// takes longer the more elements are pending.
+///
+/// The receiver side is the slowest consumer group of the queue (see
+/// [`crate::ReceiverBuilder::group`]), as of the last time it saved its state
+/// (see [`crate::ReceiverBuilder::save_every`]). If no receiver has ever been
+/// opened, everything in the queue is pending. Delayed elements (see
+/// [`crate::Sender::send_at`]) are never counted, not even once they are due.
+///
+/// # Errors
+///
+/// This function returns an error of kind `NotFound` if there is no queue at
+/// the given path, besides any other IO error.
+pub fn stats<P: AsRef<Path>>(base: P) -> io::Result<Stats> {
+    let segments = segments(base.as_ref())?;
+    let record_format = check_queue_version(base.as_ref(), None)?;
+    let receiver = slowest_state(base.as_ref(), &segments)?;
+    let sender = QueueState::for_send_metadata(base.as_ref())?;
+    let (pending_elements, pending_bytes) = pending(base.as_ref(), record_format, receiver)?;
+
+    Ok(Stats {
+        pending_elements,
+        pending_bytes,
+        segments: segments.len() as u64,
+        sender_segment: sender.segment,
+        sender_position: sender.position,
+        receiver_segment: receiver.segment,
+        receiver_position: receiver.position,
+    })
+}
+
+/// Finds the saved state of the slowest consumer group, or where the oldest
+/// segment starts, if none is older than that. Spins while a group is being
+/// saved.
+fn slowest_state(base: &Path, segments: &[u64]) -> io::Result<QueueState> {
+    let group_states = loop {
+        match QueueStatePersistence::group_states(base) {
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => std::thread::yield_now(),
+            result => break result?,
+        }
+    };
+
+    let oldest = QueueState {
+        segment: segments.first().copied().unwrap_or(0),
+        position: 0,
+    };
+
+    let slowest = group_states
+        .into_iter()
+        .map(|(_, state)| state)
+        .fold(None, |slowest, state| match slowest {
+            Some(slowest) if slowest <= state => Some(slowest),
+            _ => Some(state),
+        });
+
+    match slowest {
+        Some(slowest) if slowest.segment >= oldest.segment => Ok(slowest),
+        _ => Ok(oldest),
+    }
+}
+
+/// Counts the elements in the queue from a given state on and how many bytes
+/// they take. See [`RecordFormat::count_elements`].
+pub(crate) fn pending(
+    base: &Path,
+    record_format: RecordFormat,
+    from: QueueState,
+) -> io::Result<(u64, u64)> {
+    let mut elements = 0;
+    let mut bytes = 0;
+
+    for segment in segments(base)? {
+        if segment < from.segment {
+            continue;
+        }
+
+        let file = match File::open(segment_filename(base, segment)) {
+            // Consumed in the meantime:
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+
+        let end = file.metadata()?.len();
+        let start = if segment == from.segment {
+            from.position
+        } else {
+            0
+        };
+
+        let mut reader = BufReader::new(file);
+        reader.seek(io::SeekFrom::Start(start))?;
+        let (in_segment, in_bytes) = record_format.count_elements(&mut reader, start, end)?;
+
+        elements += in_segment;
+        bytes += in_bytes;
+    }
+
+    Ok((elements, bytes))
+}
+
+/// How many bytes there are in the queue from a given state on, record headers
+/// included, going only by the size of the segment files. Unlike [`pending`],
+/// this reads no records, so it takes as long however many elements are
+/// pending. An element still being written counts with what is there of it.
+pub(crate) fn pending_bytes(base: &Path, from: QueueState) -> io::Result<u64> {
+    let mut lens = vec![];
+    for segment in from.segment.. {
+        match metadata(segment_filename(base, segment)) {
+            Ok(metadata) => lens.push(metadata.len()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+            Err(err) => return Err(err),
+        }
+    }
+
+    // Every segment but the last one ends with the EOF header:
+    let n_segments = lens.len();
+    let bytes = lens
+        .into_iter()
+        .enumerate()
+        .map(|(i, len)| {
+            if i + 1 < n_segments {
+                len.saturating_sub(HEADER_EOF.len() as u64)
+            } else {
+                len
+            }
+        })
+        .sum::<u64>();
+
+    Ok(bytes.saturating_sub(from.position))
+}
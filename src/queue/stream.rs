@@ -0,0 +1,81 @@
+use futures::future::BoxFuture;
+use futures::stream::{FusedStream, Stream};
+use futures::FutureExt;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use super::{OwnedRecvGuard, Receiver};
+
+/// A receive in progress, which owns the receiver until it is done.
+type Next = BoxFuture<'static, (Receiver, io::Result<OwnedRecvGuard>)>;
+
+/// A [`Stream`] of the elements in a queue. Get one with
+/// [`Receiver::into_stream`].
+///
+/// The stream ends right after yielding an error, so that a corrupted record
+/// (see [`crate::Corrupted`]) is not yielded over and over again. Use
+/// [`RecvStream::into_inner`] to get the receiver back, deal with the error
+/// (e.g., with [`Receiver::skip_segment`]) and then make a new stream.
+pub struct RecvStream {
+    receiver: Option<Receiver>, // none while receiving!
+    next: Option<Next>,
+    is_terminated: bool,
+}
+
+impl RecvStream {
+    pub(crate) fn new(receiver: Receiver) -> RecvStream {
+        RecvStream {
+            receiver: Some(receiver),
+            next: None,
+            is_terminated: false,
+        }
+    }
+
+    /// Gets the receiver back. This returns `None` if the stream was polled
+    /// and a receive is still in progress, in which case the receiver is
+    /// dropped (and any element being received stays in the queue). After the
+    /// stream has yielded an element or an error, the receiver is always
+    /// there.
+    pub fn into_inner(self) -> Option<Receiver> {
+        self.receiver
+    }
+}
+
+impl Stream for RecvStream {
+    type Item = io::Result<OwnedRecvGuard>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.is_terminated {
+            return Poll::Ready(None);
+        }
+
+        if self.next.is_none() {
+            let mut receiver = self
+                .receiver
+                .take()
+                .expect("receiver is here if not receiving");
+            self.next = Some(
+                async move {
+                    let outcome = receiver.recv_owned().await;
+                    (receiver, outcome)
+                }
+                .boxed(),
+            );
+        }
+
+        let next = self.next.as_mut().expect("receive in progress");
+        let (receiver, outcome) = futures::ready!(next.poll_unpin(cx));
+        self.next = None;
+        self.receiver = Some(receiver);
+        self.is_terminated = outcome.is_err();
+
+        Poll::Ready(Some(outcome))
+    }
+}
+
+impl FusedStream for RecvStream {
+    fn is_terminated(&self) -> bool {
+        self.is_terminated
+    }
+}
@@ -0,0 +1,637 @@
+use futures_timer::Delay;
+use std::collections::VecDeque;
+use std::fs::*;
+use std::io::{self, Read};
+use std::ops::{Deref, DerefMut};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::durability::{replace_file, Durability, Syncer};
+use crate::encryption::Keyring;
+use crate::error::TryRecvError;
+use crate::record::RecordFormat;
+use crate::state::{QueueState, QueueStatePersistence};
+use crate::sync::{FileGuard, SyncFollower, LOCK_RETRY_INTERVAL, SPIN_LOCK_TIMEOUT};
+use crate::version::check_queue_version;
+
+use super::delayed::{self, delayed_dirname};
+use super::receiver::{recv_lock_filename, spin_acquire_recv_lock};
+use super::{remove_consumed_segments, segment_filename, HEADER_EOF};
+
+/// The name of the lease table in the queue folder.
+fn leases_filename<P: AsRef<Path>>(base: P) -> PathBuf {
+    base.as_ref().join("recv-leases")
+}
+
+/// The current time, in milliseconds since the Unix epoch. This has to be wall
+/// clock time, since leases are shared between processes.
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Reads the element at a given position in the queue, if it is already
+/// entirely there. Returns the element and the position right after it.
+fn read_at(
+    base: &Path,
+    record_format: RecordFormat,
+    keyring: Option<&Keyring>,
+    mut state: QueueState,
+) -> io::Result<Option<(Vec<u8>, QueueState)>> {
+    let mut element = vec![];
+
+    loop {
+        let mut follower = SyncFollower::open(segment_filename(base, state.segment))?;
+        follower.seek(io::SeekFrom::Start(state.position))?;
+
+        // Read header:
+        let mut header = [0; 4];
+        match follower.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        // If the header is EOF, advance segment and try again:
+        if header == HEADER_EOF {
+            log::trace!("got EOF header. Advancing...");
+            state.advance_segment();
+            continue;
+        }
+
+        // With the length, read the data:
+        let decoded = record_format.decode_header(header, state.segment, state.position)?;
+        let len = decoded.len() as usize;
+        let mut data = vec![0; len];
+        match follower.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(err) => return Err(err),
+        }
+
+        let record = record_format.decode(data, state.segment, state.position)?;
+        state.advance_position(4 + len as u64);
+        if element.is_empty() {
+            element = record.payload;
+        } else {
+            element.extend(record.payload);
+        }
+
+        // The rest of the element is in the next record:
+        if record.is_continued {
+            continue;
+        }
+
+        let (element, _) = record.encoding.decode(element, keyring)?;
+        break Ok(Some((element, state)));
+    }
+}
+
+/// One element claimed by a worker, but not yet part of the receiver state.
+#[derive(Debug, Clone, Copy)]
+struct LeaseEntry {
+    /// Where the element starts.
+    start: QueueState,
+    /// Where the element ends (and the next one starts).
+    end: QueueState,
+    /// When the lease expires, in milliseconds since the Unix epoch.
+    expires_at: u64,
+    /// A random number identifying the current lease on the element. Every
+    /// claim gets a new one, so that a worker whose lease has expired cannot
+    /// acknowledge or release the element once it was claimed again.
+    lease_id: u64,
+    /// Whether the element was already acknowledged.
+    is_acked: bool,
+}
+
+impl LeaseEntry {
+    const SIZE: usize = 8 * 6 + 1;
+
+    fn encode(&self) -> [u8; LeaseEntry::SIZE] {
+        let mut encoded = [0; LeaseEntry::SIZE];
+
+        encoded[0..8].copy_from_slice(&self.start.segment.to_be_bytes());
+        encoded[8..16].copy_from_slice(&self.start.position.to_be_bytes());
+        encoded[16..24].copy_from_slice(&self.end.segment.to_be_bytes());
+        encoded[24..32].copy_from_slice(&self.end.position.to_be_bytes());
+        encoded[32..40].copy_from_slice(&self.expires_at.to_be_bytes());
+        encoded[40..48].copy_from_slice(&self.lease_id.to_be_bytes());
+        encoded[48] = self.is_acked as u8;
+
+        encoded
+    }
+
+    fn decode(encoded: &[u8]) -> LeaseEntry {
+        let u64_at = |i: usize| {
+            let mut buffer = [0; 8];
+            buffer.copy_from_slice(&encoded[i..i + 8]);
+            u64::from_be_bytes(buffer)
+        };
+
+        LeaseEntry {
+            start: QueueState {
+                segment: u64_at(0),
+                position: u64_at(8),
+            },
+            end: QueueState {
+                segment: u64_at(16),
+                position: u64_at(24),
+            },
+            expires_at: u64_at(32),
+            lease_id: u64_at(40),
+            is_acked: encoded[48] != 0,
+        }
+    }
+}
+
+/// The state shared by all the workers of a queue. This is only ever loaded
+/// while holding the receiver lock, which is released when the table is
+/// dropped.
+struct LeaseTable {
+    base: PathBuf,
+    /// The position before which everything was acknowledged. This is the
+    /// state saved in `recv-metadata`, just like for a regular receiver.
+    watermark: QueueState,
+    persistence: QueueStatePersistence,
+    /// The elements that were claimed, in queue order.
+    entries: VecDeque<LeaseEntry>,
+    syncer: Syncer,
+    _file_guard: FileGuard,
+}
+
+impl LeaseTable {
+    /// Locks the queue for receiving and loads the table.
+    fn load(base: &Path, durability: Durability) -> io::Result<LeaseTable> {
+        let file_guard = spin_acquire_recv_lock(base)?;
+        LeaseTable::load_locked(base, durability, file_guard)
+    }
+
+    /// Same as [`LeaseTable::load`], but returns `None` right away if the queue
+    /// is locked.
+    fn try_load(base: &Path, durability: Durability) -> io::Result<Option<LeaseTable>> {
+        match FileGuard::try_lock(recv_lock_filename(base))? {
+            Some(file_guard) => Ok(Some(LeaseTable::load_locked(base, durability, file_guard)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn load_locked(
+        base: &Path,
+        durability: Durability,
+        file_guard: FileGuard,
+    ) -> io::Result<LeaseTable> {
+        let mut persistence = QueueStatePersistence::new();
+        persistence.set_durability(durability);
+        let watermark = persistence.open(base)?;
+
+        let entries = match File::open(leases_filename(base)) {
+            Ok(mut file) => {
+                let mut contents = vec![];
+                file.read_to_end(&mut contents)?;
+                contents
+                    .chunks_exact(LeaseEntry::SIZE)
+                    .map(LeaseEntry::decode)
+                    .collect()
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(err) => return Err(err),
+        };
+
+        Ok(LeaseTable {
+            base: base.to_path_buf(),
+            watermark,
+            persistence,
+            entries,
+            syncer: Syncer::new(durability),
+            _file_guard: file_guard,
+        })
+    }
+
+    /// Saves the table. The file is replaced atomically, so that a crash never
+    /// leaves a half-written table behind.
+    fn save(&mut self) -> io::Result<()> {
+        let contents = self
+            .entries
+            .iter()
+            .flat_map(LeaseEntry::encode)
+            .collect::<Vec<_>>();
+
+        replace_file(&leases_filename(&self.base), &contents, &mut self.syncer)
+    }
+
+    /// The position of the next element that was never claimed.
+    fn cursor(&self) -> QueueState {
+        self.entries
+            .back()
+            .map(|entry| entry.end)
+            .unwrap_or(self.watermark)
+    }
+
+    /// Finds the entry of a lease, if it was not claimed by someone else since.
+    fn find(&mut self, start: QueueState, lease_id: u64) -> Option<&mut LeaseEntry> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.start == start && entry.lease_id == lease_id)
+    }
+
+    /// Moves the watermark past all the acknowledged elements at the front of
+    /// the table, deleting the segments that are now fully consumed.
+    fn advance_watermark(&mut self) -> io::Result<()> {
+        let initial_watermark = self.watermark;
+
+        while let Some(entry) = self.entries.front() {
+            if !entry.is_acked {
+                break;
+            }
+
+            self.watermark = entry.end;
+            self.entries.pop_front();
+        }
+
+        if self.watermark != initial_watermark {
+            if self.watermark.segment > initial_watermark.segment {
+                remove_consumed_segments(&self.base, Some((None, self.watermark)), None)?;
+            }
+
+            self.persistence.save(&self.watermark)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A builder for [`Worker`]s. Use this if you want to have fine-grained control
+/// over the configuration of the workers. Most defaults should be ok of most
+/// applications.
+pub struct WorkerBuilder {
+    lease_duration: Duration,
+    poll_every: Duration,
+    durability: Durability,
+    keyring: Option<Keyring>,
+}
+
+impl Default for WorkerBuilder {
+    fn default() -> WorkerBuilder {
+        WorkerBuilder {
+            lease_duration: Duration::from_secs(30),
+            poll_every: Duration::from_millis(50),
+            durability: Durability::default(),
+            keyring: None,
+        }
+    }
+}
+
+impl WorkerBuilder {
+    pub fn new() -> WorkerBuilder {
+        WorkerBuilder::default()
+    }
+
+    /// Sets for how long a claimed element stays invisible to other workers. If
+    /// the element is not acknowledged within this time, it becomes visible
+    /// again and another worker may claim it.
+    ///
+    /// Default value: 30 seconds.
+    pub fn lease_duration(mut self, duration: Duration) -> WorkerBuilder {
+        self.lease_duration = duration;
+        self
+    }
+
+    /// Sets how often [`Worker::claim`] checks the queue for new elements (or
+    /// for expired leases) while waiting.
+    ///
+    /// Default value: every 50 milliseconds.
+    pub fn poll_every(mut self, interval: Duration) -> WorkerBuilder {
+        self.poll_every = interval;
+        self
+    }
+
+    /// Sets how hard the workers try to get the shared receiver state onto the
+    /// disk. See [`crate::ReceiverBuilder::durability`].
+    ///
+    /// Default value: `Durability::Flush`.
+    pub fn durability(mut self, durability: Durability) -> WorkerBuilder {
+        self.durability = durability;
+        self
+    }
+
+    /// Sets the keys used to decrypt encrypted elements. See
+    /// [`crate::SenderBuilder::keyring`].
+    ///
+    /// Default value: `None` (encrypted elements cannot be claimed).
+    pub fn keyring(mut self, keyring: Keyring) -> WorkerBuilder {
+        self.keyring = Some(keyring);
+        self
+    }
+
+    /// Opens a queue for receiving as one worker in a pool of competing
+    /// consumers. As opposed to [`crate::Receiver`], many workers, in the same
+    /// process or in different processes, may receive from the same queue.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an IO error if any is encountered while
+    /// opening the queue.
+    pub fn open<P: AsRef<Path>>(self, base: P) -> io::Result<Worker> {
+        // Guarantee that the queue exists:
+        create_dir_all(base.as_ref())?;
+
+        log::trace!("created queue directory");
+
+        // Versioning stuff (this should be lightning-fast. Therefore, shameless block):
+        let record_format = check_queue_version(base.as_ref(), None)?;
+
+        Ok(Worker {
+            base: PathBuf::from(base.as_ref()),
+            record_format,
+            lease_duration: self.lease_duration,
+            poll_every: self.poll_every,
+            durability: self.durability,
+            keyring: self.keyring,
+            due_checked_at: Arc::new(Mutex::new(None)),
+        })
+    }
+}
+
+/// One receiver in a pool of competing consumers (a work queue). Each element
+/// in the queue is delivered to only one worker at a time, under a [`Lease`].
+/// If the lease is not acknowledged before it expires, the element becomes
+/// visible to all the workers again. Therefore, delivery is _at least once_.
+///
+/// The workers share the receiver state of the queue, in the `recv-metadata`
+/// and `recv-leases` files. Every operation holds the `recv.lock` file while
+/// it runs. So, do not open a [`crate::Receiver`] on a queue with workers: it
+/// holds the lock all the time and the workers would wait for it to go away.
+/// Workers also do not look at time to live (see
+/// [`crate::Sender::try_send_with_ttl`]): expired elements are delivered like
+/// any other. Nor do they hand out [`crate::Metadata`] or upgrade elements of
+/// older schema versions (see [`crate::Schema`]), nor ever claim delayed
+/// elements (see [`crate::Sender::send_at`]): they only log a warning when
+/// some are due.
+///
+/// This structure is cheap to clone and all clones belong to the same pool.
+#[derive(Clone)]
+pub struct Worker {
+    base: PathBuf,
+    record_format: RecordFormat,
+    lease_duration: Duration,
+    poll_every: Duration,
+    durability: Durability,
+    keyring: Option<Keyring>,
+    /// Last time the pool looked for due delayed elements, to warn about them.
+    due_checked_at: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Worker {
+    /// Opens a queue for receiving as one worker in a pool of competing
+    /// consumers, with the default configuration.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an IO error if any is encountered while
+    /// opening the queue.
+    pub fn open<P: AsRef<Path>>(base: P) -> io::Result<Worker> {
+        WorkerBuilder::default().open(base)
+    }
+
+    /// Claims the next visible element and returns it under a lease, if there
+    /// is any. Elements whose leases have expired are claimed before new ones.
+    fn claim_next(&self, mut table: LeaseTable) -> io::Result<Option<Lease>> {
+        self.warn_if_due()?;
+
+        let now = now_millis();
+        let expires_at = now + self.lease_duration.as_millis() as u64;
+        let lease_id = rand::random();
+
+        // Expired leases first:
+        let expired = table
+            .entries
+            .iter_mut()
+            .find(|entry| !entry.is_acked && entry.expires_at <= now);
+
+        let (data, start, end) = if let Some(entry) = expired {
+            log::debug!("reclaiming expired lease at {:?}", entry.start);
+            entry.expires_at = expires_at;
+            entry.lease_id = lease_id;
+            let keyring = self.keyring.as_ref();
+            let (data, end) = read_at(&self.base, self.record_format, keyring, entry.start)?
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("leased element at {:?} is incomplete", entry.start),
+                    )
+                })?;
+            (data, entry.start, end)
+        } else {
+            let start = table.cursor();
+
+            match read_at(&self.base, self.record_format, self.keyring.as_ref(), start)? {
+                Some((data, end)) => {
+                    table.entries.push_back(LeaseEntry {
+                        start,
+                        end,
+                        expires_at,
+                        lease_id,
+                        is_acked: false,
+                    });
+                    (data, start, end)
+                }
+                None => return Ok(None),
+            }
+        };
+
+        table.save()?;
+
+        log::trace!("claimed element from {:?} to {:?}", start, end);
+
+        Ok(Some(Lease {
+            base: self.base.clone(),
+            durability: self.durability,
+            start,
+            lease_id,
+            item: Some(data),
+            was_finished: false,
+        }))
+    }
+
+    /// Warns if there are delayed elements due, which workers never claim.
+    /// The pool looks at the delayed folder at most once every
+    /// `delayed::SCAN_INTERVAL`.
+    fn warn_if_due(&self) -> io::Result<()> {
+        {
+            let mut due_checked_at = self
+                .due_checked_at
+                .lock()
+                .expect("due check mutex poisoned");
+            if matches!(*due_checked_at, Some(at) if at.elapsed() < delayed::SCAN_INTERVAL) {
+                return Ok(());
+            }
+
+            *due_checked_at = Some(Instant::now());
+        }
+
+        delayed::warn_if_due(&delayed_dirname(&self.base, None), "Worker::claim")
+    }
+
+    /// Tries to claim an element from the queue. The returned value is a lease
+    /// that has to be acknowledged with [`Lease::ack`] before it expires.
+    /// Otherwise, the element becomes visible to the other workers again.
+    pub fn try_claim(&self) -> Result<Lease, TryRecvError> {
+        let table = LeaseTable::load(&self.base, self.durability)?;
+        self.claim_next(table)?.ok_or(TryRecvError::QueueEmpty)
+    }
+
+    /// Claims an element from the queue, waiting for one to become visible
+    /// if necessary. The returned value is a lease that has to be acknowledged
+    /// with [`Lease::ack`] before it expires. Otherwise, the element becomes
+    /// visible to the other workers again.
+    ///
+    /// The workers cannot know when leases held by other processes expire.
+    /// Therefore, this function polls the queue every so often (as configured
+    /// in [`WorkerBuilder::poll_every`]) while waiting.
+    ///
+    /// # Errors
+    ///
+    /// Besides any IO error, this function returns an error of kind
+    /// `WouldBlock` if another worker holds the `recv.lock` file for too long,
+    /// which probably means that it has died while holding it.
+    pub async fn claim(&self) -> io::Result<Lease> {
+        loop {
+            if let Some(lease) = self.claim_next(self.lock_table().await?)? {
+                break Ok(lease);
+            }
+
+            Delay::new(self.poll_every).await;
+        }
+    }
+
+    /// Loads the lease table, awaiting a little between tries while some
+    /// other worker holds the lock.
+    async fn lock_table(&self) -> io::Result<LeaseTable> {
+        let started_at = Instant::now();
+
+        loop {
+            if let Some(table) = LeaseTable::try_load(&self.base, self.durability)? {
+                break Ok(table);
+            } else if started_at.elapsed() >= SPIN_LOCK_TIMEOUT {
+                break Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "queue `{}` still locked by another worker after {:?}",
+                        self.base.to_string_lossy(),
+                        SPIN_LOCK_TIMEOUT
+                    ),
+                ));
+            }
+
+            Delay::new(LOCK_RETRY_INTERVAL).await;
+        }
+    }
+}
+
+/// An element claimed by a [`Worker`]. While the lease holds, no other worker
+/// will receive this element. Use [`Lease::ack`] once the element is processed.
+///
+/// If it is dropped without being acknowledged, the lease will be released in
+/// a "best effort" policy, making the element visible again right away. If
+/// you *can* do something with the IO error, you may use [`Lease::release`]
+/// explicitly to catch it.
+///
+/// This struct implements `Deref` and `DerefMut`. As opposed to
+/// [`crate::queue::RecvGuard`], it does not borrow the worker. So, you may
+/// move it to another task and acknowledge it from there.
+pub struct Lease {
+    base: PathBuf,
+    durability: Durability,
+    start: QueueState,
+    lease_id: u64,
+    item: Option<Vec<u8>>,
+    was_finished: bool,
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        if !self.was_finished {
+            if let Err(err) = self.release_mut() {
+                log::error!("unable to release lease on drop: {}", err);
+            }
+        }
+    }
+}
+
+impl Deref for Lease {
+    type Target = Vec<u8>;
+    fn deref(&self) -> &Vec<u8> {
+        self.item.as_ref().expect("unreachable")
+    }
+}
+
+impl DerefMut for Lease {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        self.item.as_mut().expect("unreachable")
+    }
+}
+
+impl Lease {
+    /// Acknowledges the element, consuming this lease. The receiver state of
+    /// the queue advances as soon as all the elements before this one are
+    /// also acknowledged. Only then fully consumed segments are deleted.
+    ///
+    /// If the lease has already expired, it is still acknowledged, unless
+    /// another worker has claimed the element in the meantime.
+    ///
+    /// # Errors
+    ///
+    /// Besides any IO error, this function returns an error of kind `TimedOut`
+    /// if the lease expired and another worker claimed the element. It is now
+    /// up to that worker to acknowledge it.
+    pub fn ack(mut self) -> io::Result<()> {
+        let mut table = LeaseTable::load(&self.base, self.durability)?;
+        self.was_finished = true;
+
+        if let Some(entry) = table.find(self.start, self.lease_id) {
+            entry.is_acked = true;
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "lease on element at {:?} expired and was claimed by another worker",
+                    self.start
+                ),
+            ));
+        }
+
+        table.advance_watermark()?;
+        table.save()?;
+
+        Ok(())
+    }
+
+    /// Same as release, but doesn't consume the lease. This is for internal use only.
+    fn release_mut(&mut self) -> io::Result<()> {
+        let mut table = LeaseTable::load(&self.base, self.durability)?;
+
+        // If the element was claimed again, it is not ours to release anymore:
+        if let Some(entry) = table.find(self.start, self.lease_id) {
+            if !entry.is_acked {
+                entry.expires_at = 0;
+            }
+        }
+
+        table.save()?;
+        self.was_finished = true;
+
+        Ok(())
+    }
+
+    /// Gives up on the lease, making the element visible to all the workers
+    /// again right away. This is also done on drop. However, on drop, the
+    /// possible IO error is ignored (but logged as an error) because we
+    /// cannot have errors inside drops. Use this if you want to control
+    /// errors at release.
+    pub fn release(mut self) -> io::Result<()> {
+        self.release_mut()
+    }
+}
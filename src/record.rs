@@ -0,0 +1,1210 @@
+//! The formats of the records in the queue segments. Every record starts with a
+//! [`Header`], holding the length of what comes after it (the body). What the
+//! body is depends on the format:
+//!
+//! * [`RecordFormat::V1`]: the body is the payload itself. This is the format
+//!   of every queue before `v2` was introduced.
+//! * [`RecordFormat::V2`]: the body is a CRC32C checksum (4 bytes, big endian),
+//!   followed by a flags byte and then the payload. The checksum covers the
+//!   flags and the payload. The header is SECDED (see [`crate::header`]), so
+//!   single flipped bits in the length are corrected. If the [`FLAG_EXPIRES`]
+//!   flag is set, the flags are followed by the time the element expires, in
+//!   milliseconds since the epoch (8 bytes, big endian), which the checksum
+//!   also covers. Then, if the [`FLAG_METADATA`] flag is set, comes the
+//!   [`Metadata`] of the element: the sequence number (8 bytes, big endian),
+//!   the timestamp in milliseconds since the epoch (8 bytes, big endian), the
+//!   number of headers (2 bytes, big endian) and then each header as the
+//!   length of its name (2 bytes, big endian), the name, the length of its
+//!   value (2 bytes, big endian) and the value. Then, if the [`FLAG_SCHEMA`]
+//!   flag is set, comes the version of the schema of the element (4 bytes,
+//!   big endian; see [`crate::Schema`]).
+//!
+//! In `v2`, the payload may also be compressed, as a whole, before being split
+//! into chunks. The [`FLAG_LZ4`] or [`FLAG_ZSTD`] flag in every chunk tells
+//! which algorithm was used. Compressed payloads are only written if they are
+//! smaller than the original. Then, if the [`FLAG_ENCRYPTED`] flag is set, the
+//! payload is encrypted as a whole, as described in [`crate::encryption`]. The
+//! metadata of an encrypted payload is not in the records, but encrypted with
+//! the payload, right before it.
+//!
+//! A record can hold at most [`Header::MAX_LEN`] bytes. In `v1`, this is the
+//! biggest payload that can be sent. In `v2`, bigger payloads are split into
+//! _chunks_, one per record, all written at once. Every chunk but the last one
+//! has the [`FLAG_CONTINUED`] flag set and readers put the chunks back
+//! together before handing the payload out.
+//!
+//! The format is chosen when the queue is created and recorded in its
+//! `version` file. See [`crate::version::check_queue_version`].
+
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::io::{self, BufReader, Read, Seek, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::encryption::{self, Keyring};
+use crate::error::{Corrupted, CorruptionKind};
+use crate::header::{Decoded, Header};
+
+/// The size of the checksum and the flags in a `v2` record.
+const V2_PREAMBLE_LEN: usize = 5;
+
+/// The biggest chunk of payload that fits in a `v2` record. Records can be no
+/// longer than `Header::MAX_LEN - 1`, since the SECDED header for
+/// `Header::MAX_LEN` is all ones, just like the EOF header.
+const V2_MAX_CHUNK_LEN: usize = Header::MAX_LEN as usize - 1 - V2_PREAMBLE_LEN;
+
+/// Flag of a `v2` record whose payload continues in the next record.
+const FLAG_CONTINUED: u8 = 0b1;
+
+/// Flag of a `v2` record whose payload starts with an expiry time.
+const FLAG_EXPIRES: u8 = 0b10;
+
+/// The size of the expiry time in a `v2` record.
+const EXPIRY_LEN: usize = 8;
+
+/// Flag of a `v2` record whose payload starts with metadata (after the expiry
+/// time, if any).
+const FLAG_METADATA: u8 = 0b100;
+
+/// Flag of a `v2` record whose payload is compressed with LZ4, as one block
+/// prefixed by its uncompressed size (4 bytes, little endian).
+const FLAG_LZ4: u8 = 0b1000;
+
+/// Flag of a `v2` record whose payload is compressed with Zstandard, as one
+/// frame.
+const FLAG_ZSTD: u8 = 0b1_0000;
+
+/// Flag of a `v2` record whose payload is encrypted (after being compressed,
+/// if it is).
+const FLAG_ENCRYPTED: u8 = 0b10_0000;
+
+/// Flag of a `v2` record whose payload starts with the schema version of the
+/// element (after the metadata, if any).
+const FLAG_SCHEMA: u8 = 0b100_0000;
+
+/// The size of the schema version in a `v2` record.
+const SCHEMA_VERSION_LEN: usize = 4;
+
+/// All the flags this version knows about.
+const KNOWN_FLAGS: u8 = FLAG_CONTINUED
+    | FLAG_EXPIRES
+    | FLAG_METADATA
+    | FLAG_LZ4
+    | FLAG_ZSTD
+    | FLAG_ENCRYPTED
+    | FLAG_SCHEMA;
+
+/// How many headers were corrected so far.
+static CORRECTED_HEADERS: AtomicU64 = AtomicU64::new(0);
+
+/// How many headers could not be corrected so far.
+static UNCORRECTABLE_HEADERS: AtomicU64 = AtomicU64::new(0);
+
+/// How many corrupted headers were read so far in this process, for all
+/// queues. The same header is counted every time it is read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeaderErrors {
+    /// Headers with a single flipped bit, which was corrected. This only
+    /// happens in [`RecordFormat::V2`].
+    pub corrected: u64,
+    /// Headers that could not be corrected. These are reported as
+    /// [`Corrupted`] errors.
+    pub uncorrectable: u64,
+}
+
+/// Gets how many corrupted headers were read so far in this process. See
+/// [`HeaderErrors`].
+pub fn header_errors() -> HeaderErrors {
+    HeaderErrors {
+        corrected: CORRECTED_HEADERS.load(Ordering::Relaxed),
+        uncorrectable: UNCORRECTABLE_HEADERS.load(Ordering::Relaxed),
+    }
+}
+
+/// A record, as read from a segment.
+#[derive(Debug)]
+pub(crate) struct Record {
+    /// The payload of the record (or a chunk of it).
+    pub payload: Vec<u8>,
+    /// Whether the payload continues in the next record.
+    pub is_continued: bool,
+    /// When the element expires, if ever. All the chunks of an element expire
+    /// at the same time.
+    pub expires_at: Option<SystemTime>,
+    /// The metadata of the element, if any. All the chunks of an element carry
+    /// the same metadata, unless it is encrypted with the element (see
+    /// [`Encoding::decode`]), in which case this is `None`.
+    pub metadata: Option<Metadata>,
+    /// The schema version of the element, if it was tagged with one. All the
+    /// chunks of an element carry the same version.
+    pub schema_version: Option<u32>,
+    /// How the element was compressed or encrypted. All the chunks of an
+    /// element are encoded the same way.
+    pub encoding: Encoding,
+}
+
+/// How a whole element was transformed before being split into records. Use
+/// [`Encoding::decode`] once the element is put back together.
+#[derive(Debug, Default)]
+pub(crate) struct Encoding {
+    /// The compression flags of the element, or `0` if it is not compressed.
+    pub compression: u8,
+    /// If the element is encrypted, the associated data its encryption
+    /// authenticates: the flags (but [`FLAG_CONTINUED`]), the expiry time and
+    /// the schema version, as written.
+    pub associated: Option<Vec<u8>>,
+    /// Whether the metadata of the element was encrypted together with it,
+    /// ahead of the (compressed) element, instead of being in every record.
+    pub sealed_metadata: bool,
+}
+
+impl Encoding {
+    /// Turns an element back into what was sent, decrypting it and then
+    /// decompressing it. Returns the element and the metadata that was
+    /// encrypted with it, if any.
+    ///
+    /// # Errors
+    ///
+    /// See [`decompress`] and [`encryption::decrypt`]. This function also
+    /// returns an error of kind `InvalidData` if the encrypted metadata is
+    /// malformed.
+    pub fn decode(
+        &self,
+        data: Vec<u8>,
+        keyring: Option<&Keyring>,
+    ) -> io::Result<(Vec<u8>, Option<Metadata>)> {
+        let (data, metadata) = match &self.associated {
+            Some(associated) => {
+                let mut data = encryption::decrypt(keyring, associated, &data)?;
+
+                if self.sealed_metadata {
+                    let (metadata, len) = Metadata::decode(&data).ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "malformed metadata in encrypted element",
+                        )
+                    })?;
+                    data.drain(..len);
+
+                    (data, Some(metadata))
+                } else {
+                    (data, None)
+                }
+            }
+            None => (data, None),
+        };
+
+        Ok((decompress(self.compression, data)?, metadata))
+    }
+}
+
+/// What goes in the records of a payload, besides the payload itself. Only
+/// `v2` has room for any of this.
+#[derive(Debug, Default)]
+pub(crate) struct Envelope<'a> {
+    /// When the payload expires, if ever.
+    pub expires_at: Option<SystemTime>,
+    /// The metadata of the payload, if any.
+    pub metadata: Option<&'a Metadata>,
+    /// The schema version to tag the payload with, if any.
+    pub schema_version: Option<u32>,
+    /// How to compress the payload, if at all.
+    pub compression: Option<Compression>,
+    /// What to encrypt the payload with, if anything.
+    pub keyring: Option<&'a Keyring>,
+}
+
+/// How elements are compressed in queues with record format `v2`. See
+/// [`crate::queue::SenderBuilder::compression`]. Each algorithm needs a
+/// feature of the same name, both for compressing and for decompressing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// LZ4, which is very fast, but does not compress as much. Needs the `lz4`
+    /// feature.
+    #[cfg(feature = "lz4")]
+    Lz4,
+    /// Zstandard, with the given compression level (from 1 to 22; 3 is the
+    /// usual default). Needs the `zstd` feature.
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+impl Compression {
+    /// The flag of the records compressed this way.
+    fn flag(&self) -> u8 {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => FLAG_LZ4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => FLAG_ZSTD,
+        }
+    }
+
+    /// Compresses a whole payload.
+    #[cfg_attr(not(any(feature = "lz4", feature = "zstd")), allow(unused_variables))]
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match *self {
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => zstd::bulk::compress(data, level),
+        }
+    }
+}
+
+/// Decompresses a whole payload, given the compression flags of its records.
+///
+/// # Errors
+///
+/// This function returns an error of kind `InvalidData` if the payload cannot
+/// be decompressed and of kind `Unsupported` if the feature for its algorithm
+/// is not enabled.
+fn decompress(compression: u8, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match compression {
+        0 => Ok(data),
+        #[cfg(feature = "lz4")]
+        FLAG_LZ4 => lz4_flex::decompress_size_prepended(&data)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        #[cfg(not(feature = "lz4"))]
+        FLAG_LZ4 => Err(unsupported("lz4")),
+        #[cfg(feature = "zstd")]
+        FLAG_ZSTD => zstd::stream::decode_all(&data[..])
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        #[cfg(not(feature = "zstd"))]
+        FLAG_ZSTD => Err(unsupported("zstd")),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown compression flags {:#b}", compression),
+        )),
+    }
+}
+
+/// The error for a payload compressed with an algorithm whose feature is not
+/// enabled.
+#[cfg(not(all(feature = "lz4", feature = "zstd")))]
+fn unsupported(feature: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "element is compressed with {0}; enable the `{0}` feature to read it",
+            feature
+        ),
+    )
+}
+
+/// The metadata of an element, sent along with it in queues with record format
+/// `v2`. See [`crate::queue::SenderBuilder::metadata`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    /// The number of the element in the queue. Each element gets the number
+    /// after the one of the element before it.
+    pub sequence: u64,
+    /// When the element was sent, with millisecond precision.
+    pub timestamp: SystemTime,
+    /// Headers set by the user, such as a trace id or a content type.
+    pub headers: BTreeMap<String, String>,
+}
+
+impl Metadata {
+    /// The maximum size of encoded metadata, headers included.
+    pub const MAX_LEN: usize = u16::MAX as usize;
+
+    /// Appends the encoded metadata to a buffer.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidInput` if the metadata is
+    /// bigger than [`Metadata::MAX_LEN`]. Nothing is appended in this case.
+    fn encode_into(&self, buffer: &mut Vec<u8>) -> io::Result<()> {
+        let len = self
+            .headers
+            .iter()
+            .map(|(name, value)| 4 + name.len() + value.len())
+            .sum::<usize>()
+            + 18;
+
+        if len > Metadata::MAX_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "metadata of {} bytes is too big (max. {} bytes)",
+                    len,
+                    Metadata::MAX_LEN
+                ),
+            ));
+        }
+
+        // Every length fits in two bytes now:
+        buffer.extend_from_slice(&self.sequence.to_be_bytes());
+        buffer.extend_from_slice(&as_millis(self.timestamp).to_be_bytes());
+        buffer.extend_from_slice(&(self.headers.len() as u16).to_be_bytes());
+        for (name, value) in &self.headers {
+            buffer.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(name.as_bytes());
+            buffer.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(value.as_bytes());
+        }
+
+        Ok(())
+    }
+
+    /// Decodes the metadata at the start of some bytes. Returns the metadata
+    /// and its encoded length, or `None` if it is malformed.
+    fn decode(bytes: &[u8]) -> Option<(Metadata, usize)> {
+        let mut cursor = 0;
+        let mut take = |len: usize| {
+            let taken = bytes.get(cursor..cursor + len)?;
+            cursor += len;
+            Some(taken)
+        };
+
+        let sequence = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let timestamp = u64::from_be_bytes(take(8)?.try_into().ok()?);
+        let n_headers = u16::from_be_bytes(take(2)?.try_into().ok()?);
+
+        let mut headers = BTreeMap::new();
+        for _ in 0..n_headers {
+            let name_len = u16::from_be_bytes(take(2)?.try_into().ok()?);
+            let name = String::from_utf8(take(name_len as usize)?.to_vec()).ok()?;
+            let value_len = u16::from_be_bytes(take(2)?.try_into().ok()?);
+            let value = String::from_utf8(take(value_len as usize)?.to_vec()).ok()?;
+            headers.insert(name, value);
+        }
+
+        let metadata = Metadata {
+            sequence,
+            timestamp: UNIX_EPOCH + Duration::from_millis(timestamp),
+            headers,
+        };
+
+        Some((metadata, cursor))
+    }
+}
+
+fn as_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// The format of the records in a queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RecordFormat {
+    /// Just the length and the payload. Corruption in the payload is never
+    /// detected.
+    #[default]
+    V1,
+    /// Length, checksum, flags and payload. Corruption in the payload is
+    /// detected on read.
+    V2,
+}
+
+impl std::fmt::Display for RecordFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecordFormat::V1 => write!(f, "v1"),
+            RecordFormat::V2 => write!(f, "v2"),
+        }
+    }
+}
+
+impl FromStr for RecordFormat {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<RecordFormat> {
+        match s {
+            "v1" => Ok(RecordFormat::V1),
+            "v2" => Ok(RecordFormat::V2),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown record format `{}`", s),
+            )),
+        }
+    }
+}
+
+impl RecordFormat {
+    /// Checks whether a payload of a given length can be written in this
+    /// format. Nothing is written if this fails.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidInput` if the payload is
+    /// too big for a `v1` record.
+    pub(crate) fn check_len(&self, len: usize) -> io::Result<()> {
+        match self {
+            RecordFormat::V1 if len > Header::MAX_LEN as usize => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "payload of {} bytes is too big for record format v1 (max. {} bytes); \
+                    use record format v2 for bigger payloads",
+                    len,
+                    Header::MAX_LEN
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes the header of a record, correcting it, if possible. Both
+    /// corrected and uncorrectable headers are logged and counted (see
+    /// [`header_errors`]). The segment and the position of the record are only
+    /// used for error reporting.
+    pub(crate) fn decode_header(
+        &self,
+        header: [u8; 4],
+        segment: u64,
+        position: u64,
+    ) -> io::Result<Header> {
+        let decoded = match self {
+            RecordFormat::V1 => Header::decode(header).map_or(Decoded::Uncorrectable, Decoded::Ok),
+            RecordFormat::V2 => match Header::decode_secded(header) {
+                // Never written. This is what a damaged EOF header looks like:
+                Decoded::Corrected { header, .. } if header.len() == Header::MAX_LEN => {
+                    Decoded::Uncorrectable
+                }
+                decoded => decoded,
+            },
+        };
+
+        match decoded {
+            Decoded::Ok(decoded) => Ok(decoded),
+            Decoded::Corrected {
+                header: corrected,
+                bit,
+            } => {
+                CORRECTED_HEADERS.fetch_add(1, Ordering::Relaxed);
+                log::warn!(
+                    "corrected bit {} of header {:?} at segment {}, position {}",
+                    bit,
+                    header,
+                    segment,
+                    position
+                );
+
+                Ok(corrected)
+            }
+            Decoded::Uncorrectable => {
+                UNCORRECTABLE_HEADERS.fetch_add(1, Ordering::Relaxed);
+                log::error!(
+                    "uncorrectable header {:?} at segment {}, position {}",
+                    header,
+                    segment,
+                    position
+                );
+
+                Err(Corrupted {
+                    segment,
+                    position,
+                    kind: CorruptionKind::Header(header),
+                }
+                .into())
+            }
+        }
+    }
+
+    /// Writes a whole payload, as one or more records (header and body), with
+    /// nothing else. Returns the number of bytes written.
+    #[cfg(test)]
+    pub(crate) fn write<W: Write>(&self, writer: &mut W, data: &[u8]) -> io::Result<u64> {
+        self.write_enveloped(writer, data, &Envelope::default())
+    }
+
+    /// Writes a whole payload, as one or more records (header and body), with
+    /// an expiry time, metadata or a schema version in every record, if any.
+    /// The payload is compressed and then encrypted first, if asked to. Returns
+    /// the number of bytes written. Nothing is written if this fails.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidInput` if the envelope is
+    /// not empty, but the format is `v1`, or if the metadata is too big.
+    pub(crate) fn write_enveloped<W: Write>(
+        &self,
+        writer: &mut W,
+        data: &[u8],
+        envelope: &Envelope,
+    ) -> io::Result<u64> {
+        let mut extras = vec![];
+        let mut flags = 0;
+
+        if let Some(expires_at) = envelope.expires_at {
+            extras.extend_from_slice(&as_millis(expires_at).to_be_bytes());
+            flags |= FLAG_EXPIRES;
+        }
+
+        // Encrypted elements keep their metadata secret, inside the ciphertext:
+        let mut metadata = vec![];
+        if let Some(envelope_metadata) = envelope.metadata {
+            envelope_metadata.encode_into(&mut metadata)?;
+            flags |= FLAG_METADATA;
+
+            if envelope.keyring.is_none() {
+                extras.append(&mut metadata);
+            }
+        }
+
+        if let Some(schema_version) = envelope.schema_version {
+            extras.extend_from_slice(&schema_version.to_be_bytes());
+            flags |= FLAG_SCHEMA;
+        }
+
+        let compressed = match envelope.compression {
+            Some(compression) => Some((compression.flag(), compression.compress(data)?)),
+            None => None,
+        };
+
+        // Only worth it if it got any smaller:
+        let data = match &compressed {
+            Some((flag, compressed)) if compressed.len() < data.len() => {
+                flags |= flag;
+                compressed
+            }
+            _ => data,
+        };
+
+        // Everything but the chunking is authenticated:
+        let encrypted;
+        let data = match envelope.keyring {
+            Some(keyring) => {
+                flags |= FLAG_ENCRYPTED;
+                let mut associated = vec![flags];
+                associated.extend_from_slice(&extras);
+                metadata.extend_from_slice(data);
+                encrypted = encryption::encrypt(keyring, &associated, &metadata)?;
+                &encrypted
+            }
+            None => data,
+        };
+
+        let max_chunk_len = V2_MAX_CHUNK_LEN - extras.len();
+        self.write_chunked(writer, data, &extras, flags, max_chunk_len)
+    }
+
+    /// Writes a payload in chunks of at most `max_chunk_len` bytes, each one
+    /// with the given extras (expiry time, metadata and schema version) and
+    /// their flags.
+    fn write_chunked<W: Write>(
+        &self,
+        writer: &mut W,
+        data: &[u8],
+        extras: &[u8],
+        extra_flags: u8,
+        max_chunk_len: usize,
+    ) -> io::Result<u64> {
+        self.check_len(data.len())?;
+
+        match self {
+            RecordFormat::V1 if extra_flags != 0 => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "expiry times, metadata, schema versions, compression and encryption need \
+                record format v2",
+            )),
+            RecordFormat::V1 => {
+                let header = Header::new(data.len() as u32).encode();
+                writer.write_all(&header)?;
+                writer.write_all(data)?;
+
+                Ok(4 + data.len() as u64)
+            }
+            RecordFormat::V2 => {
+                let mut written = 0;
+                let mut chunks = data.chunks(max_chunk_len).peekable();
+
+                // Even an empty payload gets its record:
+                if chunks.peek().is_none() {
+                    return write_v2_record(writer, &[], extras, extra_flags);
+                }
+
+                while let Some(chunk) = chunks.next() {
+                    let flags = if chunks.peek().is_some() {
+                        extra_flags | FLAG_CONTINUED
+                    } else {
+                        extra_flags
+                    };
+                    written += write_v2_record(writer, chunk, extras, flags)?;
+                }
+
+                Ok(written)
+            }
+        }
+    }
+
+    /// Turns the body of a record into its payload, checking its integrity on
+    /// the way. The segment and the position of the record are only used for
+    /// error reporting.
+    pub(crate) fn decode(
+        &self,
+        mut body: Vec<u8>,
+        segment: u64,
+        position: u64,
+    ) -> io::Result<Record> {
+        match self {
+            RecordFormat::V1 => Ok(Record {
+                payload: body,
+                is_continued: false,
+                expires_at: None,
+                metadata: None,
+                schema_version: None,
+                encoding: Encoding::default(),
+            }),
+            RecordFormat::V2 => {
+                let corrupted = |kind| Corrupted {
+                    segment,
+                    position,
+                    kind,
+                };
+
+                if body.len() < V2_PREAMBLE_LEN {
+                    return Err(corrupted(CorruptionKind::Malformed).into());
+                }
+
+                let expected = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let found = crc32c::crc32c(&body[4..]);
+                if expected != found {
+                    return Err(corrupted(CorruptionKind::Checksum { expected, found }).into());
+                }
+
+                let flags = body[4];
+                if flags & !KNOWN_FLAGS != 0 {
+                    return Err(corrupted(CorruptionKind::UnknownFlags(flags)).into());
+                }
+
+                body.drain(..V2_PREAMBLE_LEN);
+
+                let mut associated = if flags & FLAG_ENCRYPTED != 0 {
+                    Some(vec![flags & !FLAG_CONTINUED])
+                } else {
+                    None
+                };
+
+                let expires_at = if flags & FLAG_EXPIRES != 0 {
+                    if body.len() < EXPIRY_LEN {
+                        return Err(corrupted(CorruptionKind::Malformed).into());
+                    }
+
+                    let mut millis = [0; EXPIRY_LEN];
+                    millis.copy_from_slice(&body[..EXPIRY_LEN]);
+                    if let Some(associated) = &mut associated {
+                        associated.extend_from_slice(&millis);
+                    }
+                    body.drain(..EXPIRY_LEN);
+
+                    Some(UNIX_EPOCH + Duration::from_millis(u64::from_be_bytes(millis)))
+                } else {
+                    None
+                };
+
+                // Unless it is encrypted with the element:
+                let metadata = if flags & FLAG_METADATA != 0 && associated.is_none() {
+                    let (metadata, len) = Metadata::decode(&body)
+                        .ok_or_else(|| corrupted(CorruptionKind::Malformed))?;
+                    body.drain(..len);
+
+                    Some(metadata)
+                } else {
+                    None
+                };
+
+                let schema_version = if flags & FLAG_SCHEMA != 0 {
+                    if body.len() < SCHEMA_VERSION_LEN {
+                        return Err(corrupted(CorruptionKind::Malformed).into());
+                    }
+
+                    let mut version = [0; SCHEMA_VERSION_LEN];
+                    version.copy_from_slice(&body[..SCHEMA_VERSION_LEN]);
+                    if let Some(associated) = &mut associated {
+                        associated.extend_from_slice(&version);
+                    }
+                    body.drain(..SCHEMA_VERSION_LEN);
+
+                    Some(u32::from_be_bytes(version))
+                } else {
+                    None
+                };
+
+                Ok(Record {
+                    payload: body,
+                    is_continued: flags & FLAG_CONTINUED != 0,
+                    expires_at,
+                    metadata,
+                    schema_version,
+                    encoding: Encoding {
+                        compression: flags & (FLAG_LZ4 | FLAG_ZSTD),
+                        sealed_metadata: flags & FLAG_METADATA != 0 && associated.is_some(),
+                        associated,
+                    },
+                })
+            }
+        }
+    }
+
+    /// Reads a whole element from a buffer holding its records (and nothing
+    /// else), as written by [`RecordFormat::write_enveloped`], putting its
+    /// chunks back together and decoding it. Returns the element and its last
+    /// record, without the payload, for the expiry time and the metadata.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidData` if the records are
+    /// incomplete or corrupted, besides the errors of [`Encoding::decode`].
+    pub(crate) fn read_element(
+        &self,
+        records: &[u8],
+        keyring: Option<&Keyring>,
+    ) -> io::Result<(Vec<u8>, Record)> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "malformed records");
+        let mut element = vec![];
+        let mut position = 0;
+
+        loop {
+            let header = records
+                .get(position..position + 4)
+                .and_then(|header| self.decode_header_quietly(header.try_into().expect("4 bytes")))
+                .ok_or_else(malformed)?;
+
+            let body_start = position + 4;
+            let body = records
+                .get(body_start..body_start + header.len() as usize)
+                .ok_or_else(malformed)?;
+            let mut record = self
+                .decode(body.to_vec(), 0, position as u64)
+                .map_err(|_| malformed())?;
+            position = body_start + body.len();
+
+            element.append(&mut record.payload);
+            if !record.is_continued {
+                let (element, metadata) = record.encoding.decode(element, keyring)?;
+                if metadata.is_some() {
+                    record.metadata = metadata;
+                }
+
+                break Ok((element, record));
+            }
+        }
+    }
+
+    /// Finds the sequence number of the last element with metadata in a piece
+    /// of a segment starting at a record. This stops at the end of the
+    /// segment or at the first header that cannot be read, without counting
+    /// it (see [`header_errors`]). Records that cannot be decoded are skipped.
+    /// Metadata encrypted with its element is only read with the keyring and
+    /// only the last such element is decrypted.
+    pub(crate) fn last_sequence(&self, records: &[u8], keyring: Option<&Keyring>) -> Option<u64> {
+        // Only `v2` has metadata:
+        if *self == RecordFormat::V1 {
+            return None;
+        }
+
+        let mut last_sequence = None;
+        let mut last_sealed = None;
+        let mut element = vec![];
+        let mut position = 0;
+
+        while let Some(header) = records.get(position..position + 4) {
+            let header = match self.decode_header_quietly(header.try_into().expect("4 bytes")) {
+                Some(header) => header,
+                None => break,
+            };
+
+            let body_start = position + 4;
+            let body = match records.get(body_start..body_start + header.len() as usize) {
+                Some(body) => body,
+                None => break,
+            };
+
+            match self.decode(body.to_vec(), 0, 0) {
+                Ok(mut record) => {
+                    element.append(&mut record.payload);
+
+                    if !record.is_continued {
+                        let element = std::mem::take(&mut element);
+
+                        if let Some(metadata) = record.metadata {
+                            last_sequence = Some(metadata.sequence);
+                            last_sealed = None;
+                        } else if record.encoding.sealed_metadata {
+                            last_sealed = Some((element, record.encoding));
+                        }
+                    }
+                }
+                Err(_) => element.clear(),
+            }
+
+            position = body_start + body.len();
+        }
+
+        if let Some((element, encoding)) = last_sealed {
+            if let Ok((_, Some(metadata))) = encoding.decode(element, keyring) {
+                return Some(metadata.sequence);
+            }
+        }
+
+        last_sequence
+    }
+
+    /// Counts the elements in a segment, from a record at `position` on,
+    /// reading only the headers (and the flags, in `v2`). Returns how many
+    /// elements there are and how many bytes they take, headers included.
+    /// Elements that are not all there yet (e.g., being written) are not
+    /// counted. Like [`RecordFormat::last_sequence`], this stops at the end of
+    /// the segment or at the first header that cannot be read.
+    pub(crate) fn count_elements<R: Read + Seek>(
+        &self,
+        reader: &mut BufReader<R>,
+        mut position: u64,
+        end: u64,
+    ) -> io::Result<(u64, u64)> {
+        let mut elements = 0;
+        let mut bytes = 0;
+        let mut element_bytes = 0;
+
+        while position + 4 <= end {
+            let mut header = [0; 4];
+            reader.read_exact(&mut header)?;
+
+            let len = match self.decode_header_quietly(header) {
+                Some(header) => header.len() as u64,
+                None => break,
+            };
+
+            if position + 4 + len > end {
+                break;
+            }
+
+            let is_continued = match self {
+                RecordFormat::V1 => {
+                    reader.seek_relative(len as i64)?;
+                    false
+                }
+                RecordFormat::V2 if len >= V2_PREAMBLE_LEN as u64 => {
+                    let mut preamble = [0; V2_PREAMBLE_LEN];
+                    reader.read_exact(&mut preamble)?;
+                    reader.seek_relative((len - V2_PREAMBLE_LEN as u64) as i64)?;
+                    preamble[4] & FLAG_CONTINUED != 0
+                }
+                // Malformed. Receiving it will tell what is wrong:
+                RecordFormat::V2 => break,
+            };
+
+            position += 4 + len;
+            element_bytes += 4 + len;
+            if !is_continued {
+                elements += 1;
+                bytes += element_bytes;
+                element_bytes = 0;
+            }
+        }
+
+        Ok((elements, bytes))
+    }
+
+    /// Decodes the header of a record, like [`RecordFormat::decode_header`],
+    /// but without logging or counting errors. Returns `None` for the EOF
+    /// header and for headers that cannot be read.
+    fn decode_header_quietly(&self, header: [u8; 4]) -> Option<Header> {
+        if header == [255; 4] {
+            return None;
+        }
+
+        match self {
+            RecordFormat::V1 => Header::decode(header),
+            RecordFormat::V2 => match Header::decode_secded(header) {
+                Decoded::Ok(header) => Some(header),
+                // A damaged EOF header looks like this:
+                Decoded::Corrected { header, .. } if header.len() < Header::MAX_LEN => Some(header),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Writes a single `v2` record. The extras (expiry time, metadata and schema
+/// version) go between the flags and the chunk. Returns the number of bytes written.
+fn write_v2_record<W: Write>(
+    writer: &mut W,
+    chunk: &[u8],
+    extras: &[u8],
+    flags: u8,
+) -> io::Result<u64> {
+    let checksum = crc32c::crc32c_append(crc32c::crc32c(&[flags]), extras);
+    let checksum = crc32c::crc32c_append(checksum, chunk);
+    let len = V2_PREAMBLE_LEN + extras.len() + chunk.len();
+
+    let header = Header::new(len as u32).encode_secded();
+    writer.write_all(&header)?;
+    writer.write_all(&checksum.to_be_bytes())?;
+    writer.write_all(&[flags])?;
+    writer.write_all(extras)?;
+    writer.write_all(chunk)?;
+
+    Ok(4 + len as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        for format in [RecordFormat::V1, RecordFormat::V2] {
+            let mut record = vec![];
+            let written = format.write(&mut record, b"some data").unwrap();
+            assert_eq!(written, record.len() as u64);
+
+            let header = [record[0], record[1], record[2], record[3]];
+            let len = format.decode_header(header, 0, 0).unwrap().len();
+            assert_eq!(len as usize, record.len() - 4);
+
+            let record = format.decode(record[4..].to_vec(), 0, 0).unwrap();
+            assert_eq!(record.payload, b"some data");
+            assert!(!record.is_continued);
+        }
+    }
+
+    #[test]
+    fn test_detect_corruption() {
+        let mut record = vec![];
+        RecordFormat::V2.write(&mut record, b"some data").unwrap();
+        record[12] ^= 0b100;
+
+        let err = RecordFormat::V2
+            .decode(record[4..].to_vec(), 3, 14)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let corrupted = Corrupted::find(&err).unwrap();
+        assert_eq!((corrupted.segment, corrupted.position), (3, 14));
+        assert!(matches!(corrupted.kind, CorruptionKind::Checksum { .. }));
+    }
+
+    #[test]
+    fn test_chunks() {
+        let mut records = vec![];
+        let written = RecordFormat::V2
+            .write_chunked(&mut records, b"some data", &[], 0, 4)
+            .unwrap();
+        assert_eq!(written, records.len() as u64);
+
+        // Three records: "some", " dat" and "a".
+        let mut payload = vec![];
+        let mut continued = vec![];
+        let mut position = 0;
+        while position < records.len() {
+            let header = &records[position..position + 4];
+            let header = [header[0], header[1], header[2], header[3]];
+            let len = RecordFormat::V2
+                .decode_header(header, 0, position as u64)
+                .unwrap()
+                .len() as usize;
+            let body = records[position + 4..position + 4 + len].to_vec();
+            let record = RecordFormat::V2.decode(body, 0, position as u64).unwrap();
+            payload.extend(record.payload);
+            continued.push(record.is_continued);
+            position += 4 + len;
+        }
+
+        assert_eq!(payload, b"some data");
+        assert_eq!(continued, vec![true, true, false]);
+    }
+
+    #[test]
+    fn test_read_element() {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_234_567);
+        let extras = as_millis(expires_at).to_be_bytes();
+
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_chunked(&mut records, b"some data", &extras, FLAG_EXPIRES, 4)
+            .unwrap();
+        let (element, record) = RecordFormat::V2.read_element(&records, None).unwrap();
+        assert_eq!(element, b"some data");
+        assert_eq!(record.expires_at, Some(expires_at));
+
+        let err = RecordFormat::V2
+            .read_element(&records[..records.len() - 1], None)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_count_elements() {
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_chunked(&mut records, b"some data", &[], 0, 4)
+            .unwrap();
+        let first = records.len() as u64;
+        RecordFormat::V2.write(&mut records, b"more").unwrap();
+        let written = records.len() as u64;
+
+        let mut reader = BufReader::new(io::Cursor::new(records.clone()));
+        let counted = RecordFormat::V2
+            .count_elements(&mut reader, 0, written)
+            .unwrap();
+        assert_eq!(counted, (2, written));
+
+        // Half-written elements are not counted:
+        let mut reader = BufReader::new(io::Cursor::new(records));
+        let counted = RecordFormat::V2
+            .count_elements(&mut reader, 0, first - 1)
+            .unwrap();
+        assert_eq!(counted, (0, 0));
+    }
+
+    #[test]
+    fn test_expiry() {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_234_567);
+
+        let envelope = Envelope {
+            expires_at: Some(expires_at),
+            metadata: None,
+            schema_version: None,
+            compression: None,
+            keyring: None,
+        };
+
+        let mut record = vec![];
+        RecordFormat::V2
+            .write_enveloped(&mut record, b"some data", &envelope)
+            .unwrap();
+        let record = RecordFormat::V2.decode(record[4..].to_vec(), 0, 0).unwrap();
+        assert_eq!(record.payload, b"some data");
+        assert_eq!(record.expires_at, Some(expires_at));
+
+        let err = RecordFormat::V1
+            .write_enveloped(&mut vec![], b"some data", &envelope)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            sequence: 42,
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_234_567),
+            headers: vec![("trace-id".to_owned(), "abc".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        let envelope = Envelope {
+            expires_at: None,
+            metadata: Some(&metadata),
+            schema_version: None,
+            compression: None,
+            keyring: None,
+        };
+
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_enveloped(&mut records, b"some data", &envelope)
+            .unwrap();
+        let record = RecordFormat::V2
+            .decode(records[4..].to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(record.payload, b"some data");
+        assert_eq!(record.metadata.as_ref(), Some(&metadata));
+
+        RecordFormat::V2
+            .write(&mut records, b"no metadata")
+            .unwrap();
+        assert_eq!(RecordFormat::V2.last_sequence(&records, None), Some(42));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn test_compression() {
+        let envelope = Envelope {
+            compression: Some(Compression::Lz4),
+            ..Envelope::default()
+        };
+        let data = b"some data".repeat(100);
+
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_enveloped(&mut records, &data, &envelope)
+            .unwrap();
+        assert!(records.len() < data.len());
+        let record = RecordFormat::V2
+            .decode(records[4..].to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(record.encoding.compression, FLAG_LZ4);
+        let (decompressed, _) = record.encoding.decode(record.payload, None).unwrap();
+        assert_eq!(decompressed, data);
+
+        // Not worth it:
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_enveloped(&mut records, b"x", &envelope)
+            .unwrap();
+        let record = RecordFormat::V2
+            .decode(records[4..].to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(record.encoding.compression, 0);
+        assert_eq!(record.payload, b"x");
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn test_encryption() {
+        let keyring = Keyring::new(1, [1; 32]);
+        let metadata = Metadata {
+            sequence: 42,
+            timestamp: UNIX_EPOCH + Duration::from_millis(1_234_567),
+            headers: vec![("trace-id".to_owned(), "secret".to_owned())]
+                .into_iter()
+                .collect(),
+        };
+        let envelope = Envelope {
+            expires_at: Some(UNIX_EPOCH + Duration::from_millis(1_234_567)),
+            metadata: Some(&metadata),
+            keyring: Some(&keyring),
+            ..Envelope::default()
+        };
+
+        let mut records = vec![];
+        RecordFormat::V2
+            .write_enveloped(&mut records, b"some data", &envelope)
+            .unwrap();
+        assert!(!records.windows(9).any(|window| window == b"some data"));
+        assert!(!records.windows(6).any(|window| window == b"secret"));
+
+        // The metadata is encrypted with the element:
+        let record = RecordFormat::V2
+            .decode(records[4..].to_vec(), 0, 0)
+            .unwrap();
+        assert_eq!(record.metadata, None);
+        let decrypted = record.encoding.decode(record.payload, Some(&keyring));
+        assert_eq!(decrypted.unwrap(), (b"some data".to_vec(), Some(metadata)));
+        assert_eq!(RecordFormat::V2.last_sequence(&records, None), None);
+        assert_eq!(
+            RecordFormat::V2.last_sequence(&records, Some(&keyring)),
+            Some(42)
+        );
+
+        // Pushing the expiry time back is tampering, even with a good checksum:
+        records[4 + 5 + EXPIRY_LEN - 1] ^= 1;
+        let crc = crc32c::crc32c(&records[8..]);
+        records[4..8].copy_from_slice(&crc.to_be_bytes());
+        let record = RecordFormat::V2
+            .decode(records[4..].to_vec(), 0, 0)
+            .unwrap();
+        let err = record
+            .encoding
+            .decode(record.payload, Some(&keyring))
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_too_big_for_v1() {
+        let err = RecordFormat::V1
+            .check_len(Header::MAX_LEN as usize + 1)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        RecordFormat::V2
+            .check_len(Header::MAX_LEN as usize + 1)
+            .unwrap();
+    }
+}
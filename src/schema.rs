@@ -0,0 +1,93 @@
+//! Schema versions, for changing the format of the elements of a queue while
+//! older elements are still in it.
+//!
+//! A sender tags every element it sends with the version of its schema (see
+//! [`crate::SenderBuilder::schema_version`]). A receiver with a [`Schema`]
+//! (see [`crate::ReceiverBuilder::schema`]) upgrades the elements of older
+//! versions to the current one as it receives them. So, to change the format
+//! of the elements, bump the version on the senders and give the receivers an
+//! upgrade from each version that may still be in the queue. There is no need
+//! to drain the queue before deploying.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+
+/// Turns an element of an older version into one of the current version.
+type Upgrade = Arc<dyn Fn(Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync>;
+
+/// The current schema version of the elements of a queue, with an upgrade
+/// from each older version. Elements that were sent without a version (see
+/// [`crate::SenderBuilder::schema_version`]) are of version `0`.
+///
+/// There is one upgrade for each older version, going straight to the current
+/// one. If you keep a function from each version to the next, chaining them
+/// is enough.
+#[derive(Clone)]
+pub struct Schema {
+    current: u32,
+    upgrades: BTreeMap<u32, Upgrade>,
+}
+
+impl fmt::Debug for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Schema")
+            .field("current", &self.current)
+            .field("upgrades", &self.upgrades.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Schema {
+    /// Creates a schema whose current version is `version`, with no upgrades.
+    pub fn new(version: u32) -> Schema {
+        Schema {
+            current: version,
+            upgrades: BTreeMap::new(),
+        }
+    }
+
+    /// Adds an upgrade from an older version, which gets an element of version
+    /// `from` and returns it in the current version. This replaces any upgrade
+    /// already set for the same version.
+    pub fn upgrade<F>(mut self, from: u32, upgrade: F) -> Schema
+    where
+        F: 'static + Fn(Vec<u8>) -> io::Result<Vec<u8>> + Send + Sync,
+    {
+        self.upgrades.insert(from, Arc::new(upgrade));
+        self
+    }
+
+    /// The version elements are upgraded to.
+    pub fn current_version(&self) -> u32 {
+        self.current
+    }
+
+    /// Brings an element of a given version (or with no version) to the
+    /// current version.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `InvalidData` if there is no
+    /// upgrade from the version of the element (e.g., because it was sent by
+    /// a newer sender), besides any error of the upgrade itself.
+    pub(crate) fn apply(&self, version: Option<u32>, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let version = version.unwrap_or(0);
+
+        if version == self.current {
+            return Ok(data);
+        }
+
+        match self.upgrades.get(&version) {
+            Some(upgrade) => upgrade(data),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "no upgrade from schema version {} to {}",
+                    version, self.current
+                ),
+            )),
+        }
+    }
+}
@@ -2,9 +2,11 @@
 
 use std::cmp::{Ordering, PartialOrd};
 use std::fs::*;
-use std::io::{self, BufWriter, Read, Write};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
 
+use crate::durability::{replace_file, sync_dir, Durability, Syncer};
+
 /// The internal state of one side of the queue.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct QueueState {
@@ -42,6 +44,9 @@ impl QueueState {
     /// will substitute the current send metadata by this guess upon acquiring
     /// the send lock on this queue.
     ///
+    /// With multi-producer senders, call this only while holding the send lock.
+    /// Otherwise, another sender might be in the middle of an append.
+    ///
     /// # Panics
     ///
     /// This function panics if there is a file in the queue folder with extension
@@ -121,11 +126,65 @@ impl QueueState {
 #[derive(Default)]
 pub struct QueueStatePersistence {
     path: Option<PathBuf>,
+    /// The consumer group whose state this is, if not the default one.
+    group: Option<String>,
+    syncer: Syncer,
 }
 
+/// The prefix of the files of named consumer groups inside the queue folder.
+const GROUP_PERSISTENCE_PREFIX: &str = "recv-metadata-";
+
 /// The name of the file inside the queue folder.
-fn recv_persistence_filename<P: AsRef<Path>>(base: P) -> PathBuf {
-    base.as_ref().join("recv-metadata")
+fn recv_persistence_filename<P: AsRef<Path>>(base: P, group: Option<&str>) -> PathBuf {
+    match group {
+        Some(group) => base
+            .as_ref()
+            .join(format!("{}{}", GROUP_PERSISTENCE_PREFIX, group)),
+        None => base.as_ref().join("recv-metadata"),
+    }
+}
+
+/// Reads a saved queue state.
+fn read_queue_state(mut file: File) -> io::Result<QueueState> {
+    let mut u64_buffer = [0; 8];
+    let mut read_u64 = move || -> io::Result<_> {
+        file.read_exact(&mut u64_buffer)?;
+        Ok(u64::from_be_bytes(u64_buffer))
+    };
+
+    Ok(QueueState {
+        segment: read_u64()?,
+        position: read_u64()?,
+    })
+}
+
+/// The bottom of the smallest segment present in the queue folder, or the
+/// default state, if there is none.
+///
+/// # Panics
+///
+/// This function panics if there is a file in the queue folder with extension
+/// `.q` whose name is not an integer, such as `foo.q`.
+fn bottom_of_smallest_segment<P: AsRef<Path>>(base: P) -> io::Result<QueueState> {
+    let mut min_segment = None;
+    for maybe_entry in read_dir(base.as_ref())? {
+        let path = maybe_entry?.path();
+        if path.extension().map(|ext| ext == "q").unwrap_or(false) {
+            let segment = path
+                .file_stem()
+                .expect("has extension, therefore has stem")
+                .to_string_lossy()
+                .parse::<u64>()
+                .expect("failed to parse segment filename");
+
+            min_segment = Some(u64::min(segment, min_segment.unwrap_or(segment)));
+        }
+    }
+
+    Ok(QueueState {
+        segment: min_segment.unwrap_or(0),
+        ..QueueState::default()
+    })
 }
 
 impl QueueStatePersistence {
@@ -134,41 +193,104 @@ impl QueueStatePersistence {
         QueueStatePersistence::default()
     }
 
+    /// Sets how hard this persistence tries to get the state onto the disk.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.syncer = Syncer::new(durability);
+    }
+
+    /// Sets the consumer group whose state this persistence loads and saves.
+    /// Use `None` for the default group.
+    pub fn set_group(&mut self, group: Option<String>) {
+        self.group = group;
+    }
+
+    /// Loads the queue state. A consumer group (the default one included)
+    /// that did not exist yet starts at the oldest segment still in the queue
+    /// and is saved right away, so that no segment it needs is deleted from
+    /// under it by other groups or by [`crate::queue::sweep`].
     pub fn open<P: AsRef<Path>>(&mut self, base: P) -> io::Result<QueueState> {
-        let path = recv_persistence_filename(base);
+        let path = recv_persistence_filename(base.as_ref(), self.group.as_deref());
         self.path = Some(path.clone());
 
-        let mut u64_buffer = [0; 8];
-
         match File::open(&path) {
-            Ok(mut file) => {
-                let mut read_u64 = move || -> io::Result<_> {
-                    file.read_exact(&mut u64_buffer)?;
-                    Ok(u64::from_be_bytes(u64_buffer))
-                };
-
-                Ok(QueueState {
-                    segment: read_u64()?,
-                    position: read_u64()?,
-                })
+            Ok(file) => read_queue_state(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let state = bottom_of_smallest_segment(base)?;
+                self.save(&state)?;
+                Ok(state)
             }
-            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(QueueState::default()),
             Err(err) => Err(err),
         }
     }
 
-    /// Saves the queue state.
+    /// Lists the saved states of all the consumer groups of a queue. The
+    /// default group is listed as `None`, if it has ever been saved.
+    ///
+    /// # Errors
+    ///
+    /// Besides any IO error, this function returns an error of kind
+    /// `UnexpectedEof` if some group is being saved at this very moment.
+    pub fn group_states<P: AsRef<Path>>(base: P) -> io::Result<Vec<(Option<String>, QueueState)>> {
+        let mut group_states = vec![];
+
+        for maybe_entry in read_dir(base.as_ref())? {
+            let file_name = maybe_entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+
+            let group = if file_name == "recv-metadata" {
+                None
+            } else if file_name.ends_with(".tmp") {
+                // Being saved (see `QueueStatePersistence::save`):
+                continue;
+            } else if let Some(group) = file_name.strip_prefix(GROUP_PERSISTENCE_PREFIX) {
+                Some(group.to_owned())
+            } else {
+                continue;
+            };
+
+            let path = recv_persistence_filename(base.as_ref(), group.as_deref());
+            match File::open(path) {
+                Ok(file) => group_states.push((group, read_queue_state(file)?)),
+                // The group was deleted in the meantime:
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(group_states)
+    }
+
+    /// Deletes the saved state of a consumer group. Use `None` for the default
+    /// group.
+    pub fn remove<P: AsRef<Path>>(base: P, group: Option<&str>) -> io::Result<()> {
+        remove_file(recv_persistence_filename(base, group))
+    }
+
+    /// Saves the queue state. The file is replaced atomically, so that a crash
+    /// never leaves a truncated state behind.
     pub fn save(&mut self, queue_state: &QueueState) -> io::Result<()> {
-        let mut file = BufWriter::new(File::create(
-            self.path
-                .as_ref()
-                .expect("save should be called *after* open"),
-        )?);
+        let path = self
+            .path
+            .as_ref()
+            .expect("save should be called *after* open");
 
-        file.write_all(&queue_state.segment.to_be_bytes())?;
-        file.write_all(&queue_state.position.to_be_bytes())?;
-        file.flush()?;
+        let mut contents = [0; 16];
+        contents[..8].copy_from_slice(&queue_state.segment.to_be_bytes());
+        contents[8..].copy_from_slice(&queue_state.position.to_be_bytes());
 
-        Ok(())
+        replace_file(path, &contents, &mut self.syncer)
+    }
+
+    /// Syncs whatever a [`Durability::Periodic`] policy has left unsynced. Use
+    /// this before letting go of the queue.
+    pub fn sync_pending(&mut self) -> io::Result<()> {
+        match self.path.as_ref() {
+            Some(path) if self.syncer.is_dirty() => {
+                self.syncer
+                    .sync(&OpenOptions::new().write(true).open(path)?)?;
+                sync_dir(path.parent().expect("metadata file must have parent"))
+            }
+            _ => Ok(()),
+        }
     }
 }
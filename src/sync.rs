@@ -10,6 +10,7 @@ use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
 use crate::watcher::{file_removal_watcher, file_watcher, removal_watcher};
 
@@ -27,6 +28,20 @@ pub fn render_lock() -> String {
     )
 }
 
+/// How long [`FileGuard::spin_lock`] tries to lock before giving up.
+pub(crate) const SPIN_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long async code waits before trying again to take a short-lived lock
+/// held by someone else. Async code never spins: it waits on a timer instead,
+/// for up to [`SPIN_LOCK_TIMEOUT`] in total.
+pub(crate) const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(1);
+
+/// The first wait between tries in [`FileGuard::spin_lock`].
+const MIN_SPIN_LOCK_BACKOFF: Duration = Duration::from_micros(10);
+
+/// The longest wait between tries in [`FileGuard::spin_lock`].
+const MAX_SPIN_LOCK_BACKOFF: Duration = Duration::from_millis(10);
+
 /// A lock using the atomicity of [`OpenOptions::create_new`].
 ///
 /// Be careful! You can easily delete it; just open your file explorer throw
@@ -73,6 +88,38 @@ impl FileGuard {
         }
     }
 
+    /// Locks using a certain path in the disk, retrying with an increasing
+    /// backoff while the lock is locked. Use this only for locks that are held
+    /// for very short periods.
+    ///
+    /// # Errors
+    ///
+    /// This function returns an error of kind `WouldBlock` if the lock is still
+    /// locked after 5 seconds. This probably means that whoever held it has
+    /// died without releasing it.
+    pub fn spin_lock<P: AsRef<Path>>(path: P) -> io::Result<FileGuard> {
+        let started_at = Instant::now();
+        let mut backoff = MIN_SPIN_LOCK_BACKOFF;
+
+        loop {
+            if let Some(file_guard) = FileGuard::try_lock(path.as_ref())? {
+                break Ok(file_guard);
+            } else if started_at.elapsed() >= SPIN_LOCK_TIMEOUT {
+                break Err(io::Error::new(
+                    io::ErrorKind::WouldBlock,
+                    format!(
+                        "lock `{}` still locked after {:?}",
+                        path.as_ref().to_string_lossy(),
+                        SPIN_LOCK_TIMEOUT
+                    ),
+                ));
+            } else {
+                std::thread::sleep(backoff);
+                backoff = Duration::min(backoff * 2, MAX_SPIN_LOCK_BACKOFF);
+            }
+        }
+    }
+
     /// Awaits for the lock in a certain disk path to be unlocked and locks it
     /// when possible.
     pub async fn lock<P: AsRef<Path>>(path: P) -> io::Result<FileGuard> {
@@ -6,6 +6,7 @@ use std::io;
 use std::path::Path;
 
 use crate::mutex::Mutex;
+use crate::record::RecordFormat;
 
 // fn get_version_for_queue<P: AsRef<Path>>(base: P) -> io::Result<Version> {
 //     let version_file_contents = read_to_string(base.as_ref().join("yaque-version"))?;
@@ -64,7 +65,20 @@ use crate::mutex::Mutex;
 /// compatible with the current loaded version of `yaque`. It uses a mutex to implement atomicity
 /// (yes, we have had some race conditions during testing), but, for the sake of API compatibility
 /// in [`crate::Sender::open`] and [`crate::Receiver::open`], it performs a spinlock, instead of `.await`ing.
-pub fn check_queue_version<P: AsRef<Path>>(base: P) -> io::Result<()> {
+///
+/// The version file also records the [`RecordFormat`] of the queue, which is set when the queue is
+/// created (the requested one or [`RecordFormat::V1`], if none is requested). Queues in `v1` only
+/// have the version in the file, so that older versions of `yaque` can still open them. This
+/// function returns the format of the queue.
+///
+/// # Errors
+///
+/// Besides any IO error, this function returns an error of kind `InvalidInput` if a format was
+/// requested, but the queue already exists in a different format.
+pub fn check_queue_version<P: AsRef<Path>>(
+    base: P,
+    requested_format: Option<RecordFormat>,
+) -> io::Result<RecordFormat> {
     let mutex = Mutex::open(base.as_ref().join("version"))?;
 
     // Spin lock but it should be fine...
@@ -80,35 +94,62 @@ pub fn check_queue_version<P: AsRef<Path>>(base: P) -> io::Result<()> {
     let str_contents = String::from_utf8_lossy(&contents);
 
     if str_contents.is_empty() {
-        lock.write(format!("{}\n", env!("CARGO_PKG_VERSION")).as_bytes())?;
-    } else {
-        let version = match str_contents.trim().parse::<Version>() {
-            Ok(version) => version,
-            Err(err) => panic!(
-                "failed to parse `{:?}` version file: {}; contents were `{}`",
-                base.as_ref().join("yaque-version"),
-                err,
-                str_contents
-            ),
-        };
-
-        let requirement = VersionReq::parse(&format!(
-            "{}.{}.*",
-            env!("CARGO_PKG_VERSION_MAJOR"),
-            env!("CARGO_PKG_VERSION_MINOR")
-        ))
-        .expect("requirement is valid");
-
-        if !requirement.matches(&version) {
-            panic!(
-                "queue `{:?}` is of version {}, but you have yaque version {}, which is compatible with {}",
-                base.as_ref(),
-                version,
-                env!("CARGO_PKG_VERSION"),
-                requirement
-            );
+        let format = requested_format.unwrap_or_default();
+
+        if format == RecordFormat::V1 {
+            lock.write(format!("{}\n", env!("CARGO_PKG_VERSION")).as_bytes())?;
+        } else {
+            lock.write(format!("{}\n{}\n", env!("CARGO_PKG_VERSION"), format).as_bytes())?;
         }
+
+        return Ok(format);
+    }
+
+    let mut lines = str_contents.lines();
+    let version_line = lines.next().unwrap_or_default();
+
+    let version = match version_line.trim().parse::<Version>() {
+        Ok(version) => version,
+        Err(err) => panic!(
+            "failed to parse `{:?}` version file: {}; contents were `{}`",
+            base.as_ref().join("yaque-version"),
+            err,
+            str_contents
+        ),
+    };
+
+    let requirement = VersionReq::parse(&format!(
+        "{}.{}.*",
+        env!("CARGO_PKG_VERSION_MAJOR"),
+        env!("CARGO_PKG_VERSION_MINOR")
+    ))
+    .expect("requirement is valid");
+
+    if !requirement.matches(&version) {
+        panic!(
+            "queue `{:?}` is of version {}, but you have yaque version {}, which is compatible with {}",
+            base.as_ref(),
+            version,
+            env!("CARGO_PKG_VERSION"),
+            requirement
+        );
     }
 
-    Ok(())
+    let format = match lines.next() {
+        Some(format_line) => format_line.trim().parse::<RecordFormat>()?,
+        None => RecordFormat::V1,
+    };
+
+    match requested_format {
+        Some(requested_format) if requested_format != format => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "queue `{}` uses record format {}, but {} was requested",
+                base.as_ref().to_string_lossy(),
+                format,
+                requested_format
+            ),
+        )),
+        _ => Ok(format),
+    }
 }
@@ -112,28 +112,38 @@ pub(crate) fn removal_watcher(path: &Path, waker: Arc<Mutex<Option<Waker>>>) ->
     watcher
 }
 
-/// Watches a file for changes in its content.
+/// Watches a file for changes in its content. This watches the parent folder
+/// instead of the file itself, so that it does not matter if the file was
+/// removed (or not created yet) by the time the watch starts.
 pub(crate) fn file_watcher(path: &Path, waker: Arc<Mutex<Option<Waker>>>) -> RecommendedWatcher
 {
+    let file_name = path.file_name().expect("file must have name").to_owned();
+    let is_file = move |event: &Event| {
+        event
+            .paths
+            .iter()
+            .any(|path| path.file_name() == Some(&file_name))
+    };
+
     // Set up watcher:
     let mut watcher =
         notify::recommended_watcher(move |maybe_event: notify::Result<notify::Event>| {
             match maybe_event.expect("received error from watcher") {
                 // When any modification in the file happens
-                Event {
+                event @ Event {
                     kind: EventKind::Modify(ModifyKind::Data(_)),
                     ..
-                } => {
+                } if is_file(&event) => {
                     waker
                         .lock()
                         .expect("waker poisoned")
                         .take()
                         .map(|waker: Waker| waker.wake());
                 }
-                Event {
+                event @ Event {
                     kind: EventKind::Remove(_),
                     ..
-                } => {
+                } if is_file(&event) => {
                     log::debug!("file being watched was removed");
                 }
                 _ => {}
@@ -143,7 +153,10 @@ pub(crate) fn file_watcher(path: &Path, waker: Arc<Mutex<Option<Waker>>>) -> Rec
 
     // Put watcher to run:
     watcher
-        .watch(path, notify::RecursiveMode::NonRecursive)
+        .watch(
+            path.parent().expect("file must have parent"),
+            notify::RecursiveMode::NonRecursive,
+        )
         .expect("could not start watching file");
 
     watcher